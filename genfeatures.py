@@ -2,12 +2,18 @@ import kconfiglib
 import re
 
 pattern = re.compile('^UTIL_([A-Z]+)$')
+option_pattern = re.compile('^([A-Z]+)_[A-Z_]+$')
 
 
-def sym_util_to_feature(sym):
+def sym_util_to_feature(sym, kconf):
     m = pattern.match(sym.name)
     if m:
         return f'{m.group(1).lower()}-util'
+    # Options of a util, e.g. SH_PREFER_APPLETS -> sh-prefer-applets
+    m = option_pattern.match(sym.name)
+    if m and f'UTIL_{m.group(1)}' in kconf.syms:
+        return sym.name.lower().replace('_', '-')
+    return None
 
 
 if __name__ == '__main__':
@@ -17,7 +23,8 @@ if __name__ == '__main__':
     features = []
 
     for sym in kconf.unique_defined_syms:
-        if sym.str_value == "y":
-            features.append(sym_util_to_feature(sym))
+        feature = sym_util_to_feature(sym, kconf)
+        if sym.str_value == "y" and feature:
+            features.append(feature)
     
     print(' '.join(features))
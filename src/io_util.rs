@@ -1,6 +1,32 @@
 use std::io::{stdout, Write, self};
+use std::sync::atomic::{AtomicBool, Ordering};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg};
+
+/// Whether descriptor 1 was closed when last checked. Rust's stdout quietly
+/// discards output when it is, but a utility must report that it could not
+/// write.
+static STDOUT_CLOSED: AtomicBool = AtomicBool::new(false);
+
+/// Check whether descriptor 1 is open, as the shell does before each command
+/// it runs in its own process
+pub fn check_stdout() {
+    STDOUT_CLOSED.store(fcntl(1, FcntlArg::F_GETFD).is_err(), Ordering::Relaxed);
+}
+
+/// Describe an I/O error as the C library would, without the "(os error N)"
+/// which its Display adds
+pub fn error_message(why: &io::Error) -> String {
+    match why.raw_os_error() {
+        Some(errno) => Errno::from_i32(errno).desc().to_string(),
+        None => why.to_string()
+    }
+}
 
 pub fn write(s: &[u8]) -> io::Result<()> {
+    if STDOUT_CLOSED.load(Ordering::Relaxed) {
+        return Err(io::Error::from_raw_os_error(libc::EBADF))
+    }
     stdout().write_all(s)
 } 
 
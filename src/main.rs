@@ -2,9 +2,9 @@ use std::env::args_os;
 use std::error::Error;
 use std::path::Path;
 use std::process;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
 use std::process::ExitCode;
-use pico_args;
 use log::*;
 
 mod utils;
@@ -21,6 +21,8 @@ use utils::echo::echo_main;
 use utils::_false::false_main;
 #[cfg(feature = "mkdir-util")]
 use utils::mkdir::mkdir_main;
+#[cfg(feature = "printf-util")]
+use utils::printf::printf_main;
 #[cfg(feature = "pwd-util")]
 use utils::pwd::pwd_main;
 #[cfg(feature= "rm-util")]
@@ -38,7 +40,9 @@ use utils::_true::true_main;
 #[cfg(feature = "yes-util")]
 use utils::yes::yes_main;
 
-static COMMANDS: &[(&str, fn(&str, Vec<OsString>) -> Result<ExitCode, Box<(dyn std::error::Error + 'static)>>)] = &[
+pub type CommandMain = fn(&str, Vec<OsString>) -> Result<ExitCode, Box<dyn Error>>;
+
+static COMMANDS: &[(&str, CommandMain)] = &[
         #[cfg(feature = "false-util")]
         ("false", false_main),
         #[cfg(feature = "basename-util")]
@@ -49,6 +53,8 @@ static COMMANDS: &[(&str, fn(&str, Vec<OsString>) -> Result<ExitCode, Box<(dyn s
         ("echo", echo_main),
         #[cfg(feature = "mkdir-util")]
         ("mkdir", mkdir_main),
+        #[cfg(feature = "printf-util")]
+        ("printf", printf_main),
         #[cfg(feature = "pwd-util")]
         ("pwd", pwd_main),
         #[cfg(feature= "rm-util")]
@@ -73,16 +79,24 @@ pub fn list_commands() {
     for (cmd, _) in COMMANDS {
         print!("{} ", cmd);
     }
-    print!("\n");
+    println!();
 }
 
-pub fn exec_command(command_name: &str, args: Vec<OsString>) -> Result<ExitCode, Box<dyn Error>> {
-    for (cmd, cmdf) in COMMANDS {
-        if *cmd == command_name {
-            return cmdf(*cmd, args)
-        }
+/// The names of all the commands built in
+pub fn command_names() -> impl Iterator<Item = &'static str> {
+    COMMANDS.iter().map(|(cmd, _)| *cmd)
+}
+
+/// Find a command by name, which needn't be UTF-8
+pub fn find_command(command_name: &[u8]) -> Option<(&'static str, CommandMain)> {
+    COMMANDS.iter().find(|(cmd, _)| cmd.as_bytes() == command_name).copied()
+}
+
+pub fn exec_command(command_name: &OsStr, args: Vec<OsString>) -> Result<ExitCode, Box<dyn Error>> {
+    match find_command(command_name.as_bytes()) {
+        Some((cmd, cmdf)) => cmdf(cmd, args),
+        None => Ok(ExitCode::from(127))
     }
-    Ok(ExitCode::from(127))
 }
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -113,24 +127,24 @@ fn main() -> Result<ExitCode, Box<dyn Error>> {
 
     let mut args: Vec<OsString> = args_os().collect();
 
-    let first = args[0].clone();
-    let program_name = Path::new(&first).file_name().unwrap();
-    let command_name;
-    
-    if program_name.to_str().unwrap() == "rustybox" {
+    // The program may be run with no arguments at all, or with a name such
+    // as "/" which has no file name
+    let first = args.first().cloned().unwrap_or_else(|| OsString::from("rustybox"));
+    let program_name = Path::new(&first).file_name().unwrap_or(&first).to_owned();
+    let command_name = if program_name == "rustybox" {
         // Check if program name just contains "rustybox"
-        if args.len() == 1 {
+        if args.len() <= 1 {
             list_commands();
             process::exit(0);
         }
 
         // Remove "rustybox" from argv
         args.remove(0);
-        command_name = args[0].clone();
+        args[0].clone()
 
     } else {
-        command_name = Path::new(&first).file_name().unwrap().to_owned();
-    }
+        program_name
+    };
 
     let mut pargs = pico_args::Arguments::from_vec(args.clone());
     if pargs.contains("--help") {
@@ -145,7 +159,7 @@ fn main() -> Result<ExitCode, Box<dyn Error>> {
 
     // If we get to here we have a command
     args.remove(0);
-    let res = exec_command(command_name.to_str().unwrap(), args);
+    let res = exec_command(&command_name, args);
     match res {
         Ok(code) => Ok(code),
         Err(err) => {
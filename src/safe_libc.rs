@@ -3,7 +3,7 @@ use std::path::Path;
 use std::ptr::null_mut;
 use std::io;
 use std::mem::MaybeUninit;
-use libc;
+use std::ffi::CStr;
 
 pub fn getgroups() -> Vec<u32> {
     let n = 32;
@@ -23,7 +23,7 @@ pub fn getgroups() -> Vec<u32> {
         v.reserve(n_extend);
     }
 
-    return v;
+    v
 }
 
 pub fn geteuid() -> u32 {
@@ -58,4 +58,38 @@ pub fn isatty(fd: i32) -> io::Result<bool> {
             Err(err)
         }
     }
-}
\ No newline at end of file
+}
+/// A number to format with `snprintf`
+pub enum PrintfArg {
+    Int(i64),
+    Uint(u64),
+    Float(f64)
+}
+
+/// Format one number with a C printf conversion specification, which must
+/// use the `ll` length modifier for integers
+pub fn snprintf(spec: &CStr, arg: PrintfArg) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; 64];
+    loop {
+        let ptr = buf.as_mut_ptr() as *mut libc::c_char;
+        let len = unsafe {
+            match arg {
+                PrintfArg::Int(n) => libc::snprintf(ptr, buf.len(), spec.as_ptr(), n as libc::c_longlong),
+                PrintfArg::Uint(n) => libc::snprintf(ptr, buf.len(), spec.as_ptr(), n as libc::c_ulonglong),
+                PrintfArg::Float(n) => libc::snprintf(ptr, buf.len(), spec.as_ptr(), n as libc::c_double)
+            }
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error())
+        }
+        let len = len as usize;
+        if len < buf.len() {
+            buf.truncate(len);
+            return Ok(buf)
+        }
+        if buf.try_reserve_exact(len + 1 - buf.len()).is_err() {
+            return Err(io::Error::from_raw_os_error(libc::ENOMEM))
+        }
+        buf.resize(len + 1, 0);
+    }
+}
@@ -5,7 +5,7 @@ use std::process::ExitCode;
 use std::error::Error;
 use crate::io_util::write_line;
 
-fn strip_suffix<'a, 'b>(s: &'a OsStr, suffix: &'b OsStr) -> &'a OsStr {
+fn strip_suffix<'a>(s: &'a OsStr, suffix: &OsStr) -> &'a OsStr {
     let s = s.as_bytes();
     let suffix = suffix.as_bytes();
 
@@ -23,7 +23,7 @@ fn strip_suffix<'a, 'b>(s: &'a OsStr, suffix: &'b OsStr) -> &'a OsStr {
 fn get_basename<'a>(path_str: &'a OsStr, suffix: &OsStr) -> &'a OsStr {
 
     // Check null string
-    if path_str == "" {
+    if path_str.is_empty() {
         return OsStr::new(".");
     }
 
@@ -40,7 +40,7 @@ fn get_basename<'a>(path_str: &'a OsStr, suffix: &OsStr) -> &'a OsStr {
 }
 
 pub fn basename_main(_cmd_name: &str, args: Vec<OsString>) -> Result<ExitCode, Box<dyn Error>> {
-    if args.len() == 0 {
+    if args.is_empty() {
         println!("basename: missing operand");
         return Ok(ExitCode::FAILURE);
     }
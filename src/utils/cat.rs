@@ -10,7 +10,7 @@ use crate::io_util::write;
 fn path_opt_to_reader(path: Option<OsString>) -> Result<Box<dyn BufRead>, Box<dyn Error>> {
     match path {
         Some(p) => {
-            if p == OsString::from("-") {
+            if p == "-" {
                 Ok(Box::new(BufReader::new(stdin())))
             } else {
                 Ok(Box::new(BufReader::new(File::open(&p)?)))
@@ -8,6 +8,8 @@ pub mod echo;
 pub mod _false;
 #[cfg(feature= "mkdir-util")]
 pub mod mkdir;
+#[cfg(feature = "printf-util")]
+pub mod printf;
 #[cfg(feature= "pwd-util")]
 pub mod pwd;
 #[cfg(feature= "rm-util")]
@@ -0,0 +1,368 @@
+use std::ffi::{CString, OsString};
+use std::os::unix::prelude::OsStringExt;
+use std::process::ExitCode;
+use std::error::Error;
+use std::io;
+use crate::io_util::{error_message, write};
+use crate::safe_libc::{snprintf, PrintfArg};
+
+/// The output of `printf`
+#[derive(Default)]
+pub struct Formatted {
+    pub output: Vec<u8>,
+    /// What was wrong with the arguments, without the name of the command
+    pub errors: Vec<String>
+}
+
+/// Append the character given by the escape sequence at the start of `s`,
+/// which follows a backslash, returning the length of the sequence or None
+/// for `\c`, which ends the output. In the argument of `%b` an octal escape
+/// may start with an extra 0.
+fn escape(s: &[u8], in_argument: bool, output: &mut Vec<u8>) -> Option<usize> {
+    let c = match s.first() {
+        Some(c) => *c,
+        None => {
+            output.push(b'\\');
+            return Some(0)
+        }
+    };
+    let byte = match c {
+        b'\\' => b'\\',
+        b'a' => 0x07,
+        b'b' => 0x08,
+        b'f' => 0x0c,
+        b'n' => b'\n',
+        b'r' => b'\r',
+        b't' => b'\t',
+        b'v' => 0x0b,
+        b'c' => return None,
+        b'0'..=b'7' => {
+            let skip = if in_argument && c == b'0' { 1 } else { 0 };
+            let digits = s[skip..].iter().take(3).take_while(|c| (b'0'..=b'7').contains(*c)).count();
+            let value = s[skip..(skip + digits)].iter().fold(0u32, |n, c| n * 8 + (c - b'0') as u32);
+            output.push(value as u8);
+            return Some(skip + digits)
+        },
+        _ => {
+            output.extend_from_slice(&[b'\\', c]);
+            return Some(1)
+        }
+    };
+    output.push(byte);
+    Some(1)
+}
+
+/// The value of a character constant: a quote followed by a character
+fn char_constant(arg: &[u8]) -> Option<u32> {
+    match arg {
+        [b'\'' | b'"', rest @ ..] if !rest.is_empty() => {
+            let c = String::from_utf8_lossy(rest).chars().next().unwrap();
+            Some(if c == char::REPLACEMENT_CHARACTER { rest[0] as u32 } else { c as u32 })
+        },
+        _ => None
+    }
+}
+
+/// Parse an integer argument, which may be octal with a leading 0 or
+/// hexadecimal with a leading 0x
+fn parse_integer(arg: &[u8], errors: &mut Vec<String>) -> i128 {
+    if let Some(c) = char_constant(arg) {
+        return c as i128
+    }
+    if arg.is_empty() {
+        return 0
+    }
+
+    let s = &arg[arg.iter().take_while(|c| c.is_ascii_whitespace()).count()..];
+    let (negative, s) = match s.first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s)
+    };
+    let (radix, digits) = if s.starts_with(b"0x") || s.starts_with(b"0X") {
+        (16, &s[2..])
+    } else if s.starts_with(b"0") {
+        (8, s)
+    } else {
+        (10, s)
+    };
+    let len = digits.iter().take_while(|c| (**c as char).is_digit(radix)).count();
+    let n = digits[..len].iter()
+        .fold(0i128, |n, c| n.saturating_mul(radix as i128).saturating_add((*c as char).to_digit(radix).unwrap() as i128));
+    if len == 0 || len < digits.len() {
+        errors.push(format!("{}: invalid number", String::from_utf8_lossy(arg)));
+    } else if n > u64::MAX as i128 {
+        errors.push(format!("{}: number out of range", String::from_utf8_lossy(arg)));
+    }
+    if negative { -n } else { n }
+}
+
+fn parse_float(arg: &[u8], errors: &mut Vec<String>) -> f64 {
+    if let Some(c) = char_constant(arg) {
+        return c as f64
+    }
+    if arg.is_empty() {
+        return 0.0
+    }
+    match std::str::from_utf8(arg).ok().and_then(|s| s.trim_start().parse().ok()) {
+        Some(n) => n,
+        None => {
+            errors.push(format!("{}: invalid number", String::from_utf8_lossy(arg)));
+            0.0
+        }
+    }
+}
+
+/// Formats the arguments one conversion at a time
+struct Printer<'a> {
+    args: &'a [Vec<u8>],
+    next: usize,
+    formatted: Formatted
+}
+
+impl<'a> Printer<'a> {
+    /// The next argument, or an empty one once they have run out
+    fn arg(&mut self) -> &'a [u8] {
+        match self.args.get(self.next) {
+            Some(arg) => {
+                self.next += 1;
+                arg
+            },
+            None => b""
+        }
+    }
+
+    fn integer_arg(&mut self) -> i128 {
+        let arg = self.arg();
+        parse_integer(arg, &mut self.formatted.errors)
+    }
+
+    /// Append `text` padded with spaces to `width`, and cut to `precision`
+    /// bytes. Returns false if there isn't the memory for it.
+    fn pad(&mut self, text: &[u8], left: bool, width: usize, precision: Option<usize>) -> bool {
+        let text = &text[..precision.unwrap_or(text.len()).min(text.len())];
+        let fill = std::iter::repeat_n(b' ', width.saturating_sub(text.len()));
+        let output = &mut self.formatted.output;
+        if output.try_reserve(text.len() + fill.len()).is_err() {
+            self.formatted.errors.push(error_message(&io::Error::from_raw_os_error(libc::ENOMEM)));
+            return false
+        }
+        if left {
+            output.extend_from_slice(text);
+            output.extend(fill);
+        } else {
+            output.extend(fill);
+            output.extend_from_slice(text);
+        }
+        true
+    }
+
+    /// Go through `format` once, converting the arguments it asks for.
+    /// Returns false if the output was ended, by `\c` or an invalid
+    /// conversion.
+    fn format_once(&mut self, format: &[u8]) -> bool {
+        let mut i = 0;
+        while i < format.len() {
+            match format[i] {
+                b'\\' => match escape(&format[(i + 1)..], false, &mut self.formatted.output) {
+                    Some(len) => i += len + 1,
+                    None => return false
+                },
+                b'%' if format.get(i + 1) == Some(&b'%') => {
+                    self.formatted.output.push(b'%');
+                    i += 2;
+                },
+                b'%' => match self.convert(format, i) {
+                    Some(end) => i = end,
+                    None => return false
+                },
+                c => {
+                    self.formatted.output.push(c);
+                    i += 1;
+                }
+            }
+        }
+        true
+    }
+
+    /// Perform the conversion specification starting at `format[start]`,
+    /// returning where it ends
+    fn convert(&mut self, format: &[u8], start: usize) -> Option<usize> {
+        let mut i = start + 1;
+        let flags_len = format[i..].iter().take_while(|c| b"-+ #0".contains(c)).count();
+        let mut flags = format[i..(i + flags_len)].to_vec();
+        i += flags_len;
+
+        let width = if format.get(i) == Some(&b'*') {
+            i += 1;
+            let width = self.integer_arg();
+            if width < 0 {
+                flags.push(b'-');
+            }
+            width.unsigned_abs().min(i32::MAX as u128) as usize
+        } else {
+            let len = format[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+            i += len;
+            match field_size(&format[(i - len)..i]) {
+                Some(width) => width,
+                None => return self.out_of_range(&format[start..i])
+            }
+        };
+        let precision = if format.get(i) == Some(&b'.') {
+            i += 1;
+            if format.get(i) == Some(&b'*') {
+                i += 1;
+                // A negative precision is taken as if it were omitted
+                usize::try_from(self.integer_arg().min(i32::MAX as i128)).ok()
+            } else {
+                let len = format[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+                i += len;
+                match field_size(&format[(i - len)..i]) {
+                    Some(precision) => Some(precision),
+                    None => return self.out_of_range(&format[start..i])
+                }
+            }
+        } else {
+            None
+        };
+
+        let conversion = match format.get(i) {
+            Some(c) if b"diouxXeEfFgGaAcsb".contains(c) => *c,
+            _ => {
+                let spec = &format[start..(i + 1).min(format.len())];
+                self.formatted.errors.push(format!("{}: invalid conversion specification", String::from_utf8_lossy(spec)));
+                return None
+            }
+        };
+        let left = flags.contains(&b'-');
+        match conversion {
+            b's' => {
+                let arg = self.arg();
+                if !self.pad(arg, left, width, precision) {
+                    return None
+                }
+            },
+            b'c' => {
+                let arg = self.arg();
+                if !self.pad(&arg[..arg.len().min(1)], left, width, None) {
+                    return None
+                }
+            },
+            b'b' => {
+                let arg = self.arg();
+                let mut text = vec![];
+                let mut j = 0;
+                let mut stopped = false;
+                while j < arg.len() {
+                    if arg[j] != b'\\' {
+                        text.push(arg[j]);
+                        j += 1;
+                        continue;
+                    }
+                    match escape(&arg[(j + 1)..], true, &mut text) {
+                        Some(len) => j += len + 1,
+                        None => {
+                            stopped = true;
+                            break;
+                        }
+                    }
+                }
+                if !self.pad(&text, left, width, precision) || stopped {
+                    return None
+                }
+            },
+            _ => {
+                let mut spec = b"%".to_vec();
+                spec.extend_from_slice(&flags);
+                if width > 0 {
+                    spec.extend_from_slice(width.to_string().as_bytes());
+                }
+                if let Some(precision) = precision {
+                    spec.extend_from_slice(format!(".{}", precision).as_bytes());
+                }
+                let arg = match conversion {
+                    b'd' | b'i' => {
+                        spec.extend_from_slice(b"ll");
+                        PrintfArg::Int(self.integer_arg().clamp(i64::MIN as i128, i64::MAX as i128) as i64)
+                    },
+                    b'o' | b'u' | b'x' | b'X' => {
+                        // Negative numbers wrap around, as in C
+                        spec.extend_from_slice(b"ll");
+                        let n = self.integer_arg().clamp(i64::MIN as i128, u64::MAX as i128);
+                        PrintfArg::Uint(if n < 0 { n as i64 as u64 } else { n as u64 })
+                    },
+                    _ => {
+                        let arg = self.arg();
+                        PrintfArg::Float(parse_float(arg, &mut self.formatted.errors))
+                    }
+                };
+                spec.push(conversion);
+                let spec = CString::new(spec).unwrap();
+                match snprintf(&spec, arg) {
+                    Ok(text) => self.formatted.output.extend(text),
+                    Err(why) => {
+                        let spec = &format[start..(i + 1)];
+                        self.formatted.errors.push(format!("{}: {}", String::from_utf8_lossy(spec), error_message(&why)));
+                        return None
+                    }
+                }
+            }
+        }
+        Some(i + 1)
+    }
+
+    /// Report a field width or precision too large to be used, ending the
+    /// output
+    fn out_of_range(&mut self, spec: &[u8]) -> Option<usize> {
+        self.formatted.errors.push(format!("{}: field width or precision out of range", String::from_utf8_lossy(spec)));
+        None
+    }
+}
+
+/// Parse the digits of a field width or precision, which may be up to the
+/// largest that `*` accepts
+fn field_size(digits: &[u8]) -> Option<usize> {
+    if digits.is_empty() {
+        return Some(0)
+    }
+    std::str::from_utf8(digits).unwrap().parse::<i32>().ok().map(|n| n as usize)
+}
+
+/// Format `args` as directed by `format`, as the `printf` command does. The
+/// format is used again for as long as arguments remain to be converted.
+pub fn printf(format: &[u8], args: &[Vec<u8>]) -> Formatted {
+    let mut printer = Printer {
+        args,
+        next: 0,
+        formatted: Formatted::default()
+    };
+    loop {
+        let start = printer.next;
+        if !printer.format_once(format) || printer.next >= args.len() || printer.next == start {
+            break;
+        }
+    }
+    printer.formatted
+}
+
+pub fn printf_main(_cmd_name: &str, args: Vec<OsString>) -> Result<ExitCode, Box<dyn Error>> {
+    let mut args: Vec<Vec<u8>> = args.into_iter().map(|arg| arg.into_vec()).collect();
+    if args.first().map(|arg| arg.as_slice()) == Some(b"--") {
+        args.remove(0);
+    }
+    if args.is_empty() {
+        eprintln!("printf: missing format");
+        return Ok(ExitCode::FAILURE);
+    }
+
+    let formatted = printf(&args[0], &args[1..]);
+    write(&formatted.output)?;
+    for error in formatted.errors.iter() {
+        eprintln!("printf: {}", error);
+    }
+    if !formatted.errors.is_empty() {
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
@@ -25,7 +25,7 @@ fn rm_node(path: &Path, recurse: bool, force: bool) -> u8 {
         return 1;
     }
 
-    return 0;
+    0
 }
 
 fn files_from_pargs(pargs: &mut Arguments) -> Vec<OsString> {
@@ -51,7 +51,7 @@ pub fn rm_main(_cmd_name: &str, args: Vec<OsString>) -> Result<ExitCode, Box<dyn
     let files = files_from_pargs(&mut pargs);
     let mut exit_code = 0;
 
-    if files.len() == 0 {
+    if files.is_empty() {
         error!("missing operand");
         return Ok(ExitCode::FAILURE)
     }
@@ -18,10 +18,9 @@ pub fn rmdir_main(_cmd_name: &str, args: Vec<OsString>) -> Result<ExitCode, Box<
             fs::remove_dir(dirname)
         });
 
-        match success {
-            Ok(None) => break,
-            _ => ()
-        };
+        if let Ok(None) = success {
+            break;
+        }
     }
 
     Ok(ExitCode::SUCCESS)
@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::prelude::{OsStrExt, OsStringExt};
+use std::process::ExitCode;
+use nix::unistd::getpid;
+
+use crate::{find_command, CommandMain};
+use crate::io_util::check_stdout;
+use super::ast_nodes::{ExecEnv, IoRedirect};
+use super::jobs::{join_process_group, wait_foreground, Job};
+use super::redirect::{with_redirects, RedirectPlan};
+use super::subshell::fork_subshell;
+
+/// Applets which are run in the shell process itself. They must not read
+/// from stdin, run for long or leave any state behind. Other applets are
+/// run in a forked copy of the shell without executing a new binary.
+const NOFORK_APPLETS: &[&str] = &["[", "basename", "echo", "false", "pwd", "test", "true"];
+
+/// Applets which are builtins in other shells, and so are run rather than a
+/// program of the same name in PATH even without the sh-prefer-applets
+/// feature
+const BUILTIN_APPLETS: &[&str] = &["[", "echo", "false", "pwd", "test", "true"];
+
+/// An applet linked into this binary
+pub type Applet = (&'static str, CommandMain);
+
+/// Find the applet called `name`
+pub fn find_applet(name: &[u8]) -> Option<Applet> {
+    if name.contains(&b'/') {
+        return None
+    }
+    find_command(name)
+}
+
+/// Whether `applet` is run in preference to a program found in PATH
+pub fn is_builtin_applet(applet: Applet) -> bool {
+    BUILTIN_APPLETS.contains(&applet.0)
+}
+
+/// Run an applet with the arguments `fields[1..]`, as a builtin or in a
+/// subshell depending on the applet
+pub fn run_applet(applet: Applet, fields: &[Vec<u8>], assignments: &[(OsString, Vec<u8>)],
+                  redirects: &[IoRedirect], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    if NOFORK_APPLETS.contains(&applet.0) {
+        return ev.with_variables(assignments, |ev| {
+            with_redirects(redirects, ev, |_| Ok(call_applet(applet, &fields[1..])))
+        })
+    }
+
+    let plan = match RedirectPlan::new(redirects, ev)? {
+        Some(plan) => plan,
+        None => return Ok(1)
+    };
+    let mut env: Vec<(OsString, Vec<u8>)> = ev.exported_vars()
+        .map(|(name, value)| (name.to_os_string(), value.as_bytes().to_vec()))
+        .collect();
+    env.extend(assignments.iter().cloned());
+
+    let job_control = ev.job_control.is_some();
+    let pid = fork_subshell(ev, |_| {
+        if job_control {
+            join_process_group(getpid(), None);
+        }
+        if let Err(why) = plan.apply_in_child() {
+            eprintln!("sh: {}", why);
+            return 1
+        }
+        check_stdout();
+        for (name, _) in std::env::vars_os() {
+            std::env::remove_var(name);
+        }
+        for (name, value) in env.iter() {
+            std::env::set_var(name, OsStr::from_bytes(value));
+        }
+        call_applet(applet, &fields[1..])
+    })?;
+    if job_control {
+        join_process_group(pid, None);
+    }
+
+    wait_foreground(ev, Job::new(vec![pid], fields.join(&b' ')))
+}
+
+fn call_applet((name, main): Applet, args: &[Vec<u8>]) -> i32 {
+    let args = args.iter().map(|arg| OsString::from_vec(arg.clone())).collect();
+    match main(name, args) {
+        Ok(code) => exit_status(code),
+        Err(e) => {
+            eprintln!("{}: {}", name, e);
+            1
+        }
+    }
+}
+
+/// The status held by an ExitCode, which does not otherwise expose it
+fn exit_status(code: ExitCode) -> i32 {
+    (0..=255u8).find(|n| ExitCode::from(*n) == code).unwrap_or(1) as i32
+}
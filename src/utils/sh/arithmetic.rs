@@ -0,0 +1,425 @@
+use std::error::Error;
+
+use super::ast_nodes::ExecEnv;
+use super::error::ExecError;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum UnaryOp {
+    Plus,
+    Minus,
+    BitNot,
+    LogicalNot
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum BinOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    LogicalAnd,
+    LogicalOr
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Num(i64),
+    Name(Vec<u8>),
+    Op(&'static [u8])
+}
+
+#[derive(Debug, PartialEq)]
+enum Expr {
+    Num(i64),
+    Var(Vec<u8>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+    Assign(Option<BinOp>, Vec<u8>, Box<Expr>)
+}
+
+// Longest operators first so that the tokenizer is greedy
+const OPERATORS: &[&[u8]] = &[
+    b"<<=", b">>=",
+    b"<<", b">>", b"<=", b">=", b"==", b"!=", b"&&", b"||",
+    b"*=", b"/=", b"%=", b"+=", b"-=", b"&=", b"^=", b"|=",
+    b"*", b"/", b"%", b"+", b"-", b"<", b">", b"&", b"^", b"|",
+    b"~", b"!", b"?", b":", b"=", b"(", b")"
+];
+
+// Binary operators from lowest to highest precedence
+const PRECEDENCE: &[&[(&[u8], BinOp)]] = &[
+    &[(b"||", BinOp::LogicalOr)],
+    &[(b"&&", BinOp::LogicalAnd)],
+    &[(b"|", BinOp::BitOr)],
+    &[(b"^", BinOp::BitXor)],
+    &[(b"&", BinOp::BitAnd)],
+    &[(b"==", BinOp::Eq), (b"!=", BinOp::Ne)],
+    &[(b"<", BinOp::Lt), (b"<=", BinOp::Le), (b">", BinOp::Gt), (b">=", BinOp::Ge)],
+    &[(b"<<", BinOp::Shl), (b">>", BinOp::Shr)],
+    &[(b"+", BinOp::Add), (b"-", BinOp::Sub)],
+    &[(b"*", BinOp::Mul), (b"/", BinOp::Div), (b"%", BinOp::Rem)]
+];
+
+const ASSIGNMENT_OPS: &[(&[u8], Option<BinOp>)] = &[
+    (b"=", None),
+    (b"*=", Some(BinOp::Mul)),
+    (b"/=", Some(BinOp::Div)),
+    (b"%=", Some(BinOp::Rem)),
+    (b"+=", Some(BinOp::Add)),
+    (b"-=", Some(BinOp::Sub)),
+    (b"<<=", Some(BinOp::Shl)),
+    (b">>=", Some(BinOp::Shr)),
+    (b"&=", Some(BinOp::BitAnd)),
+    (b"^=", Some(BinOp::BitXor)),
+    (b"|=", Some(BinOp::BitOr))
+];
+
+/// Parse an integer constant in decimal, octal (leading 0) or hexadecimal
+/// (leading 0x) notation
+pub fn parse_number(s: &[u8]) -> Option<i64> {
+    let s = std::str::from_utf8(s).ok()?;
+    let (radix, digits) = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        (16, hex)
+    } else if s.len() > 1 && s.starts_with('0') {
+        (8, &s[1..])
+    } else {
+        (10, s)
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None
+    }
+    // Constants wrap on overflow like the C arithmetic they model
+    let mut n: i64 = 0;
+    for c in digits.chars() {
+        n = n.wrapping_mul(radix as i64).wrapping_add(c.to_digit(radix)? as i64);
+    }
+    Some(n)
+}
+
+fn tokenize(s: &[u8]) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut i = 0;
+    'outer: while i < s.len() {
+        let c = s[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        if c.is_ascii_alphanumeric() || c == b'_' {
+            let len = s[i..].iter().take_while(|c| c.is_ascii_alphanumeric() || **c == b'_').count();
+            let word = &s[i..(i + len)];
+            if c.is_ascii_digit() {
+                match parse_number(word) {
+                    Some(n) => tokens.push(Token::Num(n)),
+                    None => return Err(format!("invalid number \"{}\"", String::from_utf8_lossy(word)))
+                }
+            } else {
+                tokens.push(Token::Name(word.to_vec()));
+            }
+            i += len;
+            continue;
+        }
+
+        for op in OPERATORS {
+            if s[i..].starts_with(op) {
+                tokens.push(Token::Op(op));
+                i += op.len();
+                continue 'outer;
+            }
+        }
+
+        return Err(format!("syntax error: invalid character '{}'", c as char))
+    }
+    Ok(tokens)
+}
+
+struct ExprParser {
+    tokens: Vec<Token>,
+    pos: usize
+}
+
+impl ExprParser {
+    fn peek_op(&self) -> Option<&'static [u8]> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None
+        }
+    }
+
+    fn expect_op(&mut self, op: &[u8]) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("syntax error: expected '{}'", String::from_utf8_lossy(op)))
+        }
+    }
+
+    fn assignment(&mut self) -> Result<Expr, String> {
+        if let (Some(Token::Name(name)), Some(Token::Op(op))) = (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+            if let Some((_, binop)) = ASSIGNMENT_OPS.iter().find(|(s, _)| s == op) {
+                let name = name.clone();
+                self.pos += 2;
+                let value = self.assignment()?;
+                return Ok(Expr::Assign(*binop, name, Box::new(value)))
+            }
+        }
+        self.conditional()
+    }
+
+    fn conditional(&mut self) -> Result<Expr, String> {
+        let cond = self.binary(0)?;
+        if self.peek_op() == Some(b"?") {
+            self.pos += 1;
+            let if_true = self.assignment()?;
+            self.expect_op(b":")?;
+            let if_false = self.conditional()?;
+            return Ok(Expr::Conditional(Box::new(cond), Box::new(if_true), Box::new(if_false)))
+        }
+        Ok(cond)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary()
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = self.peek_op() {
+            let binop = match PRECEDENCE[level].iter().find(|(s, _)| *s == op) {
+                Some((_, binop)) => *binop,
+                None => break
+            };
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(binop, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let op = match self.peek_op() {
+            Some(b"+") => Some(UnaryOp::Plus),
+            Some(b"-") => Some(UnaryOp::Minus),
+            Some(b"~") => Some(UnaryOp::BitNot),
+            Some(b"!") => Some(UnaryOp::LogicalNot),
+            _ => None
+        };
+        if let Some(op) = op {
+            self.pos += 1;
+            return Ok(Expr::Unary(op, Box::new(self.unary()?)))
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let expr = match self.tokens.get(self.pos) {
+            Some(Token::Num(n)) => Expr::Num(*n),
+            Some(Token::Name(name)) => Expr::Var(name.clone()),
+            Some(Token::Op(b"(")) => {
+                self.pos += 1;
+                let expr = self.assignment()?;
+                self.expect_op(b")")?;
+                return Ok(expr)
+            },
+            Some(Token::Op(op)) => {
+                return Err(format!("syntax error: unexpected '{}'", String::from_utf8_lossy(op)))
+            },
+            None => return Err("syntax error: operand expected".to_string())
+        };
+        self.pos += 1;
+        Ok(expr)
+    }
+}
+
+fn apply(op: BinOp, lhs: i64, rhs: i64) -> Result<i64, String> {
+    let result = match op {
+        BinOp::Mul => lhs.wrapping_mul(rhs),
+        BinOp::Div | BinOp::Rem if rhs == 0 => return Err("division by zero".to_string()),
+        BinOp::Div => lhs.wrapping_div(rhs),
+        BinOp::Rem => lhs.wrapping_rem(rhs),
+        BinOp::Add => lhs.wrapping_add(rhs),
+        BinOp::Sub => lhs.wrapping_sub(rhs),
+        BinOp::Shl => lhs.wrapping_shl(rhs as u32),
+        BinOp::Shr => lhs.wrapping_shr(rhs as u32),
+        BinOp::Lt => (lhs < rhs) as i64,
+        BinOp::Le => (lhs <= rhs) as i64,
+        BinOp::Gt => (lhs > rhs) as i64,
+        BinOp::Ge => (lhs >= rhs) as i64,
+        BinOp::Eq => (lhs == rhs) as i64,
+        BinOp::Ne => (lhs != rhs) as i64,
+        BinOp::BitAnd => lhs & rhs,
+        BinOp::BitXor => lhs ^ rhs,
+        BinOp::BitOr => lhs | rhs,
+        BinOp::LogicalAnd => (lhs != 0 && rhs != 0) as i64,
+        BinOp::LogicalOr => (lhs != 0 || rhs != 0) as i64
+    };
+    Ok(result)
+}
+
+fn variable_value(name: &[u8], ev: &ExecEnv) -> Result<i64, String> {
+    let value = match ev.get_var(name) {
+        Some(value) => value,
+        None => return Ok(0)
+    };
+    let trimmed: &[u8] = value.trim_ascii();
+    if trimmed.is_empty() {
+        return Ok(0)
+    }
+    let (negative, digits) = match trimmed[0] {
+        b'-' => (true, &trimmed[1..]),
+        b'+' => (false, &trimmed[1..]),
+        _ => (false, trimmed)
+    };
+    match parse_number(digits) {
+        Some(n) if negative => Ok(n.wrapping_neg()),
+        Some(n) => Ok(n),
+        None => Err(format!("invalid number \"{}\"", String::from_utf8_lossy(value)))
+    }
+}
+
+impl Expr {
+    fn eval(&self, ev: &mut ExecEnv) -> Result<i64, String> {
+        let value = match self {
+            Expr::Num(n) => *n,
+            Expr::Var(name) => variable_value(name, ev)?,
+            Expr::Unary(op, expr) => {
+                let n = expr.eval(ev)?;
+                match op {
+                    UnaryOp::Plus => n,
+                    UnaryOp::Minus => n.wrapping_neg(),
+                    UnaryOp::BitNot => !n,
+                    UnaryOp::LogicalNot => (n == 0) as i64
+                }
+            },
+            // The logical operators only evaluate their right hand side
+            // when needed so that assignments within it are skipped
+            Expr::Binary(BinOp::LogicalAnd, lhs, rhs) => {
+                (lhs.eval(ev)? != 0 && rhs.eval(ev)? != 0) as i64
+            },
+            Expr::Binary(BinOp::LogicalOr, lhs, rhs) => {
+                (lhs.eval(ev)? != 0 || rhs.eval(ev)? != 0) as i64
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(ev)?;
+                let rhs = rhs.eval(ev)?;
+                apply(*op, lhs, rhs)?
+            },
+            Expr::Conditional(cond, if_true, if_false) => {
+                if cond.eval(ev)? != 0 {
+                    if_true.eval(ev)?
+                } else {
+                    if_false.eval(ev)?
+                }
+            },
+            Expr::Assign(op, name, expr) => {
+                let rhs = expr.eval(ev)?;
+                let value = match op {
+                    Some(op) => apply(*op, variable_value(name, ev)?, rhs)?,
+                    None => rhs
+                };
+                ev.set_var(name, value.to_string().into_bytes()).map_err(|e| e.to_string())?;
+                value
+            }
+        };
+        Ok(value)
+    }
+}
+
+/// Evaluate an arithmetic expression whose parameter expansions, command
+/// substitutions and quote removal have already been performed
+pub fn evaluate(expr: &[u8], ev: &mut ExecEnv) -> Result<i64, Box<dyn Error>> {
+    let result = tokenize(expr).and_then(|tokens| {
+        // An empty expression evaluates to zero
+        if tokens.is_empty() {
+            return Ok(0)
+        }
+        let mut parser = ExprParser { tokens, pos: 0 };
+        let parsed = parser.assignment()?;
+        if parser.pos != parser.tokens.len() {
+            return Err("syntax error in expression".to_string())
+        }
+        parsed.eval(ev)
+    });
+
+    result.map_err(|msg| {
+        let expr = String::from_utf8_lossy(expr);
+        Box::new(ExecError::new(format!("{}: {}", expr.trim(), msg))) as Box<dyn Error>
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! test_arith {
+        ( $test_name:ident, $expr:expr, $expected:expr ) => {
+            #[test]
+            fn $test_name() {
+                let mut ev = ExecEnv::new();
+                ev.set_var(b"x", b"5".to_vec()).unwrap();
+                assert_eq!(evaluate($expr.as_bytes(), &mut ev).unwrap(), $expected);
+            }
+        }
+    }
+
+    test_arith!(test_precedence, "1 + 2 * 3", 7);
+    test_arith!(test_parens, "(1 + 2) * 3", 9);
+    test_arith!(test_left_assoc, "10 - 4 - 3", 3);
+    test_arith!(test_unary, "-x + !0 + ~0", -5);
+    test_arith!(test_constants, "010 + 0x10 + 10", 34);
+    test_arith!(test_shifts, "1 << 4 >> 2", 4);
+    test_arith!(test_comparison, "(x > 4) + (x <= 4) + (x == 5) + (x != 5)", 2);
+    test_arith!(test_bitwise, "6 & 3 | 8 ^ 1", 11);
+    test_arith!(test_logical, "(0 || 2) + (1 && 0)", 1);
+    test_arith!(test_ternary, "x > 3 ? 10 : 20", 10);
+    test_arith!(test_nested_ternary, "0 ? 1 : 0 ? 2 : 3", 3);
+    test_arith!(test_unset_var, "y + 1", 1);
+    test_arith!(test_empty, "", 0);
+    test_arith!(test_modulo, "-7 % 3", -1);
+
+    #[test]
+    fn test_assignment() {
+        let mut ev = ExecEnv::new();
+        ev.set_var(b"x", b"5".to_vec()).unwrap();
+        assert_eq!(evaluate(b"x += 2", &mut ev).unwrap(), 7);
+        assert_eq!(evaluate(b"x <<= 1", &mut ev).unwrap(), 14);
+        assert_eq!(evaluate(b"y = x = 3", &mut ev).unwrap(), 3);
+        assert_eq!(ev.get_var(b"x"), Some(b"3".as_ref()));
+        assert_eq!(ev.get_var(b"y"), Some(b"3".as_ref()));
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        let mut ev = ExecEnv::new();
+        assert_eq!(evaluate(b"1 || (x = 5)", &mut ev).unwrap(), 1);
+        assert_eq!(evaluate(b"0 ? (x = 5) : 2", &mut ev).unwrap(), 2);
+        assert_eq!(ev.get_var(b"x"), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut ev = ExecEnv::new();
+        let err = evaluate(b"1 / 0", &mut ev).unwrap_err();
+        assert_eq!(err.to_string(), "1 / 0: division by zero");
+        assert!(evaluate(b"1 +", &mut ev).is_err());
+        assert!(evaluate(b"(1", &mut ev).is_err());
+        assert!(evaluate(b"1 2", &mut ev).is_err());
+        assert!(evaluate(b"09", &mut ev).is_err());
+        assert!(evaluate(b"3 = 4", &mut ev).is_err());
+    }
+}
@@ -4,202 +4,950 @@ use std::os::unix::prelude::OsStrExt;
 use std::os::unix::prelude::OsStringExt;
 use std::error::Error;
 use std::process::Command;
-use std::process::Stdio;
-use std::collections::HashMap;
-use core::slice::Iter;
+use std::os::unix::process::CommandExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use nix::fcntl::OFlag;
+use nix::unistd::{close, dup2, getpid, getppid, getuid, pipe2, Pid, User};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::fs::{self, File};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::io::{self, stderr, Write};
+use nix::errno::Errno;
+
+use super::error::ExecError;
+use super::expansions::{expand_assignment, expand_fields, expand_pattern, expand_word};
+use super::redirect::{redirect_shell, with_redirects, with_special_redirects, RedirectPlan};
+use super::applets::run_applet;
+use super::builtins::quote;
+use super::lookup::{resolve, Lookup, Resolved};
+use super::history::History;
+use super::jobs::{add_job, join_process_group, wait_foreground, Job, JobControl};
+use super::subshell::{fork_subshell, reset_signals, subshell_status};
+use super::trap::run_pending_traps;
+use super::prompt::expand_prompt;
 
 
 #[derive(Debug, PartialEq)]
 pub struct VarValue {
-    pub value: Vec<u8>,
-    pub export: bool
+    /// None for a variable which has been exported or made read-only but
+    /// not assigned
+    pub value: Option<Vec<u8>>,
+    pub export: bool,
+    pub readonly: bool
+}
+
+/// A change to the flow of execution requested by a builtin, which causes
+/// the commands following it to be skipped
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Flow {
+    Normal,
+    /// Leave the given number of enclosing loops
+    Break(usize),
+    /// Leave all but the last of the given number of enclosing loops and
+    /// start the next iteration of that one
+    Continue(usize),
+    Return,
+    Exit
+}
+
+/// The options set on the command line or with the `set` builtin
+#[derive(Debug, PartialEq, Default)]
+pub struct ShellOptions {
+    /// Exit when a command fails (-e)
+    pub errexit: bool,
+    /// Disable pathname expansion (-f)
+    pub noglob: bool,
+    /// Read commands without executing them (-n)
+    pub noexec: bool,
+    /// Treat the expansion of an unset variable as an error (-u)
+    pub nounset: bool,
+    /// Write input to standard error as it is read (-v)
+    pub verbose: bool,
+    /// Write each command to standard error before running it (-x)
+    pub xtrace: bool,
+    /// Don't let > overwrite existing files (-C)
+    pub noclobber: bool,
+    /// Give a pipeline the status of its last failing command
+    pub pipefail: bool,
+    /// Edit command lines with vi rather than emacs style keys
+    pub vi: bool
 }
 
 #[derive(Debug, PartialEq)]
 pub struct ExecEnv {
-    pub env: HashMap<OsString, VarValue>
+    pub env: HashMap<OsString, VarValue>,
+    pub functions: HashMap<OsString, Rc<ShellCommand>>,
+    /// The text substituted for each alias when commands are parsed
+    pub aliases: Rc<HashMap<Vec<u8>, Vec<u8>>>,
+    pub arg0: Vec<u8>,
+    pub args: Vec<Vec<u8>>,
+    pub last_status: i32,
+    pub last_bg_pid: Option<i32>,
+    pub shell_pid: u32,
+    pub interactive: bool,
+    /// Whether the commands are those given with -c
+    pub command_string: bool,
+    pub flow: Flow,
+    pub function_depth: usize,
+    pub loop_depth: usize,
+    /// The commands to run for each trapped signal, with 0 for EXIT
+    pub traps: HashMap<i32, Vec<u8>>,
+    pub jobs: Vec<Job>,
+    pub job_control: Option<JobControl>,
+    pub options: ShellOptions,
+    /// How many enclosing contexts in which a failure doesn't cause an exit
+    /// with errexit, such as the condition of an `if`
+    pub errexit_ignored: usize,
+    /// The signals ignored when the shell started, which a non-interactive
+    /// shell doesn't let be trapped
+    pub ignored_signals: HashSet<i32>,
+    pub history: History,
+    /// Where the programs run have been found in PATH, which is forgotten
+    /// when PATH changes
+    pub command_hash: HashMap<OsString, PathBuf>,
+    /// The position within the argument at OPTIND of the next option for
+    /// `getopts`, which starts again when OPTIND is assigned
+    pub getopts_offset: usize
 }
 
-pub type Script<'a> = Vec<CompleteCommand<'a>>;
+impl ExecEnv {
+    pub fn new() -> ExecEnv {
+        ExecEnv {
+            env: HashMap::new(),
+            functions: HashMap::new(),
+            aliases: Rc::new(HashMap::new()),
+            arg0: b"sh".to_vec(),
+            args: vec![],
+            last_status: 0,
+            last_bg_pid: None,
+            shell_pid: std::process::id(),
+            interactive: false,
+            command_string: false,
+            flow: Flow::Normal,
+            function_depth: 0,
+            loop_depth: 0,
+            traps: HashMap::new(),
+            jobs: vec![],
+            job_control: None,
+            options: ShellOptions::default(),
+            errexit_ignored: 0,
+            ignored_signals: HashSet::new(),
+            history: History::new(),
+            command_hash: HashMap::new(),
+            getopts_offset: 1
+        }
+    }
+
+    /// Import the variables in the process environment, marked for export,
+    /// and set those the shell itself maintains
+    pub fn inherit_environment(&mut self) {
+        // Variables whose names the shell can't refer to are still passed on
+        // to the commands it runs
+        for (name, value) in std::env::vars_os() {
+            self.env.insert(name, VarValue { value: Some(value.into_vec()), export: true, readonly: false });
+        }
+
+        // Field splitting and getopts start afresh whatever the environment
+        // says
+        self.env.insert(OsString::from("IFS"), VarValue { value: Some(b" \t\n".to_vec()), export: false, readonly: false });
+        self.env.insert(OsString::from("OPTIND"), VarValue { value: Some(b"1".to_vec()), export: false, readonly: false });
+        self.env.insert(OsString::from("PPID"), VarValue { value: Some(getppid().to_string().into_bytes()), export: false, readonly: false });
+
+        // PWD is kept if it is an absolute name for the working directory,
+        // and OLDPWD if it is a directory at all
+        let pwd_valid = self.get_var(b"PWD").is_some_and(|pwd| pwd.starts_with(b"/") && same_file(pwd, b"."));
+        if !pwd_valid {
+            if let Ok(cwd) = std::env::current_dir() {
+                let _ = self.set_var(b"PWD", cwd.into_os_string().into_vec());
+            }
+        }
+        if !self.get_var(b"OLDPWD").is_some_and(|dir| Path::new(OsStr::from_bytes(dir)).is_dir()) {
+            self.env.remove(OsStr::new("OLDPWD"));
+        }
+
+        if self.get_var(b"HOME").is_none() {
+            if let Ok(Some(user)) = User::from_uid(getuid()) {
+                let _ = self.set_var(b"HOME", user.dir.into_os_string().into_vec());
+            }
+        }
+    }
+
+    pub fn get_var(&self, name: &[u8]) -> Option<&[u8]> {
+        self.env.get(OsStr::from_bytes(name)).and_then(|v| v.value.as_deref())
+    }
+
+    /// The exported variables which have values, which make up the
+    /// environment of the commands run
+    pub fn exported_vars(&self) -> impl Iterator<Item = (&OsStr, &OsStr)> {
+        self.env.iter()
+            .filter(|(_, var)| var.export)
+            .filter_map(|(name, var)| Some((name.as_os_str(), OsStr::from_bytes(var.value.as_deref()?))))
+    }
+
+    pub fn set_var(&mut self, name: &[u8], value: Vec<u8>) -> Result<(), Box<dyn Error>> {
+        match name {
+            b"PATH" => self.command_hash.clear(),
+            b"OPTIND" => self.getopts_offset = 1,
+            _ => ()
+        }
+        let name = OsString::from_vec(name.to_vec());
+        match self.env.get_mut(&name) {
+            Some(var) if var.readonly => return Err(readonly_error(name.as_bytes())),
+            Some(var) => var.value = Some(value),
+            None => {
+                self.env.insert(name, VarValue { value: Some(value), export: false, readonly: false });
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a variable, which fails if it is read-only
+    pub fn unset_var(&mut self, name: &[u8]) -> Result<(), Box<dyn Error>> {
+        match self.env.get(OsStr::from_bytes(name)) {
+            Some(var) if var.readonly => Err(readonly_error(name)),
+            _ => {
+                match name {
+                    b"PATH" => self.command_hash.clear(),
+                    b"OPTIND" => self.getopts_offset = 1,
+                    _ => ()
+                }
+                self.env.remove(OsStr::from_bytes(name));
+                Ok(())
+            }
+        }
+    }
+
+    /// Run `f` with the variables assigned by `assignments` set, restoring
+    /// their previous values afterwards
+    pub fn with_variables<F>(&mut self, assignments: &[(OsString, Vec<u8>)], f: F) -> Result<i32, Box<dyn Error>>
+    where
+        F: FnOnce(&mut ExecEnv) -> Result<i32, Box<dyn Error>>
+    {
+        for (name, _) in assignments {
+            if self.env.get(name).is_some_and(|var| var.readonly) {
+                return Err(readonly_error(name.as_bytes()))
+            }
+        }
+
+        if assignments.iter().any(|(name, _)| name == "PATH") {
+            self.command_hash.clear();
+        }
+        let mut saved = vec![];
+        for (name, value) in assignments {
+            saved.push((name, self.env.remove(name)));
+            self.env.insert(name.clone(), VarValue { value: Some(value.clone()), export: true, readonly: false });
+        }
+        let result = f(self);
+        for (name, var) in saved.into_iter().rev() {
+            if name == "PATH" {
+                self.command_hash.clear();
+            }
+            match var {
+                Some(var) => self.env.insert(name.clone(), var),
+                None => self.env.remove(name)
+            };
+        }
+        result
+    }
+
+    /// Finish a pass through the body (or condition) of a loop, consuming
+    /// any `break` or `continue` aimed at it. Returns whether the loop
+    /// should stop.
+    fn end_of_iteration(&mut self) -> bool {
+        match self.flow {
+            Flow::Normal => false,
+            Flow::Continue(1) => {
+                self.flow = Flow::Normal;
+                false
+            },
+            Flow::Break(1) => {
+                self.flow = Flow::Normal;
+                true
+            },
+            Flow::Break(n) => {
+                self.flow = Flow::Break(n - 1);
+                true
+            },
+            Flow::Continue(n) => {
+                self.flow = Flow::Continue(n - 1);
+                true
+            },
+            Flow::Return | Flow::Exit => true
+        }
+    }
+
+    /// Run `f` in a context in which errexit doesn't apply
+    pub fn ignoring_errexit<T>(&mut self, f: impl FnOnce(&mut ExecEnv) -> T) -> T {
+        self.errexit_ignored += 1;
+        let result = f(self);
+        self.errexit_ignored -= 1;
+        result
+    }
+
+    /// Exit the shell if errexit is set and a command failed outside of any
+    /// context in which failures are expected
+    pub fn check_errexit(&mut self, status: i32) {
+        if status != 0 && self.options.errexit && self.errexit_ignored == 0 && self.flow == Flow::Normal {
+            self.flow = Flow::Exit;
+        }
+    }
+
+    /// The current option flags as reported by $-
+    pub fn option_flags(&self) -> Vec<u8> {
+        let options = [
+            (b'e', self.options.errexit),
+            (b'f', self.options.noglob),
+            (b'i', self.interactive),
+            (b'm', self.job_control.is_some()),
+            (b'n', self.options.noexec),
+            (b'u', self.options.nounset),
+            (b'v', self.options.verbose),
+            (b'x', self.options.xtrace),
+            (b'C', self.options.noclobber),
+            (b'c', self.command_string)
+        ];
+        options.iter().filter(|(_, on)| *on).map(|(flag, _)| *flag).collect()
+    }
+}
+
+/// Whether two names refer to the same file
+fn same_file(a: &[u8], b: &[u8]) -> bool {
+    match (fs::metadata(OsStr::from_bytes(a)), fs::metadata(OsStr::from_bytes(b))) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false
+    }
+}
+
+fn readonly_error(name: &[u8]) -> Box<dyn Error> {
+    Box::new(ExecError::new(format!("{}: readonly variable", String::from_utf8_lossy(name))))
+}
 
 #[derive(Debug, PartialEq)]
-pub struct CompleteCommand<'a> {
-    pub expression: Expression<'a>,
-    pub subshell: bool
+pub struct CompleteCommand {
+    pub list: CompoundList
 }
 
-impl<'a> CompleteCommand<'a> {
+impl CompleteCommand {
     pub fn execute(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
-        self.expression.execute(ev)
+        self.list.execute(ev)
     }   
 }
 
 #[derive(Debug, PartialEq)]
-pub struct Expression<'a> {
-    pub seq: Vec<LogicalSeqElem<'a>>,
-    pub term: PipeLine<'a>
+pub struct Expression {
+    pub seq: Vec<LogicalSeqElem>,
+    pub term: PipeLine
 }
 
-impl<'a> Expression<'a> {
+impl Expression {
     fn execute(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
-        let mut ret = self.term.execute_pipeline(ev)?;
-        for l in self.seq.iter() {
+        // Only the last pipeline of an and-or list can cause an exit with
+        // errexit
+        let mut ret = if self.seq.is_empty() {
+            self.term.execute_pipeline(ev)?
+        } else {
+            ev.ignoring_errexit(|ev| self.term.execute_pipeline(ev))?
+        };
+        for (i, l) in self.seq.iter().enumerate() {
+            if ev.flow != Flow::Normal {
+                break;
+            }
+
             let op_res = match l.op {
                 LogicalOp::And => ret == 0,
                 LogicalOp::Or => ret != 0
             };
             
-            if op_res {
+            // A skipped pipeline leaves the status for the next operator
+            if op_res && i + 1 == self.seq.len() {
                 ret = l.pipeline.execute_pipeline(ev)?;
-            } else {
-                break;
+            } else if op_res {
+                ret = ev.ignoring_errexit(|ev| l.pipeline.execute_pipeline(ev))?;
             }
         }
 
         Ok(ret)
     }
+
+    /// The source text of the and-or list, used to describe it as a job
+    fn text(&self) -> Vec<u8> {
+        let mut text = self.term.source.to_vec();
+        for l in self.seq.iter() {
+            text.extend_from_slice(match l.op {
+                LogicalOp::And => b" && ",
+                LogicalOp::Or => b" || "
+            });
+            text.extend_from_slice(&l.pipeline.source);
+        }
+        text
+    }
 }
 
 #[derive(Debug, PartialEq)]
-pub struct LogicalSeqElem<'a> {
+pub struct LogicalSeqElem {
     pub op: LogicalOp,
-    pub pipeline: PipeLine<'a>
+    pub pipeline: PipeLine
 }
 
 #[derive(Debug, PartialEq)]
-pub struct PipeLine<'a> {
+pub struct PipeLine {
     pub bang: bool,
-    pub pipesequence: Vec<SimpleCommand<'a>>
+    pub pipesequence: Vec<ShellCommand>,
+    pub source: Vec<u8>
 }
 
-impl<'a> PipeLine<'a> {
+impl PipeLine {
     fn execute_pipeline(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
-        let mut children = vec![];
-        let mut commands: Vec<_> = self.pipesequence
-            .iter()
-            .map(|cmd| cmd.setup_command(ev))
-            .collect();
-
-        commands.reverse();
-
-        let mut cmd_left = commands.pop().expect("Failed to pop command");
-        for mut cmd_right in commands {            
-            match (&mut cmd_left, &mut cmd_right) {
-                (Some(lcmd), Some(rcmd)) => {
-                    lcmd.stdout(Stdio::piped());
-                    let mut child = lcmd.spawn()?;
-                    rcmd.stdin(child.stdout.take().expect("Failed to create pipe"));
-                    children.push(Some(child));
-                },
-                (Some(lcmd), None) => {
-                    lcmd.stdout(Stdio::null());
-                    let child = lcmd.spawn()?;
-                    children.push(Some(child));
-                },
-                (None, Some(_)) => (),
-                (None, None) => ()
-            };
+        // With job control a subshell is a job of its own, so it is run
+        // like the commands of a longer pipeline
+        let subshell = matches!(self.pipesequence[0], ShellCommand::Compound(CompoundCommand::Subshell(_), _));
+        let mut final_exit_val = if self.pipesequence.len() == 1 && !(subshell && ev.job_control.is_some()) {
+            self.pipesequence[0].execute(ev)?
+        } else {
+            self.execute_in_subshells(ev)?
+        };
 
-            cmd_left = cmd_right;
+        if self.bang {
+            final_exit_val = (final_exit_val == 0) as i32;
         }
 
-        if let Some(mut final_command) = cmd_left {
-            let child = final_command.spawn()?;
-            children.push(Some(child));
+        ev.last_status = final_exit_val;
+
+        // A compound command fails because of a command within it, which
+        // has already been checked
+        let compound = self.pipesequence.len() == 1 && !subshell && matches!(self.pipesequence[0], ShellCommand::Compound(..));
+        if !self.bang && !compound {
+            ev.check_errexit(final_exit_val);
         }
-        
+        Ok(final_exit_val)
+    }
 
-        let mut final_exit_val = 0;
-        for child_opt in children {
-            final_exit_val = match child_opt {
-                Some(mut child) => child.wait()?.code().unwrap(),
-                None => 0
+    /// Run each command of the pipeline in its own subshell, connecting the
+    /// stdout of each to the stdin of the next
+    fn execute_in_subshells(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+        let job_control = ev.job_control.is_some();
+        let mut pgid = None;
+        let mut pids = vec![];
+        let mut prev_stdout: Option<OwnedFd> = None;
+        for (i, cmd) in self.pipesequence.iter().enumerate() {
+            let (next_stdin, stdout) = if i + 1 < self.pipesequence.len() {
+                let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC)?;
+                unsafe { (Some(OwnedFd::from_raw_fd(read_fd)), Some(OwnedFd::from_raw_fd(write_fd))) }
+            } else {
+                (None, None)
             };
-        }   
+            let stdin = prev_stdout.take();
+
+            let pid = fork_subshell(ev, |ev| {
+                if job_control {
+                    join_process_group(getpid(), pgid);
+                }
+                if let Some(fd) = &stdin {
+                    if dup2(fd.as_raw_fd(), 0).is_err() {
+                        return 1
+                    }
+                }
+                if let Some(fd) = &stdout {
+                    if dup2(fd.as_raw_fd(), 1).is_err() {
+                        return 1
+                    }
+                }
+                // Commands run without an exec would otherwise keep the
+                // pipes open, so a reader would never see the end of its
+                // input or a writer its reader going away
+                for fd in [&stdin, &stdout, &next_stdin].into_iter().flatten() {
+                    let _ = close(fd.as_raw_fd());
+                }
+                subshell_status(cmd.execute(ev))
+            })?;
+
+            if job_control {
+                join_process_group(pid, pgid);
+                pgid.get_or_insert(pid);
+            }
+            pids.push(pid);
+            prev_stdout = next_stdin;
+        }
 
-        Ok(final_exit_val)
+        wait_foreground(ev, Job::new(pids, self.source.to_vec()))
+    }
+}
+
+/// A command of a pipeline
+#[derive(Debug, PartialEq)]
+pub enum ShellCommand {
+    Simple(SimpleCommand),
+    Compound(CompoundCommand, Vec<IoRedirect>),
+    FunctionDefinition(FunctionDefinition)
+}
+
+impl ShellCommand {
+    fn execute(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+        match self {
+            ShellCommand::Simple(cmd) => cmd.execute(ev),
+            ShellCommand::Compound(cmd, redirects) => {
+                with_redirects(redirects, ev, |ev| cmd.execute(ev))
+            },
+            ShellCommand::FunctionDefinition(def) => def.execute(ev)
+        }
     }
 }
 
-pub type AssignmentWords = Vec<(OsString, OsString)>;
+/// A sequence of and-or lists, as found in a complete command or the body
+/// of a compound command
+#[derive(Debug, PartialEq)]
+pub struct CompoundList {
+    pub items: Vec<ListItem>
+}
 
+/// An and-or list which is run in the background when followed by `&`
 #[derive(Debug, PartialEq)]
-pub struct SimpleCommand<'a> {
-    pub assignment_words: AssignmentWords,
-    pub words: Vec<Word<'a>>
+pub struct ListItem {
+    pub expression: Expression,
+    pub asynchronous: bool
 }
 
-impl<'a> SimpleCommand<'a> {
-    fn command_name(&self) -> Option<&Word> {
-        if self.words.len() > 0 {
-            return Some(&self.words[0])
-        } else {
-            return None
+impl CompoundList {
+    pub fn execute(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+        let mut status = 0;
+        for item in self.items.iter() {
+            status = if item.asynchronous {
+                item.execute_async(ev)?
+            } else {
+                item.expression.execute(ev)?
+            };
+            run_pending_traps(ev);
+            if ev.flow == Flow::Exit {
+                return Ok(ev.last_status)
+            }
+            if ev.flow != Flow::Normal {
+                break;
+            }
         }
+        Ok(status)
     }
+}
 
-    fn args(&self) -> Iter<'a, Word> {
-        return self.words[1..].iter()
+impl ListItem {
+    /// Start the and-or list in a subshell without waiting for it
+    fn execute_async(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+        let job_control = ev.job_control.is_some();
+        let pid = fork_subshell(ev, |ev| {
+            // Without job control a background command must not compete
+            // with the shell for its input
+            if job_control {
+                join_process_group(getpid(), None);
+            } else {
+                if let Ok(null) = File::open("/dev/null") {
+                    let _ = dup2(null.as_raw_fd(), 0);
+                }
+            }
+            subshell_status(self.expression.execute(ev))
+        })?;
+
+        if job_control {
+            join_process_group(pid, None);
+        }
+        let id = add_job(ev, Job::new(vec![pid], self.expression.text()));
+        if ev.interactive {
+            eprintln!("[{}] {}", id, pid);
+        }
+        ev.last_bg_pid = Some(pid.as_raw());
+        ev.last_status = 0;
+        Ok(0)
     }
+}
 
-    fn save_variables(&self, ev: &mut ExecEnv) {
-        for (name, val) in self.assignment_words.iter() {
-            ev.env.insert(name.clone(), VarValue { 
-                value: val.clone().into_vec(), 
-                export: false
-            });
+#[derive(Debug, PartialEq)]
+pub enum CompoundCommand {
+    BraceGroup(CompoundList),
+    Subshell(CompoundList),
+    If(IfClause),
+    While(LoopClause),
+    Until(LoopClause),
+    For(ForClause),
+    Case(CaseClause)
+}
+
+impl CompoundCommand {
+    fn execute(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+        match self {
+            CompoundCommand::BraceGroup(list) => list.execute(ev),
+            CompoundCommand::Subshell(list) => {
+                let pid = fork_subshell(ev, |ev| subshell_status(list.execute(ev)))?;
+                wait_foreground(ev, Job::new(vec![pid], vec![]))
+            },
+            CompoundCommand::If(clause) => clause.execute(ev),
+            CompoundCommand::While(clause) => clause.execute(ev, true),
+            CompoundCommand::Until(clause) => clause.execute(ev, false),
+            CompoundCommand::For(clause) => clause.execute(ev),
+            CompoundCommand::Case(clause) => clause.execute(ev)
         }
     }
- 
-    fn setup_command(&self, ev: &mut ExecEnv) -> Option<Command> {
+}
 
-        let command_name = match self.command_name() {
-            Some(command_name) => OsString::from_vec(command_name.eval()),
-            None => {
-                self.save_variables(ev);
-                return None
+#[derive(Debug, PartialEq)]
+pub struct IfClause {
+    /// The condition and body of the `if` followed by those of each `elif`
+    pub branches: Vec<(CompoundList, CompoundList)>,
+    pub else_part: Option<CompoundList>
+}
+
+impl IfClause {
+    fn execute(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+        for (condition, body) in self.branches.iter() {
+            if ev.ignoring_errexit(|ev| condition.execute(ev))? == 0 {
+                return body.execute(ev)
             }
+        }
+
+        match &self.else_part {
+            Some(body) => body.execute(ev),
+            None => Ok(0)
+        }
+    }
+}
+
+/// The condition and body of a `while` or `until` loop
+#[derive(Debug, PartialEq)]
+pub struct LoopClause {
+    pub condition: CompoundList,
+    pub body: CompoundList
+}
+
+impl LoopClause {
+    /// Run the body for as long as the condition's success matches `while_true`
+    fn execute(&self, ev: &mut ExecEnv, while_true: bool) -> Result<i32, Box<dyn Error>> {
+        ev.loop_depth += 1;
+        let result = self.run(ev, while_true);
+        ev.loop_depth -= 1;
+        result
+    }
+
+    fn run(&self, ev: &mut ExecEnv, while_true: bool) -> Result<i32, Box<dyn Error>> {
+        let mut status = 0;
+        loop {
+            let condition = ev.ignoring_errexit(|ev| self.condition.execute(ev))?;
+            if ev.flow != Flow::Normal {
+                if ev.end_of_iteration() {
+                    return Ok(condition)
+                }
+                continue;
+            }
+            if (condition == 0) != while_true {
+                break;
+            }
+
+            status = self.body.execute(ev)?;
+            if ev.end_of_iteration() {
+                break;
+            }
+        }
+        Ok(status)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ForClause {
+    pub name: OsString,
+    /// The words following `in`, or None to loop over the positional
+    /// parameters
+    pub words: Option<Vec<Word>>,
+    pub body: CompoundList
+}
+
+impl ForClause {
+    fn execute(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+        let values = match &self.words {
+            Some(words) => {
+                let mut values = vec![];
+                for word in words {
+                    values.extend(word.eval_fields(ev)?);
+                }
+                values
+            },
+            None => ev.args.clone()
         };
 
-        // Convert Vec<u8> into Iter of OsStr
-        let args: Vec<Vec<u8>> = self.args().map(|w| {
-            w.eval()
-        }).collect();
-        let osargs = args.iter().map(|arg| {
-            OsStr::from_bytes(&arg)
-        });
+        ev.loop_depth += 1;
+        let mut result = Ok(0);
+        for value in values {
+            result = ev.set_var(self.name.as_bytes(), value).and_then(|_| self.body.execute(ev));
+            if result.is_err() || ev.end_of_iteration() {
+                break;
+            }
+        }
+        ev.loop_depth -= 1;
+        result
+    }
+}
 
-        let mut cmd: Command = Command::new(&command_name);
-        cmd.args(osargs);
+#[derive(Debug, PartialEq)]
+pub struct CaseClause {
+    pub word: Word,
+    pub items: Vec<CaseItem>
+}
 
-        // Pass environment if variables marked for export
-        for (name, val) in &ev.env {
-            if val.export{
-                cmd.env(name, OsStr::from_bytes(&val.value));
+#[derive(Debug, PartialEq)]
+pub struct CaseItem {
+    pub patterns: Vec<Word>,
+    pub body: Option<CompoundList>
+}
+
+impl CaseClause {
+    fn execute(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+        let value = self.word.eval(ev)?;
+        for item in self.items.iter() {
+            for pattern in item.patterns.iter() {
+                if expand_pattern(&pattern.text, ev)?.matches(&value) {
+                    return match &item.body {
+                        Some(body) => body.execute(ev),
+                        None => Ok(0)
+                    }
+                }
             }
         }
+        Ok(0)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IoRedirect {
+    File(Redirect),
+    Here(HereDoc)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Redirect {
+    pub fd: Option<IoNumber>,
+    pub op: RedirectionOp,
+    pub target: Word
+}
+
+/// A here-document, whose body is expanded when the delimiter is unquoted
+#[derive(Debug, PartialEq)]
+pub struct HereDoc {
+    pub fd: Option<IoNumber>,
+    pub body: Vec<u8>,
+    pub expand: bool
+}
+
+pub type AssignmentWords = Vec<(OsString, Word)>;
+
+/// Variable assignments with their values expanded
+pub type Assignments = Vec<(OsString, Vec<u8>)>;
+
+#[derive(Debug, PartialEq)]
+pub struct SimpleCommand {
+    pub assignment_words: AssignmentWords,
+    pub words: Vec<Word>,
+    pub redirects: Vec<IoRedirect>
+}
+
+impl SimpleCommand {
+    fn execute(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+        let mut fields = vec![];
+        for word in self.words.iter() {
+            fields.extend(word.eval_fields(ev)?);
+        }
 
-        // Pass assignment words
+        // A command consisting only of assignments takes the status of the
+        // last command substitution performed
+        if fields.is_empty() {
+            ev.last_status = 0;
+        }
+        let assignments = self.eval_assignments(ev)?;
+        if ev.options.xtrace {
+            trace_command(&assignments, &fields, ev);
+        }
+
+        if fields.is_empty() {
+            save_variables(&assignments, ev)?;
+            return with_redirects(&self.redirects, ev, |ev| Ok(ev.last_status))
+        }
+
+        match resolve(&fields[0], ev, Lookup::default()) {
+            // The variables assigned for special builtins and functions
+            // remain set afterwards
+            Some(Resolved::SpecialBuiltin(builtin)) => {
+                save_variables(&assignments, ev)?;
+                if fields.len() == 1 && fields[0] == b"exec" {
+                    return redirect_shell(&self.redirects, ev)
+                }
+                with_special_redirects(&self.redirects, ev, |ev| builtin(&fields[1..], ev))
+            },
+            Some(Resolved::Function(body)) => {
+                save_variables(&assignments, ev)?;
+                with_redirects(&self.redirects, ev, |ev| call_function(&body, &fields[1..], ev))
+            },
+            Some(Resolved::Builtin(builtin)) => ev.with_variables(&assignments, |ev| {
+                with_redirects(&self.redirects, ev, |ev| builtin(&fields[1..], ev))
+            }),
+            Some(Resolved::Applet(applet)) => run_applet(applet, &fields, &assignments, &self.redirects, ev),
+            Some(Resolved::Program(path, _)) => run_program(&path, &fields, &assignments, &self.redirects, ev),
+            None => with_redirects(&self.redirects, ev, |_| {
+                eprintln!("sh: {}: not found", String::from_utf8_lossy(&fields[0]));
+                Ok(127)
+            })
+        }
+    }
+
+    fn eval_assignments(&self, ev: &mut ExecEnv) -> Result<Assignments, Box<dyn Error>> {
+        let mut assignments = vec![];
         for (name, val) in self.assignment_words.iter() {
-            cmd.env(name, val);
+            assignments.push((name.clone(), val.eval_assignment(ev)?));
+        }
+        Ok(assignments)
+    }
+}
+
+/// Run the program at `path` as the command `fields`, and wait for it
+pub fn run_program(path: &Path, fields: &[Vec<u8>], assignments: &[(OsString, Vec<u8>)],
+                   redirects: &[IoRedirect], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let mut cmd = Command::new(path);
+    cmd.arg0(OsStr::from_bytes(&fields[0]));
+    cmd.args(fields[1..].iter().map(|arg| OsStr::from_bytes(arg)));
+
+    // Pass environment if variables marked for export
+    cmd.env_clear();
+    cmd.envs(ev.exported_vars());
+
+    // Pass assignment words
+    for (name, value) in assignments.iter() {
+        cmd.env(name, OsStr::from_bytes(value));
+    }
+
+    // Redirections are applied after the pipe has been connected so
+    // that they take precedence over it
+    let plan = match RedirectPlan::new(redirects, ev)? {
+        Some(plan) => plan,
+        None => return Ok(1)
+    };
+    let interactive = ev.interactive;
+    unsafe {
+        cmd.pre_exec(move || {
+            if interactive {
+                reset_signals();
+            }
+            plan.apply_in_child()
+        });
+    }
+    if ev.job_control.is_some() {
+        cmd.process_group(0);
+    }
+
+    let child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(why) => return with_redirects(redirects, ev, |_| Ok(exec_failure(&fields[0], &why)))
+    };
+    wait_foreground(ev, Job::new(vec![Pid::from_raw(child.id() as i32)], fields.join(&b' ')))
+}
+
+/// Report that the command `name` could not be executed, returning its
+/// status: 127 if the program was not found and 126 otherwise
+pub fn exec_failure(name: &[u8], why: &io::Error) -> i32 {
+    let name = String::from_utf8_lossy(name);
+    match (why.kind(), why.raw_os_error()) {
+        (io::ErrorKind::NotFound, _) => {
+            eprintln!("sh: {}: not found", name);
+            127
+        },
+        (_, Some(errno)) => {
+            eprintln!("sh: {}: {}", name, Errno::from_i32(errno).desc());
+            126
+        },
+        _ => {
+            eprintln!("sh: {}: {}", name, why);
+            126
         }
+    }
+}
 
-        Some(cmd)
+fn save_variables(assignments: &[(OsString, Vec<u8>)], ev: &mut ExecEnv) -> Result<(), Box<dyn Error>> {
+    for (name, value) in assignments.iter() {
+        ev.set_var(name.as_bytes(), value.clone())?;
     }
+    Ok(())
+}
+
+/// Write a command about to be run to stderr for xtrace, after $PS4
+fn trace_command(assignments: &[(OsString, Vec<u8>)], fields: &[Vec<u8>], ev: &mut ExecEnv) {
+    let mut line = expand_prompt(ev, b"PS4");
+    let mut words: Vec<Vec<u8>> = assignments.iter()
+        .map(|(name, value)| [name.as_bytes(), b"=", &trace_quote(value)].concat())
+        .collect();
+    words.extend(fields.iter().map(|field| trace_quote(field)));
+    line.extend_from_slice(&words.join(&b' '));
+    line.push(b'\n');
+    let _ = stderr().write_all(&line);
 }
 
+/// Quote a word for xtrace if it would not otherwise be read back as itself
+fn trace_quote(word: &[u8]) -> Vec<u8> {
+    if !word.is_empty() && word.iter().all(|c| c.is_ascii_alphanumeric() || b"%+,-./:=@_^".contains(c)) {
+        word.to_vec()
+    } else {
+        quote(word)
+    }
+}
+
+/// `name() compound-command`. The body is shared with the function table,
+/// so defining a function doesn't copy it.
 #[derive(Debug, PartialEq)]
-pub struct Word<'a> {
-    pub text: &'a [u8]
+pub struct FunctionDefinition {
+    pub name: OsString,
+    pub body: Rc<ShellCommand>
 }
 
-impl<'a> Word<'a> {
-    pub fn new(text: &'a [u8]) -> Word<'a> {
-        Word { text: text }
+impl FunctionDefinition {
+    fn execute(&self, ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+        ev.functions.insert(self.name.clone(), self.body.clone());
+        Ok(0)
+    }
+}
+
+/// Call a shell function with `args` as its positional parameters
+fn call_function(cmd: &ShellCommand, args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let saved_args = std::mem::replace(&mut ev.args, args.to_vec());
+    // break and continue cannot leave loops of the caller
+    let saved_loop_depth = std::mem::replace(&mut ev.loop_depth, 0);
+    ev.function_depth += 1;
+    let result = cmd.execute(ev);
+    ev.function_depth -= 1;
+    ev.loop_depth = saved_loop_depth;
+    ev.args = saved_args;
+
+    if ev.flow == Flow::Return {
+        ev.flow = Flow::Normal;
+    }
+    result
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Word {
+    pub text: Vec<u8>
+}
+
+impl Word {
+    pub fn new(text: &[u8]) -> Word {
+        Word { text: text.to_vec() }
+    }
+
+    pub fn eval(&self, ev: &mut ExecEnv) -> Result<Vec<u8>, Box<dyn Error>> {
+        expand_word(&self.text, ev)
+    }
+
+    /// Expand the word as the value of an assignment
+    pub fn eval_assignment(&self, ev: &mut ExecEnv) -> Result<Vec<u8>, Box<dyn Error>> {
+        expand_assignment(&self.text, ev)
     }
 
-    pub fn eval(&self) -> Vec<u8> {
-        self.text.to_vec()
+    /// Expand the word into any number of fields, as for the words of a
+    /// command
+    pub fn eval_fields(&self, ev: &mut ExecEnv) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        expand_fields(&self.text, ev)
     }
 }
 
@@ -226,7 +974,6 @@ pub enum IoHereOp {
     DLessDash
 }
 
-#[allow(dead_code)]
 pub type IoNumber = u32;
 
 #[derive(Debug, PartialEq)]
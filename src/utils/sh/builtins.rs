@@ -0,0 +1,1499 @@
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fs::OpenOptions;
+use std::io::{self, stderr, Write};
+#[cfg(feature = "printf-util")]
+use std::io::stdout;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::prelude::{OsStrExt, OsStringExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::rc::Rc;
+use std::str::FromStr;
+use nix::sys::resource::{getrusage, UsageWho};
+use nix::sys::signal::{kill, signal, SigHandler, Signal};
+use nix::sys::stat::{umask, Mode};
+use nix::sys::time::{TimeVal, TimeValLike};
+use nix::unistd::Pid;
+
+use super::applets::run_applet;
+use super::ast_nodes::{exec_failure, run_program, ExecEnv, Flow, VarValue};
+use super::error::{name_syntax_error, ExecError};
+use super::history::replace_last;
+use super::lineedit::read_byte;
+use super::jobs::{continue_job, current_job, enable_job_control, find_job, format_job, signal_job, update_jobs, wait_job, JobState};
+use super::lookup::{find_program, resolve, Lookup, Resolved, DEFAULT_PATH};
+use super::parser::{is_alias_name, is_name, is_reserved_word};
+use super::run_script;
+use super::subshell::reset_signals;
+use super::trap::{catch, pending_signal};
+#[cfg(feature = "printf-util")]
+use crate::io_util::write;
+use crate::io_util::{error_message, write_line};
+#[cfg(feature = "printf-util")]
+use crate::utils::printf::printf;
+
+pub type BuiltinMain = fn(&[Vec<u8>], &mut ExecEnv) -> Result<i32, Box<dyn Error>>;
+
+/// The POSIX special builtins. They are found before functions, variable
+/// assignments made with them persist, and their errors abort a
+/// non-interactive shell.
+static SPECIAL_BUILTINS: &[(&[u8], BuiltinMain)] = &[
+    (b".", builtin_dot),
+    (b":", builtin_colon),
+    (b"break", builtin_break),
+    (b"continue", builtin_continue),
+    (b"eval", builtin_eval),
+    (b"exec", builtin_exec),
+    (b"exit", builtin_exit),
+    (b"export", builtin_export),
+    (b"readonly", builtin_readonly),
+    (b"return", builtin_return),
+    (b"set", builtin_set),
+    (b"shift", builtin_shift),
+    (b"times", builtin_times),
+    (b"trap", builtin_trap),
+    (b"unset", builtin_unset)
+];
+
+/// Commands run by the shell itself rather than as a separate process
+static BUILTINS: &[(&[u8], BuiltinMain)] = &[
+    (b"alias", builtin_alias),
+    (b"bg", builtin_bg),
+    (b"cd", builtin_cd),
+    (b"command", builtin_command),
+    (b"fc", builtin_fc),
+    (b"fg", builtin_fg),
+    (b"getopts", builtin_getopts),
+    (b"hash", builtin_hash),
+    (b"history", builtin_history),
+    (b"jobs", builtin_jobs),
+    (b"kill", builtin_kill),
+    #[cfg(feature = "printf-util")]
+    (b"printf", builtin_printf),
+    (b"read", builtin_read),
+    (b"type", builtin_type),
+    (b"umask", builtin_umask),
+    (b"unalias", builtin_unalias),
+    (b"wait", builtin_wait)
+];
+
+pub fn find_special_builtin(name: &[u8]) -> Option<BuiltinMain> {
+    SPECIAL_BUILTINS.iter().find(|(builtin, _)| *builtin == name).map(|(_, f)| *f)
+}
+
+pub fn find_builtin(name: &[u8]) -> Option<BuiltinMain> {
+    BUILTINS.iter().find(|(builtin, _)| *builtin == name).map(|(_, f)| *f)
+}
+
+/// The names of all the builtins, for completion
+pub fn builtin_names() -> impl Iterator<Item = &'static [u8]> {
+    SPECIAL_BUILTINS.iter().chain(BUILTINS.iter()).map(|(name, _)| *name)
+}
+
+fn error(msg: String) -> Result<i32, Box<dyn Error>> {
+    Err(Box::new(ExecError::new(msg)))
+}
+
+/// Parse the numeric operand of `exit`, `return`, `shift`, `break` and
+/// `continue`
+fn number_operand(name: &str, arg: &[u8]) -> Result<usize, Box<dyn Error>> {
+    match std::str::from_utf8(arg).ok().and_then(|n| n.parse::<usize>().ok()) {
+        Some(n) => Ok(n),
+        None => Err(Box::new(ExecError::new(format!("{}: {}: numeric argument required", name, String::from_utf8_lossy(arg)))))
+    }
+}
+
+/// Quote a value so that it can be read back by the shell
+pub fn quote(value: &[u8]) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for c in value {
+        match c {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            _ => quoted.push(*c)
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// Write a line of output made of `parts`, which needn't be UTF-8
+fn print_line(parts: &[&[u8]]) -> io::Result<()> {
+    write_line(&parts.concat())
+}
+
+/// `:`
+fn builtin_colon(_args: &[Vec<u8>], _ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    Ok(0)
+}
+
+/// `. file`
+fn builtin_dot(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let name = match args.first() {
+        Some(name) => name,
+        None => return error(".: filename argument required".to_string())
+    };
+
+    // A name without a slash is searched for in PATH
+    let path = if name.contains(&b'/') {
+        Some(OsStr::from_bytes(name).into())
+    } else {
+        let path = ev.get_var(b"PATH").unwrap_or(DEFAULT_PATH);
+        path.split(|c| *c == b':')
+            .map(|dir| Path::new(OsStr::from_bytes(if dir.is_empty() { b"." } else { dir })).join(OsStr::from_bytes(name)))
+            .find(|path| path.is_file())
+    };
+    let script = match path.map(std::fs::read) {
+        Some(Ok(script)) => script,
+        Some(Err(why)) => return error(format!("{}: {}", String::from_utf8_lossy(name), why)),
+        None => return error(format!(".: {}: not found", String::from_utf8_lossy(name)))
+    };
+
+    // return may be used to leave a script run with `.`
+    ev.function_depth += 1;
+    let result = run_script(&script, ev).map_err(|e| name_syntax_error(e, &String::from_utf8_lossy(name)));
+    ev.function_depth -= 1;
+    if ev.flow == Flow::Return {
+        ev.flow = Flow::Normal;
+    }
+    result
+}
+
+/// `break [n]`
+fn builtin_break(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    leave_loops(args, ev, "break", Flow::Break)
+}
+
+/// `continue [n]`
+fn builtin_continue(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    leave_loops(args, ev, "continue", Flow::Continue)
+}
+
+fn leave_loops(args: &[Vec<u8>], ev: &mut ExecEnv, name: &str, flow: fn(usize) -> Flow) -> Result<i32, Box<dyn Error>> {
+    let n = match args.first() {
+        Some(n) => number_operand(name, n)?,
+        None => 1
+    };
+    if n == 0 {
+        return error(format!("{}: {}: loop count out of range", name, n))
+    }
+
+    // Leaving more loops than there are leaves them all
+    if ev.loop_depth > 0 {
+        ev.flow = flow(n.min(ev.loop_depth));
+    }
+    Ok(0)
+}
+
+/// `eval [arg ...]`
+fn builtin_eval(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let script = args.join(&b' ');
+    if script.iter().all(u8::is_ascii_whitespace) {
+        return Ok(0)
+    }
+    run_script(&script, ev).map_err(|e| name_syntax_error(e, "eval"))
+}
+
+/// `exec [command [arg ...]]`. Without a command the redirections are made
+/// permanent by the caller.
+fn builtin_exec(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let name = match args.first() {
+        Some(name) => name,
+        None => return Ok(0)
+    };
+
+    let path = match find_program(name, ev) {
+        Some((path, _)) => path,
+        None => PathBuf::from(OsStr::from_bytes(name))
+    };
+    let mut cmd = Command::new(path);
+    cmd.arg0(OsStr::from_bytes(name));
+    cmd.args(args[1..].iter().map(|arg| OsStr::from_bytes(arg)));
+    cmd.env_clear();
+    cmd.envs(ev.exported_vars());
+    if ev.interactive {
+        unsafe {
+            cmd.pre_exec(|| {
+                reset_signals();
+                Ok(())
+            });
+        }
+    }
+
+    // exec only returns if the command could not be run, which ends a
+    // non-interactive shell
+    let why = cmd.exec();
+    if !ev.interactive {
+        ev.flow = Flow::Exit;
+    }
+    Ok(exec_failure(name, &why))
+}
+
+/// `exit [n]`
+fn builtin_exit(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let status = match args.first() {
+        Some(n) => (number_operand("exit", n)? & 0xff) as i32,
+        None => ev.last_status
+    };
+    ev.flow = Flow::Exit;
+    Ok(status)
+}
+
+/// `export [-p] [name[=value] ...]`
+fn builtin_export(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    declare(args, ev, "export", |var| var.export, |var| var.export = true)
+}
+
+/// `readonly [-p] [name[=value] ...]`
+fn builtin_readonly(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    declare(args, ev, "readonly", |var| var.readonly, |var| var.readonly = true)
+}
+
+/// Set the attribute tested by `has` on each of the variables named in
+/// `args`, or list the variables which have it
+fn declare(args: &[Vec<u8>], ev: &mut ExecEnv, name: &str, has: fn(&VarValue) -> bool, set: fn(&mut VarValue)) -> Result<i32, Box<dyn Error>> {
+    let args = match args.first() {
+        Some(arg) if arg == b"-p" => &args[1..],
+        _ => args
+    };
+
+    if args.is_empty() {
+        let mut vars: Vec<(&OsString, &VarValue)> = ev.env.iter().filter(|(_, var)| has(var)).collect();
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        for (var_name, var) in vars {
+            match &var.value {
+                Some(value) => print_line(&[name.as_bytes(), b" ", var_name.as_bytes(), b"=", &quote(value)])?,
+                None => print_line(&[name.as_bytes(), b" ", var_name.as_bytes()])?
+            }
+        }
+        return Ok(0)
+    }
+
+    for arg in args {
+        let (var_name, value) = match arg.iter().position(|c| *c == b'=') {
+            Some(i) => (&arg[..i], Some(arg[(i + 1)..].to_vec())),
+            None => (arg.as_slice(), None)
+        };
+        if !is_name(var_name) {
+            return error(format!("{}: {}: bad variable name", name, String::from_utf8_lossy(var_name)))
+        }
+
+        if let Some(value) = value {
+            ev.set_var(var_name, value)?;
+        }
+        let var = ev.env.entry(OsString::from_vec(var_name.to_vec()))
+            .or_insert(VarValue { value: None, export: false, readonly: false });
+        set(var);
+    }
+    Ok(0)
+}
+
+/// `return [n]`
+fn builtin_return(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    if ev.function_depth == 0 {
+        eprintln!("sh: return: can only return from a function");
+        return Ok(1)
+    }
+
+    let status = match args.first() {
+        Some(n) => match std::str::from_utf8(n).ok().and_then(|n| n.parse::<i32>().ok()) {
+            Some(n) => n & 0xff,
+            None => {
+                eprintln!("sh: return: {}: numeric argument required", String::from_utf8_lossy(n));
+                2
+            }
+        },
+        None => ev.last_status
+    };
+
+    ev.flow = Flow::Return;
+    Ok(status)
+}
+
+/// `wait [pid|%job ...]`
+fn builtin_wait(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    if args.is_empty() {
+        while !ev.jobs.is_empty() {
+            if wait_job(ev, 0)?.is_none() {
+                return Ok(interrupted_status())
+            }
+        }
+        return Ok(0)
+    }
+
+    update_jobs(ev);
+    let mut status = 0;
+    for arg in args {
+        status = match find_job(ev, arg) {
+            Some(index) => match wait_job(ev, index)? {
+                Some(status) => status,
+                None => return Ok(interrupted_status())
+            },
+            None => {
+                if arg.starts_with(b"%") {
+                    eprintln!("sh: wait: {}: no such job", String::from_utf8_lossy(arg));
+                }
+                127
+            }
+        };
+    }
+    Ok(status)
+}
+
+/// The status of `wait` when a signal with a trap interrupts it
+fn interrupted_status() -> i32 {
+    128 + pending_signal().unwrap_or(0)
+}
+
+/// `set [-+m] [--] [arg ...]`
+fn builtin_set(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    if args.is_empty() {
+        let mut vars: Vec<(&OsString, &[u8])> = ev.env.iter()
+            .filter_map(|(name, var)| Some((name, var.value.as_deref()?)))
+            .collect();
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, value) in vars {
+            print_line(&[name.as_bytes(), b"=", &quote(value)])?;
+        }
+        return Ok(0)
+    }
+
+    let mut operands = None;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg == b"--" || arg == b"-" {
+            operands = Some(&args[(i + 1)..]);
+            break;
+        }
+
+        let on = match arg.first() {
+            Some(b'-') => true,
+            Some(b'+') => false,
+            _ => {
+                operands = Some(&args[i..]);
+                break;
+            }
+        };
+        for c in arg[1..].iter() {
+            if *c == b'o' {
+                i += 1;
+                match args.get(i) {
+                    Some(name) => set_named_option(ev, name, on)?,
+                    None => print_options(ev, !on)
+                }
+            } else {
+                set_option(ev, *c, on)?;
+            }
+        }
+        i += 1;
+    }
+
+    if let Some(operands) = operands {
+        ev.args = operands.to_vec();
+    }
+    Ok(0)
+}
+
+/// The names of the options for `set -o`, with their flags
+const OPTION_NAMES: [(&str, Option<u8>); 11] = [
+    ("emacs", None),
+    ("errexit", Some(b'e')),
+    ("monitor", Some(b'm')),
+    ("noclobber", Some(b'C')),
+    ("noexec", Some(b'n')),
+    ("noglob", Some(b'f')),
+    ("nounset", Some(b'u')),
+    ("pipefail", None),
+    ("verbose", Some(b'v')),
+    ("vi", None),
+    ("xtrace", Some(b'x'))
+];
+
+/// Set or unset the option with the given flag
+pub fn set_option(ev: &mut ExecEnv, option: u8, on: bool) -> Result<(), Box<dyn Error>> {
+    match option {
+        b'e' => ev.options.errexit = on,
+        b'f' => ev.options.noglob = on,
+        b'm' if on => {
+            if ev.job_control.is_none() && ev.interactive {
+                enable_job_control(ev)?;
+            }
+        },
+        b'm' => ev.job_control = None,
+        b'n' => ev.options.noexec = on,
+        b'u' => ev.options.nounset = on,
+        b'v' => ev.options.verbose = on,
+        b'x' => ev.options.xtrace = on,
+        b'C' => ev.options.noclobber = on,
+        _ => return Err(Box::new(ExecError::new(format!("set: {}{}: invalid option", if on { '-' } else { '+' }, option as char))))
+    }
+    Ok(())
+}
+
+/// Set or unset an option given by its long name, as with `set -o name`
+pub fn set_named_option(ev: &mut ExecEnv, name: &[u8], on: bool) -> Result<(), Box<dyn Error>> {
+    match OPTION_NAMES.iter().find(|(n, _)| n.as_bytes() == name) {
+        Some((_, Some(option))) => set_option(ev, *option, on),
+        Some((name, None)) => {
+            match *name {
+                "emacs" => ev.options.vi &= !on,
+                "vi" => ev.options.vi = on,
+                _ => ev.options.pipefail = on
+            }
+            Ok(())
+        },
+        None => Err(Box::new(ExecError::new(format!("set: {}: invalid option name", String::from_utf8_lossy(name)))))
+    }
+}
+
+/// Write the state of each option for `set -o`, or as commands to restore
+/// it for `set +o`
+fn print_options(ev: &ExecEnv, as_commands: bool) {
+    let flags = ev.option_flags();
+    for (name, option) in OPTION_NAMES.iter() {
+        let on = match (option, *name) {
+            (Some(option), _) => flags.contains(option),
+            (None, "emacs") => !ev.options.vi,
+            (None, "vi") => ev.options.vi,
+            (None, _) => ev.options.pipefail
+        };
+        if as_commands {
+            println!("set {}o {}", if on { '-' } else { '+' }, name);
+        } else {
+            println!("{:<15} {}", name, if on { "on" } else { "off" });
+        }
+    }
+}
+
+/// `shift [n]`
+fn builtin_shift(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let n = match args.first() {
+        Some(n) => number_operand("shift", n)?,
+        None => 1
+    };
+    if n > ev.args.len() {
+        return error("shift: can't shift that many".to_string())
+    }
+    ev.args.drain(..n);
+    Ok(0)
+}
+
+/// `trap [action condition ...]` or `trap -p [condition ...]`
+fn builtin_trap(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let args = match args.first().map(|arg| arg.as_slice()) {
+        Some(b"--") => &args[1..],
+        _ => args
+    };
+    if args.is_empty() || args[0] == b"-p" {
+        let mut numbers = vec![];
+        for condition in args.iter().skip(1) {
+            match trap_number(condition) {
+                Some(number) => numbers.push(number),
+                None => return error(format!("trap: {}: bad trap", String::from_utf8_lossy(condition)))
+            }
+        }
+        if numbers.is_empty() {
+            numbers = ev.traps.keys().copied().collect();
+            numbers.sort();
+        }
+        for number in numbers {
+            if let Some(action) = ev.traps.get(&number) {
+                print_line(&[b"trap -- ", &quote(action), b" ", trap_name(number).as_bytes()])?;
+            }
+        }
+        return Ok(0)
+    }
+
+    // A first operand which is a number is a condition to reset
+    let (action, conditions) = if args[0].iter().all(u8::is_ascii_digit) {
+        (&b"-"[..], args)
+    } else {
+        (args[0].as_slice(), &args[1..])
+    };
+
+    for condition in conditions {
+        let number = match trap_number(condition) {
+            Some(number) => number,
+            None => return error(format!("trap: {}: bad trap", String::from_utf8_lossy(condition)))
+        };
+        // A non-interactive shell keeps ignoring the signals it was started
+        // with ignored
+        if !ev.interactive && ev.ignored_signals.contains(&number) {
+            continue;
+        }
+
+        match action {
+            b"-" => {
+                ev.traps.remove(&number);
+            },
+            _ => {
+                ev.traps.insert(number, action.to_vec());
+            }
+        }
+        if number == 0 {
+            continue;
+        }
+
+        let sig = Signal::try_from(number)?;
+        let result = match action {
+            b"" => unsafe { signal(sig, SigHandler::SigIgn) }.map(drop),
+            // The keyboard signals stay ignored by an interactive shell
+            b"-" if ev.interactive && matches!(sig, Signal::SIGINT | Signal::SIGQUIT | Signal::SIGTSTP | Signal::SIGTTIN | Signal::SIGTTOU) => {
+                unsafe { signal(sig, SigHandler::SigIgn) }.map(drop)
+            },
+            b"-" => unsafe { signal(sig, SigHandler::SigDfl) }.map(drop),
+            _ => catch(sig)
+        };
+        if result.is_err() {
+            ev.traps.remove(&number);
+            return error(format!("trap: {}: cannot trap", trap_name(number)))
+        }
+    }
+    Ok(0)
+}
+
+/// The number of a trap condition given by name or number, with 0 for EXIT
+fn trap_number(condition: &[u8]) -> Option<i32> {
+    let condition = std::str::from_utf8(condition).ok()?;
+    if condition == "EXIT" || condition == "0" {
+        return Some(0)
+    }
+    if let Ok(number) = condition.parse::<i32>() {
+        return Signal::try_from(number).ok().map(|sig| sig as i32)
+    }
+
+    let name = if condition.starts_with("SIG") { condition.to_string() } else { format!("SIG{}", condition) };
+    Signal::from_str(&name).ok().map(|sig| sig as i32)
+}
+
+fn trap_name(number: i32) -> String {
+    match Signal::try_from(number) {
+        Ok(sig) if number != 0 => sig.as_str().trim_start_matches("SIG").to_string(),
+        _ => "EXIT".to_string()
+    }
+}
+
+/// Run the EXIT trap, if one is set, as the shell exits with `status`.
+/// Returns the status the shell should exit with.
+pub fn run_exit_trap(ev: &mut ExecEnv, status: i32) -> i32 {
+    let action = match ev.traps.remove(&0) {
+        Some(action) => action,
+        None => return status
+    };
+
+    ev.flow = Flow::Normal;
+    ev.last_status = status;
+    if let Err(e) = run_script(&action, ev) {
+        eprintln!("sh: {}", e);
+    }
+    match ev.flow {
+        Flow::Exit => ev.last_status,
+        _ => status
+    }
+}
+
+/// `times`
+fn builtin_times(_args: &[Vec<u8>], _ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    for who in [UsageWho::RUSAGE_SELF, UsageWho::RUSAGE_CHILDREN] {
+        let usage = getrusage(who)?;
+        let times = format!("{} {}", format_time(usage.user_time()), format_time(usage.system_time()));
+        print_line(&[times.as_bytes()])?;
+    }
+    Ok(0)
+}
+
+/// Format a time as minutes and seconds, as `times` prints them
+fn format_time(time: TimeVal) -> String {
+    let millis = time.num_milliseconds();
+    format!("{}m{}.{:03}s", millis / 60000, millis / 1000 % 60, millis % 1000)
+}
+
+/// `unset [-fv] name ...`
+fn builtin_unset(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let (functions, names) = match args.first().map(|arg| arg.as_slice()) {
+        Some(b"-f") => (true, &args[1..]),
+        Some(b"-v") => (false, &args[1..]),
+        _ => (false, args)
+    };
+
+    for name in names {
+        if functions {
+            ev.functions.remove(OsStr::from_bytes(name));
+        } else {
+            ev.unset_var(name)?;
+        }
+    }
+    Ok(0)
+}
+
+/// Assign or, for None, unset the variables set by `read` or `getopts`,
+/// reporting any which can't be changed. Returns whether all were.
+fn assign_results(command: &str, results: &[(&[u8], Option<Vec<u8>>)], ev: &mut ExecEnv) -> bool {
+    for (name, value) in results {
+        let result = match value {
+            Some(value) => ev.set_var(name, value.clone()),
+            None => ev.unset_var(name)
+        };
+        if let Err(e) = result {
+            eprintln!("sh: {}: {}", command, e);
+            return false
+        }
+    }
+    true
+}
+
+/// Read a line from stdin a byte at a time, so that none of the input
+/// after it is consumed. Unless `raw`, a backslash quotes the next
+/// character and joins lines. Returns the line with whether each byte was
+/// quoted, and whether the line was ended by a newline.
+fn read_line(raw: bool) -> io::Result<(Vec<u8>, Vec<bool>, bool)> {
+    let mut line = vec![];
+    let mut quoted = vec![];
+    while let Some(c) = read_byte()? {
+        match c {
+            b'\n' => return Ok((line, quoted, true)),
+            b'\\' if !raw => match read_byte()? {
+                Some(b'\n') => (),
+                Some(c) => {
+                    line.push(c);
+                    quoted.push(true);
+                },
+                None => break
+            },
+            _ => {
+                line.push(c);
+                quoted.push(false);
+            }
+        }
+    }
+    Ok((line, quoted, false))
+}
+
+/// Split a line read by `read` into `count` fields at the unquoted
+/// characters of `ifs`, the last field taking the rest of the line
+fn split_read_fields(line: &[u8], quoted: &[bool], ifs: &[u8], count: usize) -> Vec<Vec<u8>> {
+    let is_ifs = |i: usize| !quoted[i] && ifs.contains(&line[i]);
+    let is_ifs_white = |i: usize| is_ifs(i) && b" \t\n".contains(&line[i]);
+    let skip_white = |mut i: usize| {
+        while i < line.len() && is_ifs_white(i) {
+            i += 1;
+        }
+        i
+    };
+
+    let mut fields = vec![];
+    let mut i = skip_white(0);
+    while fields.len() + 1 < count && i < line.len() {
+        let start = i;
+        while i < line.len() && !is_ifs(i) {
+            i += 1;
+        }
+        fields.push(line[start..i].to_vec());
+
+        // A delimiter is white space with at most one other IFS character
+        i = skip_white(i);
+        if i < line.len() && is_ifs(i) {
+            i = skip_white(i + 1);
+        }
+    }
+    let mut end = line.len();
+    while end > i && is_ifs_white(end - 1) {
+        end -= 1;
+    }
+    fields.push(line[i.min(end)..end].to_vec());
+    fields.resize(count, vec![]);
+    fields
+}
+
+/// `read [-r] [name ...]`
+fn builtin_read(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let mut raw = false;
+    let mut i = 0;
+    while let Some(arg) = args.get(i) {
+        if arg == b"--" {
+            i += 1;
+            break;
+        }
+        if arg.len() < 2 || arg[0] != b'-' {
+            break;
+        }
+        for c in arg[1..].iter() {
+            match c {
+                b'r' => raw = true,
+                _ => {
+                    eprintln!("sh: read: -{}: invalid option", *c as char);
+                    return Ok(2)
+                }
+            }
+        }
+        i += 1;
+    }
+    let names = &args[i..];
+    if let Some(name) = names.iter().find(|name| !is_name(name)) {
+        eprintln!("sh: read: {}: bad variable name", String::from_utf8_lossy(name));
+        return Ok(2)
+    }
+
+    let (line, quoted, complete) = match read_line(raw) {
+        Ok(line) => line,
+        Err(why) => {
+            eprintln!("sh: read: {}", why);
+            return Ok(2)
+        }
+    };
+
+    // Without names the whole line is assigned to REPLY
+    let results: Vec<(&[u8], Option<Vec<u8>>)> = if names.is_empty() {
+        vec![(b"REPLY", Some(line))]
+    } else {
+        let ifs = ev.get_var(b"IFS").unwrap_or(b" \t\n");
+        let fields = split_read_fields(&line, &quoted, ifs, names.len());
+        names.iter().map(|name| name.as_slice()).zip(fields.into_iter().map(Some)).collect()
+    };
+    if !assign_results("read", &results, ev) {
+        return Ok(2)
+    }
+    Ok(if complete { 0 } else { 1 })
+}
+
+/// `getopts optstring name [arg ...]`
+fn builtin_getopts(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    if args.len() < 2 {
+        eprintln!("sh: getopts: usage: getopts optstring name [arg ...]");
+        return Ok(2)
+    }
+    let (optstring, name) = (&args[0], args[1].as_slice());
+    if !is_name(name) {
+        eprintln!("sh: getopts: {}: bad variable name", String::from_utf8_lossy(name));
+        return Ok(2)
+    }
+    let params = if args.len() > 2 { &args[2..] } else { &ev.args[..] };
+    // A leading colon selects silent error reporting through OPTARG
+    let silent = optstring.first() == Some(&b':');
+
+    let mut optind = ev.get_var(b"OPTIND")
+        .and_then(|n| std::str::from_utf8(n).ok()?.parse::<usize>().ok())
+        .unwrap_or(1)
+        .max(1);
+    let mut offset = ev.getopts_offset;
+    let arg = match params.get(optind - 1) {
+        Some(arg) if arg == b"--" => None,
+        Some(arg) if arg.len() > 1 && arg[0] == b'-' && offset < arg.len() => Some(arg.clone()),
+        _ => None
+    };
+    let arg = match arg {
+        Some(arg) => arg,
+        None => {
+            // The end of the options, stepping over a "--" which ends them
+            if params.get(optind - 1).is_some_and(|arg| arg == b"--") {
+                optind += 1;
+            }
+            let optind = optind.to_string().into_bytes();
+            if !assign_results("getopts", &[(name, Some(b"?".to_vec())), (b"OPTARG", None), (b"OPTIND", Some(optind))], ev) {
+                return Ok(2)
+            }
+            return Ok(1)
+        }
+    };
+
+    let c = arg[offset];
+    offset += 1;
+    if offset == arg.len() {
+        optind += 1;
+        offset = 1;
+    }
+    let spec = optstring.iter().position(|o| *o == c && c != b':');
+    let (option, optarg) = match spec.map(|i| optstring.get(i + 1) == Some(&b':')) {
+        None => {
+            if !silent {
+                eprintln!("sh: illegal option -- {}", c as char);
+            }
+            (b'?', silent.then(|| vec![c]))
+        },
+        Some(false) => (c, None),
+        // The option argument is the rest of this argument or the next one
+        Some(true) if offset > 1 => {
+            let value = arg[offset..].to_vec();
+            optind += 1;
+            offset = 1;
+            (c, Some(value))
+        },
+        Some(true) => match params.get(optind - 1) {
+            Some(value) => {
+                optind += 1;
+                (c, Some(value.clone()))
+            },
+            None if silent => (b':', Some(vec![c])),
+            None => {
+                eprintln!("sh: option requires an argument -- {}", c as char);
+                (b'?', None)
+            }
+        }
+    };
+
+    let optind = optind.to_string().into_bytes();
+    if !assign_results("getopts", &[(name, Some(vec![option])), (b"OPTARG", optarg), (b"OPTIND", Some(optind))], ev) {
+        return Ok(2)
+    }
+    ev.getopts_offset = offset;
+    Ok(0)
+}
+
+/// `printf format [argument ...]`
+#[cfg(feature = "printf-util")]
+fn builtin_printf(args: &[Vec<u8>], _ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let args = match args.first().map(|arg| arg.as_slice()) {
+        Some(b"--") => &args[1..],
+        _ => args
+    };
+    let format = match args.first() {
+        Some(format) => format,
+        None => {
+            eprintln!("sh: printf: missing format");
+            return Ok(2)
+        }
+    };
+
+    let formatted = printf(format, &args[1..]);
+    // The output is flushed as it may not end with a newline
+    if let Err(why) = write(&formatted.output).and_then(|_| stdout().flush()) {
+        eprintln!("sh: printf: {}", why);
+        return Ok(1)
+    }
+    for error in formatted.errors.iter() {
+        eprintln!("sh: printf: {}", error);
+    }
+    Ok(if formatted.errors.is_empty() { 0 } else { 1 })
+}
+
+/// `alias [-p] [name[=value] ...]`
+fn builtin_alias(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let args = match args.first().map(|arg| arg.as_slice()) {
+        Some(b"-p") | Some(b"--") => &args[1..],
+        _ => args
+    };
+    if args.is_empty() {
+        let mut names: Vec<&Vec<u8>> = ev.aliases.keys().collect();
+        names.sort();
+        for name in names {
+            print_line(&[b"alias ", name, b"=", &quote(&ev.aliases[name])])?;
+        }
+        return Ok(0)
+    }
+
+    let mut status = 0;
+    for arg in args {
+        match arg.iter().position(|c| *c == b'=') {
+            Some(eq) if is_alias_name(&arg[..eq]) => {
+                Rc::make_mut(&mut ev.aliases).insert(arg[..eq].to_vec(), arg[(eq + 1)..].to_vec());
+            },
+            Some(eq) => {
+                eprintln!("sh: alias: {}: invalid alias name", String::from_utf8_lossy(&arg[..eq]));
+                status = 1;
+            },
+            None => match ev.aliases.get(arg) {
+                Some(value) => print_line(&[b"alias ", arg, b"=", &quote(value)])?,
+                None => {
+                    eprintln!("sh: alias: {}: not found", String::from_utf8_lossy(arg));
+                    status = 1;
+                }
+            }
+        }
+    }
+    Ok(status)
+}
+
+/// `unalias -a` or `unalias name ...`
+fn builtin_unalias(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    if args.first().map(|arg| arg.as_slice()) == Some(b"-a") {
+        Rc::make_mut(&mut ev.aliases).clear();
+        return Ok(0)
+    }
+    if args.is_empty() {
+        eprintln!("sh: unalias: usage: unalias [-a] name ...");
+        return Ok(2)
+    }
+
+    let mut status = 0;
+    for name in args {
+        if Rc::make_mut(&mut ev.aliases).remove(name).is_none() {
+            eprintln!("sh: unalias: {}: not found", String::from_utf8_lossy(name));
+            status = 1;
+        }
+    }
+    Ok(status)
+}
+
+/// `umask [-S] [mask]`
+fn builtin_umask(args: &[Vec<u8>], _ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let (symbolic, args) = match args.first().map(|arg| arg.as_slice()) {
+        Some(b"-S") => (true, &args[1..]),
+        Some(b"--") => (false, &args[1..]),
+        _ => (false, args)
+    };
+
+    // The mask can only be read by setting it
+    let current = umask(Mode::empty());
+    umask(current);
+    let current = current.bits() & 0o777;
+
+    let mode = match args.first() {
+        Some(mode) => mode,
+        None => {
+            if symbolic {
+                let allowed = !current;
+                let classes = [(b'u', 6), (b'g', 3), (b'o', 0)].map(|(class, shift)| {
+                    let mut text = vec![class, b'='];
+                    text.extend(b"rwx".iter().zip([4, 2, 1]).filter(|(_, bit)| allowed >> shift & bit != 0).map(|(c, _)| *c));
+                    text
+                });
+                print_line(&[&classes.join(&b',')])?;
+            } else {
+                print_line(&[format!("{:04o}", current).as_bytes()])?;
+            }
+            return Ok(0)
+        }
+    };
+
+    let mask = if mode.first().is_some_and(u8::is_ascii_digit) {
+        std::str::from_utf8(mode).ok()
+            .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+            .filter(|mask| *mask <= 0o777)
+    } else {
+        symbolic_mask(mode, current)
+    };
+    match mask {
+        Some(mask) => {
+            umask(Mode::from_bits_truncate(mask));
+            Ok(0)
+        },
+        None => {
+            eprintln!("sh: umask: {}: invalid mode", String::from_utf8_lossy(mode));
+            Ok(1)
+        }
+    }
+}
+
+/// Apply a symbolic mode such as `u=rwx,go-w`, which gives the permissions
+/// allowed rather than those masked, to the file mode creation mask `mask`
+fn symbolic_mask(mode: &[u8], mask: u32) -> Option<u32> {
+    let mut allowed = !mask & 0o777;
+    for clause in mode.split(|c| *c == b',') {
+        let mut i = 0;
+        let mut who = 0;
+        while let Some(c) = clause.get(i) {
+            who |= match c {
+                b'u' => 0o700,
+                b'g' => 0o070,
+                b'o' => 0o007,
+                b'a' => 0o777,
+                _ => break
+            };
+            i += 1;
+        }
+        if who == 0 {
+            who = 0o777;
+        }
+        if i == clause.len() {
+            return None
+        }
+
+        while let Some(op) = clause.get(i) {
+            if !b"+-=".contains(op) {
+                return None
+            }
+            i += 1;
+            let mut perms = 0;
+            while let Some(c) = clause.get(i) {
+                perms |= match c {
+                    b'r' => 0o444,
+                    b'w' => 0o222,
+                    b'x' => 0o111,
+                    // Copy the permissions of another class
+                    b'u' => (allowed >> 6 & 0o7) * 0o111,
+                    b'g' => (allowed >> 3 & 0o7) * 0o111,
+                    b'o' => (allowed & 0o7) * 0o111,
+                    _ => break
+                };
+                i += 1;
+            }
+            let perms = perms & who;
+            allowed = match op {
+                b'+' => allowed | perms,
+                b'-' => allowed & !perms,
+                _ => allowed & !who | perms
+            };
+        }
+    }
+    Some(!allowed & 0o777)
+}
+
+/// `cd [-L|-P] [directory|-]`
+fn builtin_cd(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let args = match args.first().map(|arg| arg.as_slice()) {
+        Some(b"-L") | Some(b"-P") => &args[1..],
+        _ => args
+    };
+
+    let (dir, print) = match args.first().map(|arg| arg.as_slice()) {
+        Some(b"-") => match ev.get_var(b"OLDPWD") {
+            Some(dir) => (dir.to_vec(), true),
+            None => {
+                eprintln!("sh: cd: OLDPWD not set");
+                return Ok(1)
+            }
+        },
+        Some(dir) => (dir.to_vec(), false),
+        None => match ev.get_var(b"HOME") {
+            Some(dir) => (dir.to_vec(), false),
+            None => {
+                eprintln!("sh: cd: HOME not set");
+                return Ok(1)
+            }
+        }
+    };
+
+    let old = std::env::current_dir().ok();
+    if let Err(why) = std::env::set_current_dir(OsStr::from_bytes(&dir)) {
+        eprintln!("sh: cd: {}: {}", String::from_utf8_lossy(&dir), error_message(&why));
+        return Ok(1)
+    }
+
+    if let Some(old) = old {
+        ev.set_var(b"OLDPWD", old.into_os_string().into_vec())?;
+    }
+    let new = std::env::current_dir()?;
+    if print {
+        print_line(&[new.as_os_str().as_bytes()])?;
+    }
+    ev.set_var(b"PWD", new.into_os_string().into_vec())?;
+    Ok(0)
+}
+
+/// `command [-p] name [arg ...]` or `command [-p] -v|-V name ...`
+fn builtin_command(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let mut lookup = Lookup { skip_functions: true, default_path: false };
+    let mut describe = None;
+    let mut i = 0;
+    while let Some(arg) = args.get(i) {
+        if arg == b"--" {
+            i += 1;
+            break;
+        }
+        if arg.len() < 2 || arg[0] != b'-' {
+            break;
+        }
+        for c in arg[1..].iter() {
+            match c {
+                b'p' => lookup.default_path = true,
+                b'v' => describe = Some(false),
+                b'V' => describe = Some(true),
+                _ => return error(format!("command: -{}: invalid option", *c as char))
+            }
+        }
+        i += 1;
+    }
+    let args = &args[i..];
+    let name = match args.first() {
+        Some(name) => name,
+        None => return Ok(0)
+    };
+
+    if let Some(verbose) = describe {
+        lookup.skip_functions = false;
+        let mut status = 0;
+        for name in args {
+            if !describe_command(name, ev, lookup, verbose)? {
+                status = 1;
+            }
+        }
+        return Ok(status)
+    }
+
+    match resolve(name, ev, lookup) {
+        // Errors of special builtins run this way don't make the shell exit
+        Some(Resolved::SpecialBuiltin(builtin) | Resolved::Builtin(builtin)) => match builtin(&args[1..], ev) {
+            Ok(status) => Ok(status),
+            Err(e) => {
+                eprintln!("sh: {}", e);
+                Ok(1)
+            }
+        },
+        Some(Resolved::Applet(applet)) => run_applet(applet, args, &[], &[], ev),
+        Some(Resolved::Program(path, _)) => run_program(&path, args, &[], &[], ev),
+        // Functions are skipped
+        Some(Resolved::Function(_)) | None => {
+            eprintln!("sh: {}: not found", String::from_utf8_lossy(name));
+            Ok(127)
+        }
+    }
+}
+
+/// `type name ...`
+fn builtin_type(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let mut status = 0;
+    for name in args {
+        if !describe_command(name, ev, Lookup::default(), true)? {
+            status = 1;
+        }
+    }
+    Ok(status)
+}
+
+/// Write what the command `name` would run, as a sentence for `command -V`
+/// and `type` or as the name or path to run for `command -v`. Returns
+/// whether it was found.
+fn describe_command(name: &[u8], ev: &mut ExecEnv, lookup: Lookup, verbose: bool) -> io::Result<bool> {
+    if let Some(value) = ev.aliases.get(name) {
+        if verbose {
+            print_line(&[name, b" is an alias for ", value])?;
+        } else {
+            print_line(&[b"alias ", name, b"=", &quote(value)])?;
+        }
+        return Ok(true)
+    }
+    let kind = if is_reserved_word(name) {
+        "a shell keyword"
+    } else {
+        match resolve(name, ev, lookup) {
+            Some(Resolved::SpecialBuiltin(_)) => "a special shell builtin",
+            Some(Resolved::Function(_)) => "a function",
+            Some(Resolved::Builtin(_)) => "a shell builtin",
+            Some(Resolved::Applet(_)) => "a rustybox applet",
+            Some(Resolved::Program(path, hashed)) => {
+                let path = std::env::current_dir().map(|dir| dir.join(&path)).unwrap_or(path);
+                let path = path.as_os_str().as_bytes();
+                match (verbose, hashed) {
+                    (false, _) => print_line(&[path])?,
+                    (true, false) => print_line(&[name, b" is ", path])?,
+                    (true, true) => print_line(&[name, b" is hashed (", path, b")"])?
+                }
+                return Ok(true)
+            },
+            None => {
+                if verbose {
+                    eprintln!("sh: {}: not found", String::from_utf8_lossy(name));
+                }
+                return Ok(false)
+            }
+        }
+    };
+    if verbose {
+        print_line(&[name, b" is ", kind.as_bytes()])?;
+    } else {
+        print_line(&[name])?;
+    }
+    Ok(true)
+}
+
+/// `hash [-r] [name ...]`
+fn builtin_hash(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    if args.is_empty() {
+        let mut paths: Vec<(&OsString, &PathBuf)> = ev.command_hash.iter().collect();
+        paths.sort();
+        for (_, path) in paths {
+            print_line(&[path.as_os_str().as_bytes()])?;
+        }
+        return Ok(0)
+    }
+
+    let names = match args.first() {
+        Some(arg) if arg == b"-r" => {
+            ev.command_hash.clear();
+            &args[1..]
+        },
+        _ => args
+    };
+    let mut status = 0;
+    for name in names {
+        // Only programs are remembered, but any command may be named
+        if resolve(name, ev, Lookup::default()).is_none() {
+            eprintln!("sh: hash: {}: not found", String::from_utf8_lossy(name));
+            status = 1;
+        }
+    }
+    Ok(status)
+}
+
+/// `history`
+fn builtin_history(_args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    for (i, entry) in ev.history.entries.iter().enumerate() {
+        print_line(&[format!("{:5}  ", ev.history.first + i).as_bytes(), entry])?;
+    }
+    Ok(0)
+}
+
+/// `jobs`
+fn builtin_jobs(_args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    update_jobs(ev);
+    let current = current_job(ev);
+    for (index, job) in ev.jobs.iter().enumerate() {
+        print_line(&[&format_job(job, current == Some(index), job.state())])?;
+    }
+    ev.jobs.retain(|job| !matches!(job.state(), JobState::Done(_)));
+    Ok(0)
+}
+
+/// `fc [-r] [-e editor] [first [last]]`, `fc -l [-nr] [first [last]]` or
+/// `fc -s [old=new] [first]`
+fn builtin_fc(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let (mut list, mut numbered, mut reverse, mut substitute) = (false, true, false, false);
+    let mut editor = None;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg == b"--" {
+            i += 1;
+            break;
+        }
+        // A negative number is an operand rather than options
+        if arg.len() < 2 || arg[0] != b'-' || arg[1].is_ascii_digit() {
+            break;
+        }
+        for c in arg[1..].iter() {
+            match c {
+                b'l' => list = true,
+                b'n' => numbered = false,
+                b'r' => reverse = true,
+                b's' => substitute = true,
+                b'e' => {
+                    i += 1;
+                    match args.get(i) {
+                        Some(name) => editor = Some(name.clone()),
+                        None => return error("fc: -e: editor name expected".to_string())
+                    }
+                },
+                _ => return error(format!("fc: -{}: invalid option", *c as char))
+            }
+        }
+        i += 1;
+    }
+    let mut operands = &args[i..];
+
+    // The fc command just entered is not one of those it can refer to
+    let end = match ev.history.next_number() - ev.interactive as usize {
+        end if end > ev.history.first => end,
+        _ => return error("fc: no history".to_string())
+    };
+    let history_number = |operand: &[u8]| -> Result<usize, Box<dyn Error>> {
+        if let Some(n) = std::str::from_utf8(operand).ok().and_then(|n| n.parse::<i64>().ok()) {
+            let n = if n < 0 { end as i64 + n } else { n };
+            return Ok(n.clamp(ev.history.first as i64, end as i64 - 1) as usize)
+        }
+        (ev.history.first..end).rev()
+            .find(|n| ev.history.get(*n).is_some_and(|entry| entry.starts_with(operand)))
+            .ok_or_else(|| ExecError::new(format!("fc: {}: event not found", String::from_utf8_lossy(operand))).into())
+    };
+
+    if substitute {
+        let (old, new) = match operands.first().and_then(|arg| arg.iter().position(|c| *c == b'=')) {
+            Some(eq) => {
+                let arg = &operands[0];
+                operands = &operands[1..];
+                (arg[..eq].to_vec(), arg[(eq + 1)..].to_vec())
+            },
+            None => (vec![], vec![])
+        };
+        let number = history_number(operands.first().map(|arg| arg.as_slice()).unwrap_or(b"-1"))?;
+        let mut command = ev.history.get(number).unwrap_or_default().to_vec();
+        if let Some(at) = command.windows(old.len()).position(|w| !old.is_empty() && w == old.as_slice()) {
+            command.splice(at..(at + old.len()), new);
+        }
+        eprintln!("{}", String::from_utf8_lossy(&command));
+        replace_last(ev, &command);
+        return run_script(&command, ev)
+    }
+
+    let default_first: &[u8] = if list { b"-16" } else { b"-1" };
+    let first = history_number(operands.first().map(|arg| arg.as_slice()).unwrap_or(default_first))?;
+    let last = match operands.get(1) {
+        Some(last) => history_number(last)?,
+        None if list => end - 1,
+        None => first
+    };
+    let mut numbers: Vec<usize> = if first <= last {
+        (first..=last).collect()
+    } else {
+        (last..=first).rev().collect()
+    };
+    if reverse {
+        numbers.reverse();
+    }
+
+    if list {
+        for n in numbers {
+            let entry = ev.history.get(n).unwrap_or_default();
+            if numbered {
+                print_line(&[n.to_string().as_bytes(), b"\t", entry])?;
+            } else {
+                print_line(&[b"\t", entry])?;
+            }
+        }
+        return Ok(0)
+    }
+
+    // Edit the commands, then run them as edited
+    let mut commands = vec![];
+    for n in numbers {
+        commands.extend_from_slice(ev.history.get(n).unwrap_or_default());
+        commands.push(b'\n');
+    }
+    let path = fc_file(&commands)?;
+    let editor = editor
+        .or_else(|| ev.get_var(b"FCEDIT").map(|e| e.to_vec()))
+        .unwrap_or_else(|| b"ed".to_vec());
+    let mut edit = editor;
+    edit.push(b' ');
+    edit.extend_from_slice(&quote(path.as_os_str().as_bytes()));
+    let status = run_script(&edit, ev)?;
+    let commands = std::fs::read(&path);
+    let _ = std::fs::remove_file(&path);
+    if status != 0 {
+        return Ok(status)
+    }
+
+    let commands = commands?;
+    stderr().write_all(&commands)?;
+    replace_last(ev, &commands);
+    run_script(&commands, ev)
+}
+
+/// Create a new file holding the commands for `fc` to edit, returning its
+/// path
+fn fc_file(commands: &[u8]) -> io::Result<PathBuf> {
+    let mut n = 0;
+    loop {
+        let path = std::env::temp_dir().join(format!("sh-fc-{}-{}", std::process::id(), n));
+        n += 1;
+        match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+            Ok(mut file) => {
+                file.write_all(commands)?;
+                return Ok(path)
+            },
+            Err(why) if why.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(why) => return Err(why)
+        }
+    }
+}
+
+/// `fg [%job]`
+fn builtin_fg(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let spec = args.first().map(|arg| arg.as_slice()).unwrap_or(b"%+");
+    match job_operand("fg", spec, ev) {
+        Some(index) => continue_job(ev, index, true),
+        None => Ok(1)
+    }
+}
+
+/// `bg [%job ...]`
+fn builtin_bg(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let specs: Vec<&[u8]> = match args.len() {
+        0 => vec![b"%+"],
+        _ => args.iter().map(|arg| arg.as_slice()).collect()
+    };
+
+    let mut status = 0;
+    for spec in specs {
+        status = match job_operand("bg", spec, ev) {
+            Some(index) => continue_job(ev, index, false)?,
+            None => 1
+        };
+    }
+    Ok(status)
+}
+
+/// `kill [-s signal|-signal] pid|%job ...` or `kill -l [status]`
+fn builtin_kill(args: &[Vec<u8>], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let (name, operands) = match args.first().map(|arg| arg.as_slice()) {
+        Some(b"-l") => {
+            match args.get(1) {
+                Some(status) => {
+                    // An exit status names the signal which killed a command
+                    let number = std::str::from_utf8(status).ok().and_then(|n| n.parse::<i32>().ok());
+                    match number.map(|n| if n > 128 { n - 128 } else { n }).and_then(|n| Signal::try_from(n).ok()) {
+                        Some(sig) => print_line(&[trap_name(sig as i32).as_bytes()])?,
+                        None => {
+                            eprintln!("sh: kill: {}: invalid signal specification", String::from_utf8_lossy(status));
+                            return Ok(1)
+                        }
+                    }
+                },
+                None => for sig in Signal::iterator() {
+                    print_line(&[trap_name(sig as i32).as_bytes()])?;
+                }
+            }
+            return Ok(0)
+        },
+        Some(b"-s") => match args.get(1) {
+            Some(name) => (name.as_slice(), &args[2..]),
+            None => {
+                eprintln!("sh: kill: -s: option requires an argument");
+                return Ok(2)
+            }
+        },
+        Some(b"--") => (&b"TERM"[..], &args[1..]),
+        Some([b'-', name @ ..]) if !name.is_empty() => (name, &args[1..]),
+        _ => (&b"TERM"[..], args)
+    };
+    let sig = match name {
+        b"0" => None,
+        _ => match trap_number(name).filter(|number| *number != 0) {
+            Some(number) => Signal::try_from(number).ok(),
+            None => {
+                eprintln!("sh: kill: {}: invalid signal specification", String::from_utf8_lossy(name));
+                return Ok(1)
+            }
+        }
+    };
+    if operands.is_empty() {
+        eprintln!("sh: kill: usage: kill [-s signal | -signal] pid | %job ...");
+        return Ok(2)
+    }
+
+    let mut status = 0;
+    for operand in operands {
+        let result = if operand.starts_with(b"%") {
+            update_jobs(ev);
+            match find_job(ev, operand) {
+                Some(index) => signal_job(ev, index, sig),
+                None => {
+                    eprintln!("sh: kill: {}: no such job", String::from_utf8_lossy(operand));
+                    status = 1;
+                    continue;
+                }
+            }
+        } else {
+            match std::str::from_utf8(operand).ok().and_then(|pid| pid.parse::<i32>().ok()) {
+                Some(pid) => kill(Pid::from_raw(pid), sig),
+                None => {
+                    eprintln!("sh: kill: {}: arguments must be process or job IDs", String::from_utf8_lossy(operand));
+                    status = 1;
+                    continue;
+                }
+            }
+        };
+        if let Err(errno) = result {
+            eprintln!("sh: kill: {}: {}", String::from_utf8_lossy(operand), errno.desc());
+            status = 1;
+        }
+    }
+    Ok(status)
+}
+
+/// The index of the job named by `spec`, reporting an error if there is no
+/// such job or job control is off
+fn job_operand(name: &str, spec: &[u8], ev: &mut ExecEnv) -> Option<usize> {
+    if ev.job_control.is_none() {
+        eprintln!("sh: {}: no job control", name);
+        return None
+    }
+
+    update_jobs(ev);
+    let index = find_job(ev, spec).filter(|index| !matches!(ev.jobs[*index].state(), JobState::Done(_)));
+    if index.is_none() {
+        eprintln!("sh: {}: {}: no such job", name, String::from_utf8_lossy(spec));
+    }
+    index
+}
@@ -1,24 +1,52 @@
 use std::fmt;
+use std::error::Error;
 use nom::error::{ParseError, ErrorKind};
 
 
 pub type RbResult<I, O, E = RbError> = Result<(I, O), nom::Err<E>>;
 
 
+/// What kind of problem a parser found in its input
+#[derive(Debug, PartialEq)]
+pub enum RbErrorKind {
+    /// A syntax error, with the nom parser which detected it
+    Syntax(ErrorKind),
+    /// A syntax error at a token which can't appear where it does
+    Unexpected(Vec<u8>),
+    /// The input ended before the command did, so more input is needed to
+    /// tell whether it is valid
+    Incomplete
+}
+
 #[derive(Debug, PartialEq)]
 pub struct RbError {
-    pub code: ErrorKind
+    pub kind: RbErrorKind,
+    /// The byte offset in the input where the error was found. This is
+    /// only known for errors from the parser's entry points.
+    pub offset: usize
 }
 
 impl RbError {
     pub fn new(code: ErrorKind) -> Self {
-        RbError {code: code}
+        RbError {kind: RbErrorKind::Syntax(code), offset: 0}
+    }
+
+    pub fn unexpected(token: &[u8], offset: usize) -> Self {
+        RbError {kind: RbErrorKind::Unexpected(token.to_vec()), offset}
+    }
+
+    pub fn incomplete(offset: usize) -> Self {
+        RbError {kind: RbErrorKind::Incomplete, offset}
+    }
+
+    pub fn is_incomplete(&self) -> bool {
+        self.kind == RbErrorKind::Incomplete
     }
 }
 
 impl<I> ParseError<I> for RbError {
     fn from_error_kind(_: I, kind: ErrorKind) -> Self {
-        Self {code: kind}
+        Self::new(kind)
     }
 
     fn append(_: I, _: ErrorKind, other: Self) -> Self {
@@ -28,6 +56,93 @@ impl<I> ParseError<I> for RbError {
 
 impl fmt::Display for RbError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "error {:?}", self.code)
+        match &self.kind {
+            RbErrorKind::Syntax(code) => write!(f, "syntax error ({:?})", code),
+            RbErrorKind::Unexpected(token) => {
+                write!(f, "syntax error near unexpected token '{}'", String::from_utf8_lossy(token))
+            },
+            RbErrorKind::Incomplete => write!(f, "syntax error: unexpected end of file")
+        }
+    }
+}
+
+/// A syntax error in a script, located by its line number and, once known,
+/// the name of the file or builtin the script came from
+#[derive(Debug)]
+pub struct SyntaxError {
+    pub name: Option<String>,
+    pub line: usize,
+    pub error: RbError
+}
+
+impl SyntaxError {
+    pub fn new(input: &[u8], error: RbError) -> Self {
+        let (line, _) = line_column(input, error.offset);
+        SyntaxError {name: None, line, error}
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{}: ", name)?;
+        }
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
+
+impl Error for SyntaxError {}
+
+/// Name the source of a syntax error, unless a script run from it already
+/// named its own
+pub fn name_syntax_error(e: Box<dyn Error>, name: &str) -> Box<dyn Error> {
+    match e.downcast::<SyntaxError>() {
+        Ok(mut e) => {
+            e.name.get_or_insert_with(|| name.to_string());
+            e
+        },
+        Err(e) => e
+    }
+}
+
+/// The line and column, counting from 1, of a byte offset in `input`
+pub fn line_column(input: &[u8], offset: usize) -> (usize, usize) {
+    let before = &input[..offset.min(input.len())];
+    let line_start = before.iter().rposition(|c| *c == b'\n').map_or(0, |i| i + 1);
+    let line = before.iter().filter(|c| **c == b'\n').count() + 1;
+    (line, before.len() - line_start + 1)
+}
+
+/// An error raised while executing a command which aborts the command
+/// being run (and the shell itself when it is not interactive)
+#[derive(Debug, PartialEq)]
+pub struct ExecError {
+    pub msg: String
+}
+
+impl ExecError {
+    pub fn new(msg: String) -> Self {
+        ExecError {msg}
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl Error for ExecError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_column() {
+        let input = b"echo a\nif true\nthen fi\n";
+        assert_eq!(line_column(input, 0), (1, 1));
+        assert_eq!(line_column(input, 7), (2, 1));
+        assert_eq!(line_column(input, 20), (3, 6));
     }
-}
\ No newline at end of file
+}
@@ -1,48 +1,864 @@
-use std::ffi::OsString; 
-use std::os::unix::prelude::OsStringExt;
 use nom::{
-    bytes::complete::{tag, take_until, is_a},
-    branch::alt
+    bytes::complete::{tag, take, take_till, take_till1},
+    branch::alt,
+    combinator::{map, verify, fail},
+    multi::many0,
+    sequence::preceded
 };
 
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::ffi::OsStringExt;
+use nix::unistd::{pipe, dup2, close, User};
+
 use super::error::*;
 use super::ast_nodes::ExecEnv;
+use super::subshell::{fork_subshell, subshell_status, wait_for};
+use super::run_script;
+use super::parser::{self, backquoted_len, dollar_len, is_name};
+use super::arithmetic;
+use super::glob::{expand_pathname, Pattern};
 
+/// A piece of a word as written in the source. Quoting is preserved so that
+/// later expansion stages know which bytes must be treated literally.
+#[derive(Debug, PartialEq)]
 pub enum Expandable {
     Text(Vec<u8>),
-    VariableSub(Vec<u8>),
-    CommandSub(Vec<Expandable>),
+    Tilde(Vec<u8>),
+    QuotedText(Vec<u8>),
+    DoubleQuoted(Vec<Expandable>),
+    VariableSub(ParamExp),
+    BadSubstitution(Vec<u8>),
+    CommandSub(Vec<u8>),
     Arithmetic(Vec<Expandable>)
 }
 
-impl Expandable {
-    fn expand(self, ev: ExecEnv) -> Option<Vec<u8>> {
-        match self {
-            Self::Text(s) => Some(s),
-            Self::VariableSub(s) => {
-                let k = OsString::from_vec(s);
-                Some(ev.env.get(&k)?.value.clone())
+#[derive(Debug, PartialEq)]
+pub struct ParamExp {
+    pub name: Vec<u8>,
+    pub op: ParamOp
+}
+
+/// The operators of parameter expansion. The boolean records whether the
+/// colon form was used, which also treats a null value as unset.
+#[derive(Debug, PartialEq)]
+pub enum ParamOp {
+    Plain,                              /* ${name} */
+    Length,                             /* ${#name} */
+    UseDefault(bool, Vec<u8>),          /* ${name:-word} */
+    AssignDefault(bool, Vec<u8>),       /* ${name:=word} */
+    ErrorIfUnset(bool, Vec<u8>),        /* ${name:?word} */
+    UseAlternative(bool, Vec<u8>),      /* ${name:+word} */
+    RemoveSmallestSuffix(Vec<u8>),      /* ${name%word} */
+    RemoveLargestSuffix(Vec<u8>),       /* ${name%%word} */
+    RemoveSmallestPrefix(Vec<u8>),      /* ${name#word} */
+    RemoveLargestPrefix(Vec<u8>)        /* ${name##word} */
+}
+
+/// The result of expanding a word, tracking which bytes were quoted
+#[derive(Debug, Default)]
+struct Expanded {
+    text: Vec<u8>,
+    quoted: Vec<bool>,
+    /// Whether each byte is the result of an unquoted expansion, and so
+    /// subject to field splitting
+    split: Vec<bool>,
+    /// Positions of the separators which must end a field, as between the
+    /// fields of "$@"
+    breaks: Vec<usize>,
+    /// Positions of quotes which keep an otherwise empty field
+    empty_quotes: Vec<usize>
+}
+
+impl Expanded {
+    fn push(&mut self, s: &[u8], quoted: bool) {
+        self.text.extend_from_slice(s);
+        self.quoted.extend(s.iter().map(|_| quoted));
+        self.split.extend(s.iter().map(|_| false));
+    }
+
+    /// Add the result of an expansion, which is split into fields unless
+    /// it was quoted
+    fn push_expansion(&mut self, s: &[u8], quoted: bool) {
+        self.text.extend_from_slice(s);
+        self.quoted.extend(s.iter().map(|_| quoted));
+        self.split.extend(s.iter().map(|_| !quoted));
+    }
+
+    fn push_empty_quotes(&mut self) {
+        self.empty_quotes.push(self.text.len());
+    }
+
+    /// Split the text into fields at the characters of `ifs` resulting
+    /// from unquoted expansions, returning the text of each field with
+    /// the quoting of its bytes
+    fn split_fields(&self, ifs: &[u8]) -> Vec<(Vec<u8>, Vec<bool>)> {
+        let is_ifs_white = |c: u8| ifs.contains(&c) && b" \t\n".contains(&c);
+        let mut fields = vec![];
+        let mut field: (Vec<u8>, Vec<bool>) = (vec![], vec![]);
+        // Whether the current field has been started by some text or quotes
+        let mut started = false;
+        // Whether the last field was ended by white space, which a
+        // following delimiter belongs to
+        let mut white_delimited = false;
+
+        for i in 0..=self.text.len() {
+            if self.empty_quotes.contains(&i) {
+                started = true;
+            }
+            if i == self.text.len() {
+                break;
+            }
+
+            let c = self.text[i];
+            if self.breaks.contains(&i) {
+                if started {
+                    fields.push(std::mem::take(&mut field));
+                }
+                started = false;
+                white_delimited = false;
+            } else if !self.split[i] || !ifs.contains(&c) {
+                field.0.push(c);
+                field.1.push(self.quoted[i]);
+                started = true;
+            } else if is_ifs_white(c) {
+                if started {
+                    fields.push(std::mem::take(&mut field));
+                    started = false;
+                    white_delimited = true;
+                }
+            } else {
+                if started || !white_delimited {
+                    fields.push(std::mem::take(&mut field));
+                }
+                started = false;
+                white_delimited = false;
+            }
+        }
+
+        if started {
+            fields.push(field);
+        }
+        fields
+    }
+}
+
+/// Split the text of a word token into its parts
+pub fn word_parts(input: &[u8]) -> Vec<Expandable> {
+    match many0(unquoted_part)(input) {
+        Ok((_, parts)) => parts.into_iter().flatten().collect(),
+        Err(_) => vec![]
+    }
+}
+
+/// Split the text of a word into its parts, finding a tilde prefix at its
+/// start. In the value of an assignment a tilde prefix may also follow any
+/// unquoted `:`.
+fn tilde_word_parts(input: &[u8], assignment: bool) -> Vec<Expandable> {
+    let parts = word_parts(input);
+    let count = parts.len();
+    let is_end = |c: &u8| *c == b'/' || (assignment && *c == b':');
+    let mut out = vec![];
+    let mut at_start = true;
+    for (i, part) in parts.into_iter().enumerate() {
+        let text = match part {
+            Expandable::Text(text) => text,
+            part => {
+                out.push(part);
+                at_start = false;
+                continue
+            }
+        };
+
+        let mut rest = &text[..];
+        while !rest.is_empty() {
+            if at_start && rest[0] == b'~' {
+                // The prefix can't run on into the next part, which is quoted
+                // or an expansion
+                let end = match rest.iter().position(is_end) {
+                    Some(end) => Some(end),
+                    None if i + 1 == count => Some(rest.len()),
+                    None => None
+                };
+                if let Some(end) = end {
+                    out.push(Expandable::Tilde(rest[1..end].to_vec()));
+                    rest = &rest[end..];
+                    at_start = false;
+                    continue
+                }
+            }
+            let len = match rest.iter().position(|&c| assignment && c == b':') {
+                Some(colon) => colon + 1,
+                None => rest.len()
+            };
+            out.push(Expandable::Text(rest[..len].to_vec()));
+            at_start = rest[len - 1] == b':' && assignment;
+            rest = &rest[len..];
+        }
+    }
+    out
+}
+
+/// Perform tilde expansion, parameter expansion and quote removal on the
+/// text of a word
+pub fn expand_word(input: &[u8], ev: &mut ExecEnv) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut out = Expanded::default();
+    expand_parts(&tilde_word_parts(input, false), false, ev, &mut out)?;
+    Ok(out.text)
+}
+
+/// Expand the value of an assignment, which is like a word except that a
+/// tilde prefix may also follow a `:`
+pub fn expand_assignment(input: &[u8], ev: &mut ExecEnv) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut out = Expanded::default();
+    expand_parts(&tilde_word_parts(input, true), false, ev, &mut out)?;
+    Ok(out.text)
+}
+
+/// Expand a prompt string, which unlike a word has no tilde expansion
+pub fn expand_prompt_string(input: &[u8], ev: &mut ExecEnv) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut out = Expanded::default();
+    expand_parts(&word_parts(input), false, ev, &mut out)?;
+    Ok(out.text)
+}
+
+/// Expand a word into fields: perform tilde expansion, parameter
+/// expansion, command substitution, arithmetic expansion, field splitting,
+/// pathname expansion and quote removal
+pub fn expand_fields(input: &[u8], ev: &mut ExecEnv) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+    let mut out = Expanded::default();
+    expand_parts(&tilde_word_parts(input, false), false, ev, &mut out)?;
+
+    let ifs = ev.get_var(b"IFS").unwrap_or(b" \t\n").to_vec();
+    let mut fields = vec![];
+    for (text, quoted) in out.split_fields(&ifs) {
+        match expand_pathname(&text, &quoted) {
+            Some(paths) if !ev.options.noglob => fields.extend(paths),
+            _ => fields.push(text)
+        }
+    }
+    Ok(fields)
+}
+
+/// Perform parameter expansion, command substitution and arithmetic
+/// expansion on the body of a here-document
+pub fn expand_here_doc(input: &[u8], ev: &mut ExecEnv) -> Result<Vec<u8>, Box<dyn Error>> {
+    let parts = match many0(here_doc_part)(input) {
+        Ok((_, parts)) => parts.into_iter().flatten().collect(),
+        Err(_) => vec![]
+    };
+    let mut out = Expanded::default();
+    expand_parts(&parts, true, ev, &mut out)?;
+    Ok(out.text)
+}
+
+fn expand_parts(parts: &[Expandable], quoted: bool, ev: &mut ExecEnv, out: &mut Expanded) -> Result<(), Box<dyn Error>> {
+    for part in parts {
+        match part {
+            Expandable::Text(s) => out.push(s, quoted),
+            Expandable::Tilde(login) => match home_directory(login, ev) {
+                // The directory is neither split into fields nor a pattern
+                Some(dir) => {
+                    if dir.is_empty() {
+                        out.push_empty_quotes();
+                    }
+                    out.push(&dir, true)
+                },
+                None => {
+                    out.push(b"~", quoted);
+                    out.push(login, quoted)
+                }
+            },
+            Expandable::QuotedText(s) => {
+                if s.is_empty() {
+                    out.push_empty_quotes();
+                }
+                out.push(s, true)
+            },
+            Expandable::DoubleQuoted(inner) => {
+                // "$@" with no positional parameters gives no field at all
+                if !inner.iter().any(is_all_args) {
+                    out.push_empty_quotes();
+                }
+                expand_parts(inner, true, ev, out)?
+            },
+            Expandable::VariableSub(param) if is_all_args(part) || (param.name == b"*" && param.op == ParamOp::Plain && !quoted) => {
+                // Each positional parameter is a separate field
+                for (i, arg) in ev.args.clone().iter().enumerate() {
+                    if i > 0 {
+                        out.breaks.push(out.text.len());
+                        out.push(b" ", quoted);
+                    }
+                    if quoted {
+                        out.push_empty_quotes();
+                    }
+                    out.push_expansion(arg, quoted);
+                }
+            },
+            Expandable::VariableSub(param) => {
+                let value = expand_parameter(param, quoted, ev)?;
+                out.push_expansion(&value, quoted);
             },
-            _ => unimplemented!()
+            Expandable::BadSubstitution(s) => {
+                let msg = format!("{}: bad substitution", String::from_utf8_lossy(s));
+                return Err(Box::new(ExecError::new(msg)))
+            },
+            Expandable::CommandSub(source) => {
+                let output = command_substitution(source, ev)?;
+                out.push_expansion(&output, quoted);
+            },
+            Expandable::Arithmetic(inner) => {
+                // The expression is expanded as if it were double quoted
+                let mut expr = Expanded::default();
+                expand_parts(inner, true, ev, &mut expr)?;
+                let value = arithmetic::evaluate(&expr.text, ev)?;
+                out.push_expansion(value.to_string().as_bytes(), quoted);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The directory a tilde prefix stands for: $HOME for a bare `~`, or the
+/// home directory of the user named
+fn home_directory(login: &[u8], ev: &ExecEnv) -> Option<Vec<u8>> {
+    if login.is_empty() {
+        return ev.get_var(b"HOME").map(|home| home.to_vec())
+    }
+    let login = std::str::from_utf8(login).ok()?;
+    match User::from_name(login) {
+        Ok(Some(user)) => Some(user.dir.into_os_string().into_vec()),
+        _ => None
+    }
+}
+
+/// Whether a part is a plain expansion of $@
+fn is_all_args(part: &Expandable) -> bool {
+    match part {
+        Expandable::VariableSub(param) => param.name == b"@" && param.op == ParamOp::Plain,
+        _ => false
+    }
+}
+
+/// Run a command in a subshell, returning its output with trailing newlines
+/// removed
+fn command_substitution(source: &[u8], ev: &mut ExecEnv) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (read_fd, write_fd) = pipe()?;
+
+    let pid = fork_subshell(ev, |ev| {
+        let _ = close(read_fd);
+        if dup2(write_fd, 1).is_err() {
+            return 1
         }
+        let _ = close(write_fd);
+
+        subshell_status(run_script(source, ev))
+    })?;
+    close(write_fd)?;
+
+    let mut output = vec![];
+    let mut reader = unsafe { File::from_raw_fd(read_fd) };
+    let read_result = reader.read_to_end(&mut output);
+    ev.last_status = wait_for(pid)?;
+    read_result?;
+
+    while output.last() == Some(&b'\n') {
+        output.pop();
+    }
+    Ok(output)
+}
+
+/// Look up the value of a parameter, returning None if it is unset
+fn lookup_parameter(name: &[u8], ev: &ExecEnv) -> Option<Vec<u8>> {
+    match name {
+        b"@" => Some(ev.args.join(&b' ')),
+        b"*" => {
+            let sep = match ev.get_var(b"IFS") {
+                Some(ifs) => ifs.first().copied(),
+                None => Some(b' ')
+            };
+            match sep {
+                Some(sep) => Some(ev.args.join(&sep)),
+                None => Some(ev.args.concat())
+            }
+        },
+        b"#" => Some(ev.args.len().to_string().into_bytes()),
+        b"?" => Some(ev.last_status.to_string().into_bytes()),
+        b"-" => Some(ev.option_flags()),
+        b"$" => Some(ev.shell_pid.to_string().into_bytes()),
+        b"!" => ev.last_bg_pid.map(|pid| pid.to_string().into_bytes()),
+        b"0" => Some(ev.arg0.clone()),
+        _ if name[0].is_ascii_digit() => {
+            let n: usize = std::str::from_utf8(name).ok()?.parse().ok()?;
+            ev.args.get(n - 1).cloned()
+        },
+        _ => ev.get_var(name).map(|v| v.to_vec())
     }
 }
 
-fn expandable<'a>(input: &'a [u8]) -> RbResult<&'a [u8], Expandable> {
+fn expand_parameter(param: &ParamExp, quoted: bool, ev: &mut ExecEnv) -> Result<Vec<u8>, Box<dyn Error>> {
+    let value = lookup_parameter(&param.name, ev);
+    let is_unset = |colon: bool| match &value {
+        None => true,
+        Some(v) => colon && v.is_empty()
+    };
+
+    // With nounset only the operators that test for an unset parameter
+    // can be used on one
+    let tests_unset = matches!(param.op, ParamOp::UseDefault(..) | ParamOp::AssignDefault(..)
+                               | ParamOp::ErrorIfUnset(..) | ParamOp::UseAlternative(..));
+    if value.is_none() && ev.options.nounset && !tests_unset {
+        let msg = format!("{}: parameter not set", String::from_utf8_lossy(&param.name));
+        return Err(Box::new(ExecError::new(msg)))
+    }
+
+    let result = match &param.op {
+        ParamOp::Plain => value.unwrap_or_default(),
+        ParamOp::Length => {
+            let value = value.unwrap_or_default();
+            String::from_utf8_lossy(&value).chars().count().to_string().into_bytes()
+        },
+        ParamOp::UseDefault(colon, word) => {
+            if is_unset(*colon) {
+                expand_sub_word(word, quoted, ev)?
+            } else {
+                value.unwrap_or_default()
+            }
+        },
+        ParamOp::AssignDefault(colon, word) => {
+            if is_unset(*colon) {
+                if !is_name(&param.name) {
+                    let msg = format!("{}: cannot assign in this way", String::from_utf8_lossy(&param.name));
+                    return Err(Box::new(ExecError::new(msg)))
+                }
+                let new_value = expand_sub_word(word, quoted, ev)?;
+                ev.set_var(&param.name, new_value.clone())?;
+                new_value
+            } else {
+                value.unwrap_or_default()
+            }
+        },
+        ParamOp::ErrorIfUnset(colon, word) => {
+            if is_unset(*colon) {
+                let mut msg = expand_sub_word(word, quoted, ev)?;
+                if msg.is_empty() {
+                    msg = if *colon {
+                        b"parameter null or not set".to_vec()
+                    } else {
+                        b"parameter not set".to_vec()
+                    };
+                }
+                let msg = format!("{}: {}", String::from_utf8_lossy(&param.name), String::from_utf8_lossy(&msg));
+                return Err(Box::new(ExecError::new(msg)))
+            }
+            value.unwrap_or_default()
+        },
+        ParamOp::UseAlternative(colon, word) => {
+            if is_unset(*colon) {
+                vec![]
+            } else {
+                expand_sub_word(word, quoted, ev)?
+            }
+        },
+        ParamOp::RemoveSmallestSuffix(word) => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(word, ev)?;
+            let i = (0..=value.len()).rev().find(|i| pattern.matches(&value[*i..]));
+            value[..i.unwrap_or(value.len())].to_vec()
+        },
+        ParamOp::RemoveLargestSuffix(word) => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(word, ev)?;
+            let i = (0..=value.len()).find(|i| pattern.matches(&value[*i..]));
+            value[..i.unwrap_or(value.len())].to_vec()
+        },
+        ParamOp::RemoveSmallestPrefix(word) => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(word, ev)?;
+            let i = (0..=value.len()).find(|i| pattern.matches(&value[..*i]));
+            value[i.unwrap_or(0)..].to_vec()
+        },
+        ParamOp::RemoveLargestPrefix(word) => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(word, ev)?;
+            let i = (0..=value.len()).rev().find(|i| pattern.matches(&value[..*i]));
+            value[i.unwrap_or(0)..].to_vec()
+        }
+    };
+
+    Ok(result)
+}
+
+/// Expand the word of a ${name:-word} style expansion
+fn expand_sub_word(word: &[u8], quoted: bool, ev: &mut ExecEnv) -> Result<Vec<u8>, Box<dyn Error>> {
+    // A tilde prefix is only recognised outside double quotes
+    let parts = if quoted { word_parts(word) } else { tilde_word_parts(word, false) };
+    let mut out = Expanded::default();
+    expand_parts(&parts, quoted, ev, &mut out)?;
+    Ok(out.text)
+}
+
+/// Expand the word of a ${name%word} style expansion into a pattern
+pub fn expand_pattern(word: &[u8], ev: &mut ExecEnv) -> Result<Pattern, Box<dyn Error>> {
+    let mut out = Expanded::default();
+    expand_parts(&tilde_word_parts(word, false), false, ev, &mut out)?;
+    Ok(Pattern::new(&out.text, &out.quoted))
+}
+
+fn unquoted_part(input: &[u8]) -> RbResult<&[u8], Option<Expandable>> {
     alt((
-        text,
-        variable
+        line_continuation,
+        map(escaped_char, Some),
+        map(single_quoted, Some),
+        map(double_quoted, Some),
+        map(dollar, Some),
+        map(backquoted(false), Some),
+        map(unquoted_text, Some),
+        map(take(1usize), |c: &[u8]| Some(Expandable::Text(c.to_vec())))
     ))(input)
 }
 
-fn text<'a>(input: &'a [u8]) -> RbResult<&'a [u8], Expandable> {
-    let (input, t) = take_until(b"$".as_ref())(input)?;
+fn line_continuation(input: &[u8]) -> RbResult<&[u8], Option<Expandable>> {
+    let (input, _) = tag(b"\\\n".as_ref())(input)?;
+    Ok((input, None))
+}
+
+fn escaped_char(input: &[u8]) -> RbResult<&[u8], Expandable> {
+    let (input, c) = preceded(tag(b"\\".as_ref()), take(1usize))(input)?;
+    Ok((input, Expandable::QuotedText(c.to_vec())))
+}
+
+fn single_quoted(input: &[u8]) -> RbResult<&[u8], Expandable> {
+    let (input, _) = tag(b"'".as_ref())(input)?;
+    let (input, t) = take_till(|c| c == b'\'')(input)?;
+    let (input, _) = tag(b"'".as_ref())(input)?;
+    Ok((input, Expandable::QuotedText(t.to_vec())))
+}
+
+fn double_quoted(input: &[u8]) -> RbResult<&[u8], Expandable> {
+    let (input, _) = tag(b"\"".as_ref())(input)?;
+    let (input, parts) = many0(double_quoted_part)(input)?;
+    let (input, _) = tag(b"\"".as_ref())(input)?;
+    Ok((input, Expandable::DoubleQuoted(parts.into_iter().flatten().collect())))
+}
+
+fn double_quoted_part(input: &[u8]) -> RbResult<&[u8], Option<Expandable>> {
+    alt((
+        line_continuation,
+        map(double_quoted_escape, Some),
+        map(dollar, Some),
+        map(backquoted(true), Some),
+        map(double_quoted_text, Some)
+    ))(input)
+}
+
+fn double_quoted_escape(input: &[u8]) -> RbResult<&[u8], Expandable> {
+    // Inside double quotes a backslash only escapes $ ` " and \
+    let escapable = |c: &[u8]| b"$`\"\\".contains(&c[0]);
+    let (input, c) = preceded(tag(b"\\".as_ref()), verify(take(1usize), escapable))(input)?;
+    Ok((input, Expandable::QuotedText(c.to_vec())))
+}
+
+fn here_doc_part(input: &[u8]) -> RbResult<&[u8], Option<Expandable>> {
+    alt((
+        line_continuation,
+        map(here_doc_escape, Some),
+        map(dollar, Some),
+        map(backquoted(false), Some),
+        map(here_doc_text, Some)
+    ))(input)
+}
+
+fn here_doc_escape(input: &[u8]) -> RbResult<&[u8], Expandable> {
+    // Double quotes are not special in a here-document
+    let escapable = |c: &[u8]| b"$`\\".contains(&c[0]);
+    let (input, c) = preceded(tag(b"\\".as_ref()), verify(take(1usize), escapable))(input)?;
+    Ok((input, Expandable::QuotedText(c.to_vec())))
+}
+
+fn here_doc_text(input: &[u8]) -> RbResult<&[u8], Expandable> {
+    let (input, t) = alt((
+        take_till1(|c| b"\\$`".contains(&c)),
+        take(1usize)
+    ))(input)?;
+    Ok((input, Expandable::QuotedText(t.to_vec())))
+}
+
+fn double_quoted_text(input: &[u8]) -> RbResult<&[u8], Expandable> {
+    let (input, t) = alt((
+        take_till1(|c| b"\\\"$`".contains(&c)),
+        tag(b"\\".as_ref())
+    ))(input)?;
+    Ok((input, Expandable::QuotedText(t.to_vec())))
+}
+
+fn unquoted_text(input: &[u8]) -> RbResult<&[u8], Expandable> {
+    let (input, t) = take_till1(|c| b"\\'\"$`".contains(&c))(input)?;
     Ok((input, Expandable::Text(t.to_vec())))
 }
 
-fn variable<'a>(input: &'a [u8]) -> RbResult<&'a [u8], Expandable> {
-    let name_char_set = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ_";
-    let (input, _) = tag(b"$".as_ref())(input)?;
-    let (input, name) = is_a(name_char_set.as_ref())(input)?;
-    Ok((input, Expandable::Text(name.to_vec())))
+fn dollar(input: &[u8]) -> RbResult<&[u8], Expandable> {
+    alt((
+        parameter,
+        arithmetic,
+        command_sub
+    ))(input)
+}
+
+fn arithmetic(input: &[u8]) -> RbResult<&[u8], Expandable> {
+    if !input.starts_with(b"$((") {
+        return fail(input);
+    }
+    let len = match dollar_len(input) {
+        Some(len) => len,
+        None => return fail(input)
+    };
+    // $((cmd) (cmd)) is a command substitution of two subshells
+    match parser::bracketed_len(&input[2..], b'(', b')') {
+        Some(inner_len) if inner_len + 3 == len => (),
+        _ => return fail(input)
+    }
+    let inner = &input[3..(len - 2)];
+    Ok((&input[len..], Expandable::Arithmetic(word_parts(inner))))
+}
+
+fn command_sub(input: &[u8]) -> RbResult<&[u8], Expandable> {
+    if !input.starts_with(b"$(") || input.starts_with(b"$((") {
+        return fail(input);
+    }
+    match dollar_len(input) {
+        Some(len) => Ok((&input[len..], Expandable::CommandSub(input[2..(len - 1)].to_vec()))),
+        None => fail(input)
+    }
+}
+
+/// Parse a backquoted command substitution. Within backquotes a backslash
+/// only escapes $ ` and \ (and " when inside double quotes).
+fn backquoted(in_double_quotes: bool) -> impl Fn(&[u8]) -> RbResult<&[u8], Expandable> {
+    move |input: &[u8]| {
+        let len = match input.first() {
+            Some(b'`') => backquoted_len(input),
+            _ => None
+        };
+        let len = match len {
+            Some(len) => len,
+            None => return fail(input)
+        };
+
+        let inner = &input[1..(len - 1)];
+        let mut source = vec![];
+        let mut i = 0;
+        while i < inner.len() {
+            let escapable = |c: u8| b"$`\\".contains(&c) || (in_double_quotes && c == b'"');
+            if inner[i] == b'\\' && i + 1 < inner.len() && escapable(inner[i + 1]) {
+                i += 1;
+            }
+            source.push(inner[i]);
+            i += 1;
+        }
+        Ok((&input[len..], Expandable::CommandSub(source)))
+    }
+}
+
+/// Split the name of a parameter from the start of s
+fn parameter_name(s: &[u8], braced: bool) -> Option<(&[u8], &[u8])> {
+    let c = *s.first()?;
+    let len = if c.is_ascii_digit() {
+        if braced {
+            s.iter().take_while(|c| c.is_ascii_digit()).count()
+        } else {
+            1
+        }
+    } else if b"@*#?-$!".contains(&c) {
+        1
+    } else if c.is_ascii_alphabetic() || c == b'_' {
+        s.iter().take_while(|c| c.is_ascii_alphanumeric() || **c == b'_').count()
+    } else {
+        return None
+    };
+    Some((&s[len..], &s[..len]))
+}
+
+fn parameter(input: &[u8]) -> RbResult<&[u8], Expandable> {
+    let (rest, _) = tag(b"$".as_ref())(input)?;
+
+    if rest.first() == Some(&b'{') {
+        let len = match dollar_len(input) {
+            Some(len) => len,
+            None => return fail(input)
+        };
+        let inner = &input[2..(len - 1)];
+        return Ok((&input[len..], braced_parameter(inner)))
+    }
+
+    match parameter_name(rest, false) {
+        Some((rest, name)) => Ok((rest, Expandable::VariableSub(ParamExp {
+            name: name.to_vec(),
+            op: ParamOp::Plain
+        }))),
+        None => fail(input)
+    }
+}
+
+fn braced_parameter(inner: &[u8]) -> Expandable {
+    // ${#name} but not ${#} or ${#-word}
+    if inner.len() > 1 && inner[0] == b'#' {
+        if let Some((b"", name)) = parameter_name(&inner[1..], true) {
+            return Expandable::VariableSub(ParamExp {
+                name: name.to_vec(),
+                op: ParamOp::Length
+            })
+        }
+    }
+
+    let (rest, name) = match parameter_name(inner, true) {
+        Some(x) => x,
+        None => return Expandable::BadSubstitution(inner.to_vec())
+    };
+
+    let (colon, rest) = match rest.strip_prefix(b":") {
+        Some(rest) => (true, rest),
+        None => (false, rest)
+    };
+
+    let op = match rest {
+        [] if !colon => ParamOp::Plain,
+        [b'-', word @ ..] => ParamOp::UseDefault(colon, word.to_vec()),
+        [b'=', word @ ..] => ParamOp::AssignDefault(colon, word.to_vec()),
+        [b'?', word @ ..] => ParamOp::ErrorIfUnset(colon, word.to_vec()),
+        [b'+', word @ ..] => ParamOp::UseAlternative(colon, word.to_vec()),
+        [b'%', b'%', word @ ..] if !colon => ParamOp::RemoveLargestSuffix(word.to_vec()),
+        [b'%', word @ ..] if !colon => ParamOp::RemoveSmallestSuffix(word.to_vec()),
+        [b'#', b'#', word @ ..] if !colon => ParamOp::RemoveLargestPrefix(word.to_vec()),
+        [b'#', word @ ..] if !colon => ParamOp::RemoveSmallestPrefix(word.to_vec()),
+        _ => return Expandable::BadSubstitution(inner.to_vec())
+    };
+
+    Expandable::VariableSub(ParamExp {
+        name: name.to_vec(),
+        op
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expand(input: &str, ev: &mut ExecEnv) -> String {
+        String::from_utf8(expand_word(input.as_bytes(), ev).unwrap()).unwrap()
+    }
+
+    macro_rules! test_expand {
+        ( $test_name:ident, $input:expr, $expected:expr ) => {
+            #[test]
+            fn $test_name() {
+                let mut ev = ExecEnv::new();
+                ev.set_var(b"foo", b"hello.tar.gz".to_vec()).unwrap();
+                ev.set_var(b"null", vec![]).unwrap();
+                ev.args = vec![b"a".to_vec(), b"b".to_vec()];
+                assert_eq!(expand($input, &mut ev), $expected);
+            }
+        }
+    }
+
+    test_expand!(test_single_quotes, "'a  b'c", "a  bc");
+    test_expand!(test_double_quotes, "\"a\\\"b\\$c\\d\"", "a\"b$c\\d");
+    test_expand!(test_mixed_quotes, "a'b'\"c\"d", "abcd");
+    test_expand!(test_backslash, "a\\ b\\;", "a b;");
+    test_expand!(test_line_continuation, "foo\\\nbar", "foobar");
+    test_expand!(test_plain_var, "$foo", "hello.tar.gz");
+    test_expand!(test_braced_var, "${foo}bar", "hello.tar.gzbar");
+    test_expand!(test_quoted_var, "\"$foo\"'$foo'", "hello.tar.gz$foo");
+    test_expand!(test_positional, "$1$2$3", "ab");
+    test_expand!(test_special_params, "$#,$?,$@", "2,0,a b");
+    test_expand!(test_length, "${#foo}", "12");
+    test_expand!(test_use_default, "${unset:-x}${null:-y}${null-z}", "xy");
+    test_expand!(test_use_alternative, "${foo:+x}${null:+y}${null+z}", "xz");
+    test_expand!(test_default_expands, "${unset:-$1}", "a");
+    test_expand!(test_remove_suffix, "${foo%.*} ${foo%%.*}", "hello.tar hello");
+    test_expand!(test_remove_prefix, "${foo#*.} ${foo##*.}", "tar.gz gz");
+    test_expand!(test_quoted_pattern, "${foo#\"*\"}", "hello.tar.gz");
+    test_expand!(test_lone_dollar, "a$ $/", "a$ $/");
+
+    #[test]
+    fn test_assign_default() {
+        let mut ev = ExecEnv::new();
+        assert_eq!(expand("${x:=set}", &mut ev), "set");
+        assert_eq!(ev.get_var(b"x"), Some(b"set".as_ref()));
+    }
+
+    #[test]
+    fn test_error_if_unset() {
+        let mut ev = ExecEnv::new();
+        let err = expand_word(b"${x:?oops}", &mut ev).unwrap_err();
+        assert_eq!(err.to_string(), "x: oops");
+        let err = expand_word(b"${x?}", &mut ev).unwrap_err();
+        assert_eq!(err.to_string(), "x: parameter not set");
+    }
+
+    fn fields(input: &str, ev: &mut ExecEnv) -> Vec<String> {
+        expand_fields(input.as_bytes(), ev).unwrap().into_iter()
+            .map(|f| String::from_utf8(f).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_field_splitting() {
+        let mut ev = ExecEnv::new();
+        ev.set_var(b"x", b"  a b\t\nc  ".to_vec()).unwrap();
+        assert_eq!(fields("$x", &mut ev), ["a", "b", "c"]);
+        assert_eq!(fields("\"$x\"", &mut ev), ["  a b\t\nc  "]);
+        assert_eq!(fields("1${x}2", &mut ev), ["1", "a", "b", "c", "2"]);
+        assert_eq!(fields("''$null\"\"", &mut ev), [""]);
+        assert!(fields("$null", &mut ev).is_empty());
+
+        ev.set_var(b"IFS", b" :".to_vec()).unwrap();
+        ev.set_var(b"x", b":a : b::c:".to_vec()).unwrap();
+        assert_eq!(fields("$x", &mut ev), ["", "a", "b", "", "c"]);
+
+        ev.set_var(b"IFS", vec![]).unwrap();
+        assert_eq!(fields("$x", &mut ev), [":a : b::c:"]);
+    }
+
+    #[test]
+    fn test_positional_fields() {
+        let mut ev = ExecEnv::new();
+        ev.args = vec![b"a b".to_vec(), b"".to_vec(), b"c".to_vec()];
+        assert_eq!(fields("\"$@\"", &mut ev), ["a b", "", "c"]);
+        assert_eq!(fields("x\"$@\"y", &mut ev), ["xa b", "", "cy"]);
+        assert_eq!(fields("$@", &mut ev), ["a", "b", "c"]);
+        assert_eq!(fields("\"$*\"", &mut ev), ["a b  c"]);
+
+        ev.args = vec![];
+        assert!(fields("\"$@\"", &mut ev).is_empty());
+    }
+
+    #[test]
+    fn test_tilde() {
+        let mut ev = ExecEnv::new();
+        ev.set_var(b"HOME", b"/home/me".to_vec()).unwrap();
+        ev.set_var(b"x", b"~".to_vec()).unwrap();
+        assert_eq!(expand("~", &mut ev), "/home/me");
+        assert_eq!(expand("~/a:~/b", &mut ev), "/home/me/a:~/b");
+        assert_eq!(expand("${unset:-~/a}", &mut ev), "/home/me/a");
+        assert_eq!(expand("'~'/a \\~ \"~\" a~ $x ~\"\" ~$x", &mut ev), "~/a ~ ~ a~ ~ ~ ~~");
+        assert_eq!(expand("\"${unset:-~}\"", &mut ev), "~");
+        assert_eq!(expand("~root/a ~nosuchuser/a", &mut ev), "/root/a ~nosuchuser/a");
+
+        let value = expand_assignment(b"~:a:~/b:~\"\":\"\"~", &mut ev).unwrap();
+        assert_eq!(value, b"/home/me:a:/home/me/b:~:~");
+
+        ev.set_var(b"HOME", b"/a b/*".to_vec()).unwrap();
+        assert_eq!(fields("~/", &mut ev), ["/a b/*/"]);
+        assert_eq!(expand_prompt_string(b"~ ", &mut ev).unwrap(), b"~ ");
+    }
+
+    #[test]
+    fn test_bad_substitution() {
+        let mut ev = ExecEnv::new();
+        assert!(expand_word(b"${x!y}", &mut ev).is_err());
+    }
 }
@@ -0,0 +1,323 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::prelude::OsStrExt;
+use std::path::Path;
+
+#[derive(Debug)]
+enum PatternItem {
+    Literal(u8),
+    AnyChar,
+    AnyString,
+    Bracket(bool, Vec<BracketItem>)
+}
+
+#[derive(Debug)]
+enum BracketItem {
+    Range(u8, u8),
+    Class(fn(&u8) -> bool)
+}
+
+/// A shell pattern as used by case statements, parameter expansion and
+/// pathname expansion
+#[derive(Debug)]
+pub struct Pattern {
+    items: Vec<PatternItem>
+}
+
+fn char_class(name: &[u8]) -> Option<fn(&u8) -> bool> {
+    let f: fn(&u8) -> bool = match name {
+        b"alnum" => u8::is_ascii_alphanumeric,
+        b"alpha" => u8::is_ascii_alphabetic,
+        b"blank" => |c| *c == b' ' || *c == b'\t',
+        b"cntrl" => u8::is_ascii_control,
+        b"digit" => u8::is_ascii_digit,
+        b"graph" => u8::is_ascii_graphic,
+        b"lower" => u8::is_ascii_lowercase,
+        b"print" => |c| c.is_ascii_graphic() || *c == b' ',
+        b"punct" => u8::is_ascii_punctuation,
+        b"space" => |c| c.is_ascii_whitespace() || *c == 0x0b,
+        b"upper" => u8::is_ascii_uppercase,
+        b"xdigit" => u8::is_ascii_hexdigit,
+        _ => return None
+    };
+    Some(f)
+}
+
+/// Length of the character starting at s[0], treating invalid UTF-8 as bytes
+fn char_len(s: &[u8]) -> usize {
+    let n = match s[0] {
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1
+    };
+    if n <= s.len() && std::str::from_utf8(&s[..n]).is_ok() {
+        n
+    } else {
+        1
+    }
+}
+
+impl Pattern {
+    /// Build a pattern from expanded text. Quoted bytes only match themselves.
+    pub fn new(text: &[u8], quoted: &[bool]) -> Pattern {
+        let mut items = vec![];
+        let mut i = 0;
+        while i < text.len() {
+            let c = text[i];
+            if quoted[i] {
+                items.push(PatternItem::Literal(c));
+                i += 1;
+                continue;
+            }
+            match c {
+                b'*' => items.push(PatternItem::AnyString),
+                b'?' => items.push(PatternItem::AnyChar),
+                b'\\' if i + 1 < text.len() => {
+                    items.push(PatternItem::Literal(text[i + 1]));
+                    i += 1;
+                },
+                b'[' => {
+                    if let Some((item, len)) = Self::bracket(&text[i..]) {
+                        items.push(item);
+                        i += len;
+                        continue;
+                    }
+                    items.push(PatternItem::Literal(c));
+                },
+                _ => items.push(PatternItem::Literal(c))
+            }
+            i += 1;
+        }
+        Pattern { items }
+    }
+
+    /// Parse a bracket expression returning it and its length in bytes
+    fn bracket(s: &[u8]) -> Option<(PatternItem, usize)> {
+        let mut i = 1;
+        let negate = matches!(s.get(i), Some(b'!') | Some(b'^'));
+        if negate {
+            i += 1;
+        }
+
+        let mut items = vec![];
+        let mut first = true;
+        while i < s.len() {
+            let c = s[i];
+            if c == b']' && !first {
+                return Some((PatternItem::Bracket(negate, items), i + 1));
+            }
+            first = false;
+
+            if s[i..].starts_with(b"[:") {
+                let end = s[(i + 2)..].windows(2).position(|w| w == b":]")?;
+                let class = char_class(&s[(i + 2)..(i + 2 + end)])?;
+                items.push(BracketItem::Class(class));
+                i += end + 4;
+            } else if i + 2 < s.len() && s[i + 1] == b'-' && s[i + 2] != b']' {
+                items.push(BracketItem::Range(c, s[i + 2]));
+                i += 3;
+            } else {
+                items.push(BracketItem::Range(c, c));
+                i += 1;
+            }
+        }
+        None
+    }
+
+    /// Match a single non-star item against the start of s, returning the
+    /// number of bytes consumed
+    fn match_item(item: &PatternItem, s: &[u8]) -> Option<usize> {
+        match item {
+            PatternItem::Literal(c) if s[0] == *c => Some(1),
+            PatternItem::AnyChar => Some(char_len(s)),
+            PatternItem::Bracket(negate, items) => {
+                let c = s[0];
+                let found = items.iter().any(|item| match item {
+                    BracketItem::Range(lo, hi) => *lo <= c && c <= *hi,
+                    BracketItem::Class(f) => f(&c)
+                });
+                if found != *negate {
+                    Some(1)
+                } else {
+                    None
+                }
+            },
+            _ => None
+        }
+    }
+
+    pub fn matches(&self, s: &[u8]) -> bool {
+        let items = &self.items;
+        let mut pi = 0;
+        let mut si = 0;
+        // Position to resume from if the most recent '*' must absorb more
+        let mut backtrack: Option<(usize, usize)> = None;
+
+        while si < s.len() {
+            if pi < items.len() {
+                if matches!(items[pi], PatternItem::AnyString) {
+                    backtrack = Some((pi, si));
+                    pi += 1;
+                    continue;
+                }
+                if let Some(n) = Self::match_item(&items[pi], &s[si..]) {
+                    pi += 1;
+                    si += n;
+                    continue;
+                }
+            }
+
+            match backtrack {
+                Some((star_pi, star_si)) => {
+                    let star_si = star_si + char_len(&s[star_si..]);
+                    backtrack = Some((star_pi, star_si));
+                    pi = star_pi + 1;
+                    si = star_si;
+                },
+                None => return false
+            }
+        }
+
+        while pi < items.len() && matches!(items[pi], PatternItem::AnyString) {
+            pi += 1;
+        }
+        pi == items.len()
+    }
+
+    /// The text matched by a pattern without any special characters, or
+    /// None if it has some
+    fn literal(&self) -> Option<Vec<u8>> {
+        self.items.iter().map(|item| match item {
+            PatternItem::Literal(c) => Some(*c),
+            _ => None
+        }).collect()
+    }
+
+    /// Whether the pattern starts with a literal period, which a file name
+    /// starting with a period must be matched by
+    fn matches_dot_files(&self) -> bool {
+        matches!(self.items.first(), Some(PatternItem::Literal(b'.')))
+    }
+}
+
+/// Perform pathname expansion on a field, returning the sorted paths which
+/// match it, or None if it is not a pattern or matches nothing. Quoted
+/// bytes only match themselves.
+pub fn expand_pathname(text: &[u8], quoted: &[bool]) -> Option<Vec<Vec<u8>>> {
+    let is_special = |(c, quoted): (&u8, &bool)| !quoted && b"*?[".contains(c);
+    if !text.iter().zip(quoted).any(is_special) {
+        return None
+    }
+
+    let mut paths = vec![vec![]];
+    let mut start = 0;
+    if text.first() == Some(&b'/') {
+        paths = vec![b"/".to_vec()];
+        start = 1;
+    }
+
+    while start <= text.len() {
+        let end = text[start..].iter().position(|c| *c == b'/').map_or(text.len(), |i| start + i);
+        let pattern = Pattern::new(&text[start..end], &quoted[start..end]);
+        paths = match pattern.literal() {
+            Some(name) => paths.into_iter()
+                .map(|path| join(&path, &name))
+                .filter(|path| fs::symlink_metadata(OsStr::from_bytes(path)).is_ok())
+                .collect(),
+            None => paths.iter().flat_map(|path| matching_entries(path, &pattern)).collect()
+        };
+        if paths.is_empty() {
+            return None
+        }
+        start = end + 1;
+    }
+
+    paths.sort();
+    Some(paths)
+}
+
+/// The paths of the entries of the directory `dir` whose names match
+/// `pattern`
+fn matching_entries(dir: &[u8], pattern: &Pattern) -> Vec<Vec<u8>> {
+    let entries = match fs::read_dir(if dir.is_empty() { Path::new(".") } else { Path::new(OsStr::from_bytes(dir)) }) {
+        Ok(entries) => entries,
+        Err(_) => return vec![]
+    };
+
+    entries.filter_map(|entry| {
+        let name = entry.ok()?.file_name();
+        let name = name.as_bytes();
+        if name.starts_with(b".") && !pattern.matches_dot_files() {
+            return None
+        }
+        if !pattern.matches(name) {
+            return None
+        }
+        Some(join(dir, name))
+    }).collect()
+}
+
+fn join(dir: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut path = dir.to_vec();
+    if !path.is_empty() && !path.ends_with(b"/") {
+        path.push(b'/');
+    }
+    path.extend_from_slice(name);
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(pat: &str) -> Pattern {
+        let quoted = vec![false; pat.len()];
+        Pattern::new(pat.as_bytes(), &quoted)
+    }
+
+    #[test]
+    fn test_pattern_match() {
+        assert!(pattern("*").matches(b""));
+        assert!(pattern("a*c").matches(b"abbbc"));
+        assert!(!pattern("a*c").matches(b"abbb"));
+        assert!(pattern("a?c").matches(b"abc"));
+        assert!(pattern("[a-c]x").matches(b"bx"));
+        assert!(!pattern("[!a-c]x").matches(b"bx"));
+        assert!(pattern("[[:digit:]]*").matches(b"1abc"));
+        assert!(pattern("[]]").matches(b"]"));
+        assert!(pattern("[").matches(b"["));
+        assert!(pattern("\\*").matches(b"*"));
+        assert!(!pattern("\\*").matches(b"a"));
+    }
+
+    #[test]
+    fn test_expand_pathname() {
+        let dir = std::env::temp_dir().join(format!("rustybox-glob-{}", std::process::id()));
+        for name in ["b.txt", "a.txt", ".hidden.txt", "sub/c.txt"] {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+        let dir_text = dir.as_os_str().as_bytes().to_vec();
+        let glob = |pat: &str| {
+            let mut text = dir_text.clone();
+            text.extend_from_slice(pat.as_bytes());
+            let mut quoted = vec![true; dir_text.len()];
+            quoted.extend(pat.bytes().map(|_| false));
+            expand_pathname(&text, &quoted).map(|paths| {
+                paths.into_iter()
+                    .map(|path| String::from_utf8(path[dir_text.len()..].to_vec()).unwrap())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(glob("/*.txt"), Some(vec!["/a.txt".to_string(), "/b.txt".to_string()]));
+        assert_eq!(glob("/.*.txt"), Some(vec!["/.hidden.txt".to_string()]));
+        assert_eq!(glob("/*/c.txt"), Some(vec!["/sub/c.txt".to_string()]));
+        assert_eq!(glob("/*/d.txt"), None);
+        assert_eq!(glob("/[ab].txt"), Some(vec!["/a.txt".to_string(), "/b.txt".to_string()]));
+        assert_eq!(glob("/a.txt"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::prelude::OsStrExt;
+
+use super::ast_nodes::ExecEnv;
+
+/// The number of commands kept when HISTSIZE isn't set
+const DEFAULT_HISTSIZE: usize = 128;
+
+/// The commands entered at an interactive shell, numbered from 1
+#[derive(Debug, PartialEq)]
+pub struct History {
+    pub entries: Vec<Vec<u8>>,
+    /// The number of the oldest command kept
+    pub first: usize,
+    /// The number of commands in $HISTFILE
+    saved: usize
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            entries: vec![],
+            first: 1,
+            saved: 0
+        }
+    }
+
+    /// The number the next command entered will have
+    pub fn next_number(&self) -> usize {
+        self.first + self.entries.len()
+    }
+
+    pub fn get(&self, number: usize) -> Option<&[u8]> {
+        let index = number.checked_sub(self.first)?;
+        self.entries.get(index).map(|entry| entry.as_slice())
+    }
+
+    /// Drop the oldest commands so that no more than `size` are kept
+    fn trim(&mut self, size: usize) {
+        if self.entries.len() > size {
+            let excess = self.entries.len() - size;
+            self.entries.drain(..excess);
+            self.first += excess;
+        }
+    }
+}
+
+fn history_size(ev: &ExecEnv) -> usize {
+    ev.get_var(b"HISTSIZE")
+        .and_then(|size| std::str::from_utf8(size).ok()?.parse().ok())
+        .unwrap_or(DEFAULT_HISTSIZE)
+}
+
+/// A command as a line of $HISTFILE, with newlines written as `\n` and
+/// backslashes as `\\`
+fn encode(command: &[u8]) -> Vec<u8> {
+    let mut line = vec![];
+    for c in command {
+        match c {
+            b'\n' => line.extend_from_slice(b"\\n"),
+            b'\\' => line.extend_from_slice(b"\\\\"),
+            _ => line.push(*c)
+        }
+    }
+    line.push(b'\n');
+    line
+}
+
+fn decode(line: &[u8]) -> Vec<u8> {
+    let mut command = vec![];
+    let mut chars = line.iter();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            (b'\\', Some(b'n')) => command.push(b'\n'),
+            (b'\\', Some(b'\\')) => command.push(b'\\'),
+            _ => {
+                command.push(*c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    command
+}
+
+/// Replace the contents of $HISTFILE with the commands kept
+fn save_history(ev: &mut ExecEnv, path: &[u8]) {
+    let contents: Vec<u8> = ev.history.entries.iter().flat_map(|entry| encode(entry)).collect();
+    let _ = fs::write(OsStr::from_bytes(path), contents);
+    ev.history.saved = ev.history.entries.len();
+}
+
+/// Read the commands saved in $HISTFILE, as an interactive shell starts
+pub fn load_history(ev: &mut ExecEnv) {
+    let path = match ev.get_var(b"HISTFILE") {
+        Some(path) => path.to_vec(),
+        None => return
+    };
+    let contents = fs::read(OsStr::from_bytes(&path)).unwrap_or_default();
+    for line in contents.split(|c| *c == b'\n').filter(|line| !line.is_empty()) {
+        ev.history.entries.push(decode(line));
+    }
+    ev.history.saved = ev.history.entries.len();
+    let size = history_size(ev);
+    if ev.history.saved > size {
+        ev.history.trim(size);
+        save_history(ev, &path);
+    }
+}
+
+/// Add a command to the history, and append it to $HISTFILE if that is set
+pub fn add_history(ev: &mut ExecEnv, command: &[u8]) {
+    let command = command.strip_suffix(b"\n").unwrap_or(command);
+    if command.iter().all(u8::is_ascii_whitespace) {
+        return
+    }
+
+    ev.history.entries.push(command.to_vec());
+    let size = history_size(ev);
+    ev.history.trim(size);
+
+    let path = match ev.get_var(b"HISTFILE") {
+        Some(path) => path.to_vec(),
+        None => return
+    };
+    if ev.history.saved >= size {
+        return save_history(ev, &path)
+    }
+    let file = OpenOptions::new().append(true).create(true).open(OsStr::from_bytes(&path));
+    if let Ok(mut file) = file {
+        if file.write_all(&encode(command)).is_ok() {
+            ev.history.saved += 1;
+        }
+    }
+}
+
+/// Replace the most recent command in the history of an interactive shell,
+/// as `fc` does with the commands it runs. $HISTFILE is rewritten, as the
+/// command replaced has been appended to it already.
+pub fn replace_last(ev: &mut ExecEnv, command: &[u8]) {
+    if !ev.interactive {
+        return
+    }
+    ev.history.entries.pop();
+    let command = command.strip_suffix(b"\n").unwrap_or(command);
+    if !command.iter().all(u8::is_ascii_whitespace) {
+        ev.history.entries.push(command.to_vec());
+    }
+    if let Some(path) = ev.get_var(b"HISTFILE").map(|path| path.to_vec()) {
+        save_history(ev, &path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_size() {
+        let mut ev = ExecEnv::new();
+        ev.set_var(b"HISTSIZE", b"2".to_vec()).unwrap();
+        add_history(&mut ev, b"a\n");
+        add_history(&mut ev, b"  \n");
+        add_history(&mut ev, b"b\n");
+        add_history(&mut ev, b"c\n");
+        assert_eq!(ev.history.entries, [b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(ev.history.first, 2);
+        assert_eq!(ev.history.get(3), Some(b"c".as_ref()));
+        assert_eq!(ev.history.get(1), None);
+        assert_eq!(ev.history.next_number(), 4);
+    }
+
+    #[test]
+    fn test_history_file() {
+        let path = std::env::temp_dir().join(format!("rustybox-history-{}", std::process::id()));
+        fs::write(&path, b"a\nb\nc\n").unwrap();
+        let mut ev = ExecEnv::new();
+        ev.set_var(b"HISTFILE", path.as_os_str().as_bytes().to_vec()).unwrap();
+        ev.set_var(b"HISTSIZE", b"2".to_vec()).unwrap();
+        load_history(&mut ev);
+        assert_eq!(ev.history.entries, [b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(fs::read(&path).unwrap(), b"b\nc\n");
+
+        add_history(&mut ev, b"for i in 1 2\ndo echo \\$i\ndone\n");
+        assert_eq!(fs::read(&path).unwrap(), b"c\nfor i in 1 2\\ndo echo \\\\$i\\ndone\n");
+
+        let mut ev2 = ExecEnv::new();
+        ev2.set_var(b"HISTFILE", path.as_os_str().as_bytes().to_vec()).unwrap();
+        load_history(&mut ev2);
+        assert_eq!(ev2.history.entries, ev.history.entries);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replace_last() {
+        let path = std::env::temp_dir().join(format!("rustybox-history-fc-{}", std::process::id()));
+        let mut ev = ExecEnv::new();
+        ev.interactive = true;
+        ev.set_var(b"HISTFILE", path.as_os_str().as_bytes().to_vec()).unwrap();
+        add_history(&mut ev, b"echo a\n");
+        add_history(&mut ev, b"fc -s a=b\n");
+        replace_last(&mut ev, b"echo b\n");
+        assert_eq!(ev.history.entries, [b"echo a".to_vec(), b"echo b".to_vec()]);
+        assert_eq!(fs::read(&path).unwrap(), b"echo a\necho b\n");
+
+        add_history(&mut ev, b"echo c\n");
+        assert_eq!(fs::read(&path).unwrap(), b"echo a\necho b\necho c\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,356 @@
+use std::error::Error;
+use std::io::{stderr, Write};
+use std::os::unix::prelude::RawFd;
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::sys::signal::{kill, signal, SigHandler, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{getpgrp, getpid, setpgid, tcgetpgrp, tcsetpgrp, Pid};
+
+use super::ast_nodes::ExecEnv;
+use super::subshell::{wait_for, wait_trapped};
+
+/// The terminal and process group of a shell with job control enabled
+#[derive(Debug, PartialEq)]
+pub struct JobControl {
+    pub tty: RawFd,
+    pub shell_pgid: Pid
+}
+
+/// A process of a job, with its exit status once it has been waited for
+#[derive(Debug, PartialEq)]
+pub struct Process {
+    pub pid: Pid,
+    pub status: Option<i32>
+}
+
+/// A pipeline or asynchronous list known to the shell
+#[derive(Debug, PartialEq)]
+pub struct Job {
+    pub id: usize,
+    pub pgid: Pid,
+    /// The last process gives the exit status of the job
+    pub processes: Vec<Process>,
+    pub stopped: bool,
+    pub command: Vec<u8>
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum JobState {
+    Running,
+    Stopped,
+    Done(i32)
+}
+
+impl Job {
+    pub fn new(pids: Vec<Pid>, command: Vec<u8>) -> Job {
+        Job {
+            id: 0,
+            pgid: pids[0],
+            processes: pids.into_iter().map(|pid| Process { pid, status: None }).collect(),
+            stopped: false,
+            command
+        }
+    }
+
+    pub fn state(&self) -> JobState {
+        if self.processes.iter().all(|process| process.status.is_some()) {
+            JobState::Done(self.processes.last().and_then(|process| process.status).unwrap_or(0))
+        } else if self.stopped {
+            JobState::Stopped
+        } else {
+            JobState::Running
+        }
+    }
+
+    /// Record a status reported by waitpid for one of the job's processes
+    fn update(&mut self, wait_status: WaitStatus) {
+        let (pid, status) = match wait_status {
+            WaitStatus::Exited(pid, status) => (pid, status),
+            WaitStatus::Signaled(pid, signal, _) => (pid, 128 + signal as i32),
+            WaitStatus::Stopped(_, _) => {
+                self.stopped = true;
+                return
+            },
+            WaitStatus::Continued(_) => {
+                self.stopped = false;
+                return
+            },
+            _ => return
+        };
+
+        for process in self.processes.iter_mut() {
+            if process.pid == pid {
+                process.status = Some(status);
+            }
+        }
+    }
+}
+
+/// Turn on job control for an interactive shell reading from a terminal
+pub fn enable_job_control(ev: &mut ExecEnv) -> Result<(), Box<dyn Error>> {
+    let tty = fcntl(0, FcntlArg::F_DUPFD_CLOEXEC(10))?;
+
+    // Wait until the shell has been brought into the foreground
+    loop {
+        let pgrp = getpgrp();
+        if tcgetpgrp(tty)? == pgrp {
+            break;
+        }
+        kill(Pid::from_raw(-pgrp.as_raw()), Signal::SIGTTIN)?;
+    }
+
+    unsafe {
+        signal(Signal::SIGTSTP, SigHandler::SigIgn)?;
+        signal(Signal::SIGTTIN, SigHandler::SigIgn)?;
+        signal(Signal::SIGTTOU, SigHandler::SigIgn)?;
+    }
+
+    // Put the shell in a process group of its own and take the terminal
+    let shell_pgid = getpid();
+    if getpgrp() != shell_pgid {
+        setpgid(shell_pgid, shell_pgid)?;
+    }
+    tcsetpgrp(tty, shell_pgid)?;
+
+    ev.job_control = Some(JobControl {
+        tty,
+        shell_pgid
+    });
+    Ok(())
+}
+
+/// Put a newly started process in the process group of its job, creating
+/// the group when `pgid` is None. This is done in both the parent and the
+/// child so the group exists before either of them relies on it.
+pub fn join_process_group(pid: Pid, pgid: Option<Pid>) {
+    let _ = setpgid(pid, pgid.unwrap_or(pid));
+}
+
+/// Add a job to the job table, returning its job number. A job which has
+/// been in the table before keeps its number.
+pub fn add_job(ev: &mut ExecEnv, mut job: Job) -> usize {
+    if job.id == 0 {
+        job.id = ev.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+    }
+    let id = job.id;
+    ev.jobs.push(job);
+    id
+}
+
+/// Collect the status of any jobs which have finished, stopped or been
+/// continued, without blocking
+pub fn update_jobs(ev: &mut ExecEnv) {
+    let flags = WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED;
+    for job in ev.jobs.iter_mut() {
+        let pids: Vec<Pid> = job.processes.iter()
+            .filter(|process| process.status.is_none())
+            .map(|process| process.pid)
+            .collect();
+        for pid in pids {
+            if let Ok(status) = waitpid(pid, Some(flags)) {
+                job.update(status);
+            }
+        }
+    }
+}
+
+/// Report jobs which have finished or stopped since the last prompt,
+/// removing finished jobs from the table
+pub fn notify_jobs(ev: &mut ExecEnv) {
+    let before: Vec<JobState> = ev.jobs.iter().map(|job| job.state()).collect();
+    update_jobs(ev);
+
+    let current = current_job(ev);
+    for (index, job) in ev.jobs.iter().enumerate() {
+        let state = job.state();
+        if state != before[index] || matches!(state, JobState::Done(_)) {
+            report(&format_job(job, current == Some(index), state));
+        }
+    }
+    ev.jobs.retain(|job| !matches!(job.state(), JobState::Done(_)));
+}
+
+/// The index of the job which `%+` refers to: the most recently stopped
+/// job, or else the most recently started
+pub fn current_job(ev: &ExecEnv) -> Option<usize> {
+    match ev.jobs.iter().rposition(|job| job.state() == JobState::Stopped) {
+        Some(index) => Some(index),
+        None => ev.jobs.len().checked_sub(1)
+    }
+}
+
+fn previous_job(ev: &ExecEnv) -> Option<usize> {
+    let current = current_job(ev)?;
+    (0..ev.jobs.len()).rev().find(|index| *index != current)
+}
+
+/// A line describing a job as printed by `jobs` and job notifications
+pub fn format_job(job: &Job, current: bool, state: JobState) -> Vec<u8> {
+    let marker = if current { '+' } else { ' ' };
+    let (state, suffix) = match state {
+        JobState::Running => ("Running".to_string(), " &"),
+        JobState::Stopped => ("Stopped".to_string(), ""),
+        JobState::Done(0) => ("Done".to_string(), ""),
+        JobState::Done(status) => (format!("Done({})", status), "")
+    };
+    let mut line = format!("[{}]{}  {:<24}", job.id, marker, state).into_bytes();
+    line.extend_from_slice(&job.command);
+    line.extend_from_slice(suffix.as_bytes());
+    line
+}
+
+/// Write a line about a job to stderr
+fn report(line: &[u8]) {
+    let mut line = line.to_vec();
+    line.push(b'\n');
+    let _ = stderr().write_all(&line);
+}
+
+/// Find the job named by a job ID such as `%1`, `%%`, `%+`, `%-` or
+/// `%string`, or by the process ID of one of its processes, returning its
+/// index in the job table
+pub fn find_job(ev: &ExecEnv, spec: &[u8]) -> Option<usize> {
+    let id = match spec.strip_prefix(b"%") {
+        Some(id) => id,
+        None => {
+            let pid: i32 = std::str::from_utf8(spec).ok()?.parse().ok()?;
+            return ev.jobs.iter().position(|job| {
+                job.processes.iter().any(|process| process.pid.as_raw() == pid)
+            })
+        }
+    };
+
+    match id {
+        b"" | b"%" | b"+" => current_job(ev),
+        b"-" => previous_job(ev),
+        _ if id.iter().all(u8::is_ascii_digit) => {
+            let n: usize = std::str::from_utf8(id).ok()?.parse().ok()?;
+            ev.jobs.iter().position(|job| job.id == n)
+        },
+        _ => ev.jobs.iter().rposition(|job| job.command.starts_with(id))
+    }
+}
+
+/// Wait for the job at `index` in the job table to finish, removing it
+/// from the table and returning its exit status. If a signal with a trap is
+/// received first, the job is left in the table and None is returned.
+pub fn wait_job(ev: &mut ExecEnv, index: usize) -> Result<Option<i32>, Box<dyn Error>> {
+    for process in ev.jobs[index].processes.iter_mut() {
+        if process.status.is_none() {
+            match wait_trapped(process.pid)? {
+                Some(status) => process.status = Some(status),
+                None => return Ok(None)
+            }
+        }
+    }
+
+    match ev.jobs.remove(index).state() {
+        JobState::Done(status) => Ok(Some(status)),
+        _ => Ok(Some(0))
+    }
+}
+
+/// The exit status of a pipeline given those of its commands: that of the
+/// last command, or with pipefail that of the last one to fail
+fn pipeline_status(statuses: &[i32], pipefail: bool) -> i32 {
+    if pipefail {
+        statuses.iter().rev().find(|status| **status != 0).copied().unwrap_or(0)
+    } else {
+        statuses.last().copied().unwrap_or(0)
+    }
+}
+
+/// Wait for a job running in the foreground. With job control enabled the
+/// job is given the terminal, and is added to the job table if it stops.
+pub fn wait_foreground(ev: &mut ExecEnv, mut job: Job) -> Result<i32, Box<dyn Error>> {
+    let (tty, shell_pgid) = match &ev.job_control {
+        Some(jc) => (jc.tty, jc.shell_pgid),
+        None => {
+            let mut statuses = vec![];
+            for process in job.processes.iter() {
+                statuses.push(wait_for(process.pid)?);
+            }
+            return Ok(pipeline_status(&statuses, ev.options.pipefail))
+        }
+    };
+
+    let _ = tcsetpgrp(tty, job.pgid);
+    let mut stop_signal = None;
+    for i in 0..job.processes.len() {
+        while job.processes[i].status.is_none() {
+            let status = match waitpid(job.processes[i].pid, Some(WaitPidFlag::WUNTRACED)) {
+                Err(Errno::EINTR) => continue,
+                status => status?
+            };
+            if let WaitStatus::Stopped(_, signal) = status {
+                stop_signal = Some(signal);
+            }
+            job.update(status);
+            if job.stopped {
+                break;
+            }
+        }
+        if job.stopped {
+            break;
+        }
+    }
+    let _ = tcsetpgrp(tty, shell_pgid);
+
+    match job.state() {
+        JobState::Done(status) if ev.options.pipefail => {
+            let statuses: Vec<i32> = job.processes.iter().map(|process| process.status.unwrap_or(status)).collect();
+            Ok(pipeline_status(&statuses, true))
+        },
+        JobState::Done(status) => Ok(status),
+        _ => {
+            let signal = stop_signal.unwrap_or(Signal::SIGTSTP);
+            add_job(ev, job);
+            let index = ev.jobs.len() - 1;
+            eprintln!();
+            report(&format_job(&ev.jobs[index], true, JobState::Stopped));
+            Ok(128 + signal as i32)
+        }
+    }
+}
+
+/// Send `sig` (or, for None, check the job can be signalled) to the job at
+/// `index` in the job table: to its process group under job control, or
+/// else to each of its processes. A stopped job is continued so that it
+/// can act on the signal.
+pub fn signal_job(ev: &ExecEnv, index: usize, sig: Option<Signal>) -> nix::Result<()> {
+    let job = &ev.jobs[index];
+    let pids: Vec<Pid> = match ev.job_control {
+        Some(_) => vec![Pid::from_raw(-job.pgid.as_raw())],
+        None => job.processes.iter().filter(|process| process.status.is_none()).map(|process| process.pid).collect()
+    };
+    for pid in pids.iter() {
+        kill(*pid, sig)?;
+    }
+    if job.stopped && sig.is_some_and(|sig| sig != Signal::SIGKILL && sig != Signal::SIGCONT) {
+        for pid in pids {
+            kill(pid, Signal::SIGCONT)?;
+        }
+    }
+    Ok(())
+}
+
+/// Continue a stopped job, in the foreground or background
+pub fn continue_job(ev: &mut ExecEnv, index: usize, foreground: bool) -> Result<i32, Box<dyn Error>> {
+    let pgid = ev.jobs[index].pgid;
+    if foreground {
+        let job = ev.jobs.remove(index);
+        report(&job.command);
+        if let Some(jc) = &ev.job_control {
+            let _ = tcsetpgrp(jc.tty, pgid);
+        }
+        kill(Pid::from_raw(-pgid.as_raw()), Signal::SIGCONT)?;
+        wait_foreground(ev, Job { stopped: false, ..job })
+    } else {
+        let job = &mut ev.jobs[index];
+        job.stopped = false;
+        report(&[format!("[{}] ", job.id).as_bytes(), &job.command, b" &"].concat());
+        kill(Pid::from_raw(-pgid.as_raw()), Signal::SIGCONT)?;
+        Ok(0)
+    }
+}
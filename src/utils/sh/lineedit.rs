@@ -0,0 +1,698 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, stderr, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::prelude::OsStrExt;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::termios::{tcgetattr, tcsetattr, InputFlags, LocalFlags, SetArg, SpecialCharacterIndices, Termios};
+use nix::unistd::{isatty, read};
+
+use crate::command_names;
+use super::ast_nodes::ExecEnv;
+use super::builtins::builtin_names;
+
+/// How long to wait for the rest of an escape sequence before taking the
+/// escape key on its own, in milliseconds
+const ESCAPE_TIMEOUT: i32 = 50;
+
+/// Keeps the terminal in raw mode for as long as it is alive
+struct RawMode {
+    saved: Termios
+}
+
+impl RawMode {
+    fn enable() -> nix::Result<RawMode> {
+        let saved = tcgetattr(0)?;
+        let mut raw = saved.clone();
+        raw.local_flags.remove(LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ISIG | LocalFlags::IEXTEN);
+        raw.input_flags.remove(InputFlags::ICRNL | InputFlags::IXON);
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        tcsetattr(0, SetArg::TCSADRAIN, &raw)?;
+        Ok(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = tcsetattr(0, SetArg::TCSADRAIN, &self.saved);
+    }
+}
+
+/// A key read from the terminal, with escape sequences decoded
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Key {
+    Byte(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Delete,
+    /// The escape key pressed on its own
+    Escape,
+    /// A key pressed with Alt, which sends it after an escape
+    Alt(u8)
+}
+
+/// What to do with the line once a key has been handled
+enum Outcome {
+    Continue,
+    Accept,
+    Cancel,
+    Eof
+}
+
+pub fn read_byte() -> io::Result<Option<u8>> {
+    let mut buf = [0u8; 1];
+    loop {
+        match read(0, &mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(buf[0])),
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into())
+        }
+    }
+}
+
+/// Whether more input arrives within the escape timeout
+fn input_pending() -> bool {
+    let mut fds = [PollFd::new(0, PollFlags::POLLIN)];
+    matches!(poll(&mut fds, ESCAPE_TIMEOUT), Ok(n) if n > 0)
+}
+
+fn read_key() -> io::Result<Option<Key>> {
+    let byte = match read_byte()? {
+        Some(byte) => byte,
+        None => return Ok(None)
+    };
+    if byte != 0x1b {
+        return Ok(Some(Key::Byte(byte)))
+    }
+    if !input_pending() {
+        return Ok(Some(Key::Escape))
+    }
+
+    let introducer = match read_byte()? {
+        Some(introducer @ (b'[' | b'O')) => introducer,
+        Some(byte) => return Ok(Some(Key::Alt(byte))),
+        None => return Ok(Some(Key::Escape))
+    };
+
+    // A control sequence is any parameters followed by a final letter
+    let mut params = vec![];
+    let last = loop {
+        match read_byte()? {
+            Some(byte) if introducer == b'[' && (0x20..0x40).contains(&byte) => params.push(byte),
+            Some(byte) => break byte,
+            None => return Ok(None)
+        }
+    };
+    let key = match (last, params.as_slice()) {
+        (b'A', _) => Key::Up,
+        (b'B', _) => Key::Down,
+        (b'C', _) => Key::Right,
+        (b'D', _) => Key::Left,
+        (b'H', _) | (b'~', b"1") | (b'~', b"7") => Key::Home,
+        (b'F', _) | (b'~', b"4") | (b'~', b"8") => Key::End,
+        (b'~', b"3") => Key::Delete,
+        _ => Key::Escape
+    };
+    Ok(Some(key))
+}
+
+/// The number of characters in some UTF-8 text
+fn char_count(text: &[u8]) -> usize {
+    text.iter().filter(|c| (**c & 0xc0) != 0x80).count()
+}
+
+fn common_prefix(candidates: &[Vec<u8>]) -> Vec<u8> {
+    let mut prefix = candidates[0].clone();
+    for candidate in candidates[1..].iter() {
+        let len = prefix.iter().zip(candidate.iter()).take_while(|(a, b)| a == b).count();
+        prefix.truncate(len);
+    }
+    prefix
+}
+
+/// The commands starting with `word`: builtins, functions, the commands
+/// built into RustyBox and executables in PATH
+fn command_candidates(word: &[u8], ev: &ExecEnv) -> Vec<Vec<u8>> {
+    let mut names: Vec<Vec<u8>> = builtin_names().map(|name| name.to_vec()).collect();
+    names.extend(ev.functions.keys().map(|name| name.as_bytes().to_vec()));
+    names.extend(command_names().map(|name| name.as_bytes().to_vec()));
+
+    let path = ev.get_var(b"PATH").unwrap_or_default();
+    for dir in path.split(|c| *c == b':').filter(|dir| !dir.is_empty()) {
+        let entries = match fs::read_dir(OsStr::from_bytes(dir)) {
+            Ok(entries) => entries,
+            Err(_) => continue
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            if !name.as_bytes().starts_with(word) {
+                continue;
+            }
+            let executable = entry.path().metadata()
+                .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0);
+            if executable {
+                names.push(name.as_bytes().to_vec());
+            }
+        }
+    }
+
+    names.retain(|name| name.starts_with(word));
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// The paths starting with `word`, with a slash after those of directories
+fn file_candidates(word: &[u8]) -> Vec<Vec<u8>> {
+    let (dir, prefix) = match word.iter().rposition(|c| *c == b'/') {
+        Some(i) => (&word[..=i], &word[(i + 1)..]),
+        None => (&b""[..], word)
+    };
+    let entries = match fs::read_dir(OsStr::from_bytes(if dir.is_empty() { b"." } else { dir })) {
+        Ok(entries) => entries,
+        Err(_) => return vec![]
+    };
+
+    let mut paths = vec![];
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let name = name.as_bytes();
+        // Hidden files are only completed when asked for
+        if !name.starts_with(prefix) || (name.starts_with(b".") && !prefix.starts_with(b".")) {
+            continue;
+        }
+        let mut path = [dir, name].concat();
+        if entry.path().is_dir() {
+            path.push(b'/');
+        }
+        paths.push(path);
+    }
+    paths.sort();
+    paths
+}
+
+/// The state of a line being edited
+struct Editor<'a> {
+    ev: &'a ExecEnv,
+    /// The last line of the prompt, which is redrawn with the line
+    prompt: &'a [u8],
+    line: Vec<u8>,
+    cursor: usize,
+    /// The history entry being shown, or the number of entries for the
+    /// line being entered
+    history_index: usize,
+    /// The line being entered, kept while moving through the history
+    new_line: Vec<u8>,
+    /// The text last deleted, which can be put back
+    kill_buffer: Vec<u8>,
+    /// Whether keys are vi commands rather than text to insert
+    command_mode: bool,
+    /// A vi command waiting for the key it applies to
+    pending: Option<u8>,
+    last_key: Option<Key>,
+    /// The line and cursor position as last drawn, or None when the line
+    /// must be drawn again in full
+    drawn: Option<(Vec<u8>, usize)>,
+    out: Vec<u8>
+}
+
+impl<'a> Editor<'a> {
+    fn new(prompt: &'a [u8], ev: &'a ExecEnv) -> Editor<'a> {
+        Editor {
+            ev,
+            prompt: prompt.rsplit(|c| *c == b'\n').next().unwrap_or_default(),
+            line: vec![],
+            cursor: 0,
+            history_index: ev.history.entries.len(),
+            new_line: vec![],
+            kill_buffer: vec![],
+            command_mode: false,
+            pending: None,
+            last_key: None,
+            drawn: Some((vec![], 0)),
+            out: vec![]
+        }
+    }
+
+    /// Redraw the line and put the cursor back in place
+    fn refresh(&mut self) {
+        let drawn = self.drawn.replace((self.line.clone(), self.cursor));
+        if let Some((drawn, drawn_cursor)) = drawn {
+            // Typing at the end of the line only needs the new text echoed
+            let at_end = drawn_cursor == drawn.len() && self.cursor == self.line.len();
+            if at_end && self.line.starts_with(&drawn) {
+                self.out.extend_from_slice(&self.line[drawn.len()..]);
+                return
+            }
+        }
+
+        self.out.push(b'\r');
+        self.out.extend_from_slice(self.prompt);
+        self.out.extend_from_slice(&self.line);
+        self.out.extend_from_slice(b"\x1b[K");
+        let back = char_count(&self.line[self.cursor..]);
+        if back > 0 {
+            self.out.extend_from_slice(format!("\x1b[{}D", back).as_bytes());
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut err = stderr();
+        err.write_all(&self.out)?;
+        self.out.clear();
+        err.flush()
+    }
+
+    fn bell(&mut self) {
+        self.out.push(0x07);
+    }
+
+    fn prev_boundary(&self, mut i: usize) -> usize {
+        while i > 0 {
+            i -= 1;
+            if (self.line[i] & 0xc0) != 0x80 {
+                break;
+            }
+        }
+        i
+    }
+
+    fn next_boundary(&self, mut i: usize) -> usize {
+        while i < self.line.len() {
+            i += 1;
+            if i == self.line.len() || (self.line[i] & 0xc0) != 0x80 {
+                break;
+            }
+        }
+        i
+    }
+
+    /// The start of the word before `i`
+    fn word_start(&self, mut i: usize) -> usize {
+        while i > 0 && self.line[i - 1] == b' ' {
+            i -= 1;
+        }
+        while i > 0 && self.line[i - 1] != b' ' {
+            i -= 1;
+        }
+        i
+    }
+
+    /// The end of the word after `i`
+    fn word_end(&self, mut i: usize) -> usize {
+        while i < self.line.len() && self.line[i] == b' ' {
+            i += 1;
+        }
+        while i < self.line.len() && self.line[i] != b' ' {
+            i += 1;
+        }
+        i
+    }
+
+    /// The start of the next word after `i`, as for the vi `w` command
+    fn next_word(&self, mut i: usize) -> usize {
+        while i < self.line.len() && self.line[i] != b' ' {
+            i += 1;
+        }
+        while i < self.line.len() && self.line[i] == b' ' {
+            i += 1;
+        }
+        i
+    }
+
+    fn insert(&mut self, text: &[u8]) {
+        self.line.splice(self.cursor..self.cursor, text.iter().copied());
+        self.cursor += text.len();
+    }
+
+    /// Delete the text between two positions, keeping it to be put back
+    fn kill(&mut self, from: usize, to: usize) {
+        let (from, to) = (from.min(to), from.max(to).min(self.line.len()));
+        self.kill_buffer = self.line.drain(from..to).collect();
+        self.cursor = from;
+    }
+
+    /// Keep the cursor on a character, as it is in vi command mode
+    fn clamp_cursor(&mut self) {
+        if self.command_mode && self.cursor > 0 && self.cursor >= self.line.len() {
+            self.cursor = self.prev_boundary(self.line.len());
+        }
+    }
+
+    fn show_history(&mut self, older: bool) {
+        let entries = &self.ev.history.entries;
+        if (older && self.history_index == 0) || (!older && self.history_index == entries.len()) {
+            self.bell();
+            return
+        }
+        if self.history_index == entries.len() {
+            self.new_line = self.line.clone();
+        }
+        if older {
+            self.history_index -= 1;
+        } else {
+            self.history_index += 1;
+        }
+        self.line = match entries.get(self.history_index) {
+            Some(entry) => entry.clone(),
+            None => self.new_line.clone()
+        };
+        self.cursor = if self.command_mode { 0 } else { self.line.len() };
+    }
+
+    fn complete(&mut self) {
+        let start = self.line[..self.cursor].iter()
+            .rposition(|c| b" \t;|&()<>".contains(c))
+            .map_or(0, |i| i + 1);
+        let word = self.line[start..self.cursor].to_vec();
+        let before = self.line[..start].iter().rposition(|c| *c != b' ' && *c != b'\t').map(|i| self.line[i]);
+        let command = !word.contains(&b'/') && matches!(before, None | Some(b';' | b'|' | b'&' | b'('));
+
+        let candidates = if command {
+            command_candidates(&word, self.ev)
+        } else {
+            file_candidates(&word)
+        };
+        if candidates.is_empty() {
+            self.bell();
+            return
+        }
+
+        let common = common_prefix(&candidates);
+        if candidates.len() == 1 {
+            self.insert(&common[word.len()..]);
+            if !common.ends_with(b"/") {
+                self.insert(b" ");
+            }
+        } else if common.len() > word.len() {
+            self.insert(&common[word.len()..]);
+        } else if self.last_key == Some(Key::Byte(b'\t')) {
+            // A second tab lists the possibilities
+            let dir_len = word.iter().rposition(|c| *c == b'/').map_or(0, |i| i + 1);
+            let names: Vec<&[u8]> = candidates.iter()
+                .map(|candidate| if command { &candidate[..] } else { &candidate[dir_len..] })
+                .collect();
+            self.out.extend_from_slice(b"\n");
+            self.out.extend_from_slice(&names.join(&b"  "[..]));
+            self.out.extend_from_slice(b"\n");
+            self.drawn = None;
+        } else {
+            self.bell();
+        }
+    }
+
+    /// Handle a key in emacs mode, or in vi insert mode
+    fn edit_key(&mut self, key: Key) -> Outcome {
+        match key {
+            Key::Byte(b'\r' | b'\n') => return Outcome::Accept,
+            Key::Byte(0x03) => return Outcome::Cancel,
+            Key::Byte(0x04) if self.line.is_empty() => return Outcome::Eof,
+            Key::Byte(0x04) | Key::Delete => {
+                let next = self.next_boundary(self.cursor);
+                self.line.drain(self.cursor..next);
+            },
+            Key::Byte(0x01) | Key::Home => self.cursor = 0,
+            Key::Byte(0x05) | Key::End => self.cursor = self.line.len(),
+            Key::Byte(0x02) | Key::Left => self.cursor = self.prev_boundary(self.cursor),
+            Key::Byte(0x06) | Key::Right => self.cursor = self.next_boundary(self.cursor),
+            Key::Byte(0x08 | 0x7f) => {
+                let prev = self.prev_boundary(self.cursor);
+                self.line.drain(prev..self.cursor);
+                self.cursor = prev;
+            },
+            Key::Byte(b'\t') => self.complete(),
+            Key::Byte(0x0b) => self.kill(self.cursor, self.line.len()),
+            Key::Byte(0x0c) => {
+                self.out.extend_from_slice(b"\x1b[H\x1b[2J");
+                self.drawn = None;
+            },
+            Key::Byte(0x0e) | Key::Down => self.show_history(false),
+            Key::Byte(0x10) | Key::Up => self.show_history(true),
+            Key::Byte(0x15) => self.kill(0, self.cursor),
+            Key::Byte(0x17) | Key::Alt(0x7f) => self.kill(self.word_start(self.cursor), self.cursor),
+            Key::Byte(0x19) => {
+                let text = self.kill_buffer.clone();
+                self.insert(&text);
+            },
+            Key::Alt(b'b') => self.cursor = self.word_start(self.cursor),
+            Key::Alt(b'f') => self.cursor = self.word_end(self.cursor),
+            Key::Alt(b'd') => self.kill(self.cursor, self.word_end(self.cursor)),
+            Key::Escape if self.ev.options.vi => {
+                self.command_mode = true;
+                self.cursor = self.prev_boundary(self.cursor);
+            },
+            Key::Byte(byte) if byte >= 0x20 => self.insert(&[byte]),
+            _ => self.bell()
+        }
+        Outcome::Continue
+    }
+
+    /// The position a vi motion command moves the cursor to
+    fn vi_motion(&self, key: Key) -> Option<usize> {
+        let position = match key {
+            Key::Byte(b'h') | Key::Byte(0x08 | 0x7f) | Key::Left => self.prev_boundary(self.cursor),
+            Key::Byte(b'l' | b' ') | Key::Right => self.next_boundary(self.cursor),
+            Key::Byte(b'0') | Key::Home => 0,
+            Key::Byte(b'^') => self.line.iter().position(|c| *c != b' ').unwrap_or(0),
+            Key::Byte(b'$') | Key::End => self.line.len(),
+            Key::Byte(b'w') => self.next_word(self.cursor),
+            Key::Byte(b'b') => self.word_start(self.cursor),
+            Key::Byte(b'e') => self.prev_boundary(self.word_end(self.next_boundary(self.cursor))),
+            _ => return None
+        };
+        Some(position)
+    }
+
+    /// Apply a vi operator or replacement to the key which follows it
+    fn vi_pending(&mut self, command: u8, key: Key) {
+        match (command, key) {
+            (b'r', Key::Byte(byte)) if byte >= 0x20 && self.cursor < self.line.len() => {
+                let next = self.next_boundary(self.cursor);
+                self.line.splice(self.cursor..next, [byte]);
+            },
+            (b'r', _) => self.bell(),
+            (op, motion) => {
+                // An operator applies to the text up to where a motion goes,
+                // or to the whole line when repeated. Like vi, `cw` changes
+                // to the end of the word.
+                let end = match motion {
+                    Key::Byte(byte) if byte == op => {
+                        self.cursor = 0;
+                        Some(self.line.len())
+                    },
+                    Key::Byte(b'w') if op == b'c' && self.line.get(self.cursor) != Some(&b' ') => {
+                        Some(self.word_end(self.cursor))
+                    },
+                    Key::Byte(b'e') => self.vi_motion(motion).map(|end| self.next_boundary(end)),
+                    _ => self.vi_motion(motion)
+                };
+                match end {
+                    Some(end) => self.kill(self.cursor, end),
+                    None => self.bell()
+                }
+                if op == b'c' {
+                    self.command_mode = false;
+                }
+            }
+        }
+    }
+
+    /// Handle a key in vi command mode
+    fn vi_command(&mut self, key: Key) -> Outcome {
+        if let Some(command) = self.pending.take() {
+            self.vi_pending(command, key);
+            self.clamp_cursor();
+            return Outcome::Continue
+        }
+        if let Some(position) = self.vi_motion(key) {
+            self.cursor = position;
+            self.clamp_cursor();
+            return Outcome::Continue
+        }
+
+        match key {
+            Key::Byte(b'\r' | b'\n') => return Outcome::Accept,
+            Key::Byte(0x03) => return Outcome::Cancel,
+            Key::Byte(0x04) if self.line.is_empty() => return Outcome::Eof,
+            Key::Byte(b'i') => self.command_mode = false,
+            Key::Byte(b'a') => {
+                self.command_mode = false;
+                self.cursor = self.next_boundary(self.cursor);
+            },
+            Key::Byte(b'I') => {
+                self.command_mode = false;
+                self.cursor = 0;
+            },
+            Key::Byte(b'A') => {
+                self.command_mode = false;
+                self.cursor = self.line.len();
+            },
+            Key::Byte(b'x') | Key::Delete => self.kill(self.cursor, self.next_boundary(self.cursor)),
+            Key::Byte(b'X') => self.kill(self.prev_boundary(self.cursor), self.cursor),
+            Key::Byte(b'D') => self.kill(self.cursor, self.line.len()),
+            Key::Byte(b'C') => {
+                self.kill(self.cursor, self.line.len());
+                self.command_mode = false;
+            },
+            Key::Byte(b'S') => {
+                self.kill(0, self.line.len());
+                self.command_mode = false;
+            },
+            Key::Byte(command @ (b'd' | b'c' | b'r')) => self.pending = Some(command),
+            Key::Byte(b'p') => {
+                let text = self.kill_buffer.clone();
+                self.cursor = self.next_boundary(self.cursor);
+                self.insert(&text);
+                self.cursor = self.prev_boundary(self.cursor);
+            },
+            Key::Byte(b'P') => {
+                let text = self.kill_buffer.clone();
+                self.insert(&text);
+                self.cursor = self.prev_boundary(self.cursor);
+            },
+            Key::Byte(b'k' | b'-') | Key::Up => self.show_history(true),
+            Key::Byte(b'j' | b'+') | Key::Down => self.show_history(false),
+            _ => self.bell()
+        }
+        self.clamp_cursor();
+        Outcome::Continue
+    }
+}
+
+/// Read a line of input with no editing, including its newline, returning
+/// None at the end of the input. Input is read a byte at a time so that
+/// none is left buffered where the commands run can't read it.
+pub fn read_plain_line() -> io::Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    while let Some(byte) = read_byte()? {
+        line.push(byte);
+        if byte == b'\n' {
+            break;
+        }
+    }
+    Ok(if line.is_empty() { None } else { Some(line) })
+}
+
+/// Read a line of input after writing `prompt` to stderr, returning None at
+/// the end of the input. On a terminal the line can be edited, with keys
+/// like those of emacs or, with `set -o vi`, of vi.
+pub fn read_line(prompt: &[u8], ev: &ExecEnv) -> io::Result<Option<Vec<u8>>> {
+    let mut err = stderr();
+    if !isatty(0).unwrap_or(false) {
+        err.write_all(prompt)?;
+        err.flush()?;
+        return read_plain_line()
+    }
+
+    // Anything typed before the prompt appears is echoed by the editor
+    // rather than the terminal
+    let _raw = RawMode::enable()?;
+    err.write_all(prompt)?;
+    err.flush()?;
+    let mut editor = Editor::new(prompt, ev);
+    while let Some(key) = read_key()? {
+        let outcome = if editor.command_mode {
+            editor.vi_command(key)
+        } else {
+            editor.edit_key(key)
+        };
+        editor.last_key = Some(key);
+
+        match outcome {
+            Outcome::Continue => editor.refresh(),
+            Outcome::Accept => break,
+            Outcome::Cancel => {
+                editor.out.extend_from_slice(b"^C\n");
+                editor.flush()?;
+                return Ok(Some(b"\n".to_vec()))
+            },
+            Outcome::Eof => {
+                editor.out.extend_from_slice(b"\n");
+                editor.flush()?;
+                return Ok(None)
+            }
+        }
+        editor.flush()?;
+    }
+
+    editor.out.extend_from_slice(b"\n");
+    editor.flush()?;
+    let mut line = editor.line;
+    line.push(b'\n');
+    Ok(Some(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_keys(editor: &mut Editor, keys: &[Key]) {
+        for key in keys {
+            if editor.command_mode {
+                editor.vi_command(*key);
+            } else {
+                editor.edit_key(*key);
+            }
+            editor.last_key = Some(*key);
+        }
+    }
+
+    fn type_text(editor: &mut Editor, text: &[u8]) {
+        let keys: Vec<Key> = text.iter().map(|c| Key::Byte(*c)).collect();
+        type_keys(editor, &keys);
+    }
+
+    #[test]
+    fn test_emacs_keys() {
+        let ev = ExecEnv::new();
+        let mut editor = Editor::new(b"$ ", &ev);
+        type_text(&mut editor, b"echo one two");
+        type_keys(&mut editor, &[Key::Byte(0x17), Key::Alt(b'b'), Key::Byte(0x19)]);
+        assert_eq!(editor.line, b"echo twoone ");
+        type_keys(&mut editor, &[Key::Home, Key::Byte(0x0b)]);
+        assert_eq!(editor.line, b"");
+        assert_eq!(editor.kill_buffer, b"echo twoone ");
+    }
+
+    #[test]
+    fn test_utf8_cursor() {
+        let ev = ExecEnv::new();
+        let mut editor = Editor::new(b"$ ", &ev);
+        type_text(&mut editor, "aé".as_bytes());
+        type_keys(&mut editor, &[Key::Left, Key::Byte(b'b'), Key::End, Key::Byte(0x7f)]);
+        assert_eq!(editor.line, b"ab");
+    }
+
+    #[test]
+    fn test_vi_keys() {
+        let mut ev = ExecEnv::new();
+        ev.options.vi = true;
+        let mut editor = Editor::new(b"$ ", &ev);
+        type_text(&mut editor, b"echo one two");
+        type_keys(&mut editor, &[Key::Escape]);
+        type_text(&mut editor, b"0wdwA!");
+        assert_eq!(editor.line, b"echo two!");
+        type_keys(&mut editor, &[Key::Escape]);
+        type_text(&mut editor, b"0cwprintf");
+        assert_eq!(editor.line, b"printf two!");
+    }
+
+    #[test]
+    fn test_history_keys() {
+        let mut ev = ExecEnv::new();
+        ev.history.entries = vec![b"first".to_vec(), b"second".to_vec()];
+        let mut editor = Editor::new(b"$ ", &ev);
+        type_text(&mut editor, b"new");
+        type_keys(&mut editor, &[Key::Up, Key::Up]);
+        assert_eq!(editor.line, b"first");
+        type_keys(&mut editor, &[Key::Down, Key::Down]);
+        assert_eq!(editor.line, b"new");
+    }
+}
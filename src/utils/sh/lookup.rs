@@ -0,0 +1,166 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::prelude::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use super::applets::{find_applet, is_builtin_applet, Applet};
+use super::ast_nodes::{ExecEnv, ShellCommand};
+use super::builtins::{find_builtin, find_special_builtin, BuiltinMain};
+
+/// The directories searched for programs when PATH is unset, and by
+/// `command -p`
+pub const DEFAULT_PATH: &[u8] = b"/usr/bin:/bin";
+
+/// What a command name refers to
+pub enum Resolved {
+    SpecialBuiltin(BuiltinMain),
+    Function(Rc<ShellCommand>),
+    Builtin(BuiltinMain),
+    Applet(Applet),
+    /// A program to execute, with whether it was already in the hash table
+    Program(PathBuf, bool)
+}
+
+/// How to look up a command name
+#[derive(Clone, Copy, Default)]
+pub struct Lookup {
+    /// Skip shell functions, as `command` does
+    pub skip_functions: bool,
+    /// Search the default PATH rather than the value of PATH, and without
+    /// using the hash table
+    pub default_path: bool
+}
+
+/// Find what the command `name` runs: a special builtin, a function, a
+/// builtin, an applet or a program, in that order. Unless the
+/// sh-prefer-applets feature is enabled, a program of the same name found in
+/// PATH is run instead of an applet, except for the applets which are
+/// builtins in other shells and for a link to this binary.
+pub fn resolve(name: &[u8], ev: &mut ExecEnv, lookup: Lookup) -> Option<Resolved> {
+    if let Some(builtin) = find_special_builtin(name) {
+        return Some(Resolved::SpecialBuiltin(builtin))
+    }
+    if !lookup.skip_functions {
+        if let Some(body) = ev.functions.get(OsStr::from_bytes(name)) {
+            return Some(Resolved::Function(body.clone()))
+        }
+    }
+    if let Some(builtin) = find_builtin(name) {
+        return Some(Resolved::Builtin(builtin))
+    }
+
+    let applet = find_applet(name);
+    if let Some(applet) = applet {
+        if cfg!(feature = "sh-prefer-applets") || is_builtin_applet(applet) {
+            return Some(Resolved::Applet(applet))
+        }
+    }
+    let program = if lookup.default_path {
+        search_path(name, DEFAULT_PATH).map(|path| (path, false))
+    } else {
+        find_program(name, ev)
+    };
+    match (program, applet) {
+        (Some((path, _)), Some(applet)) if is_this_binary(&path) => Some(Resolved::Applet(applet)),
+        (Some((path, hashed)), _) => Some(Resolved::Program(path, hashed)),
+        (None, applet) => applet.map(Resolved::Applet)
+    }
+}
+
+/// Whether `path` leads to the running binary, as the links made to run
+/// each applet do
+fn is_this_binary(path: &Path) -> bool {
+    match (fs::canonicalize(path), env::current_exe()) {
+        (Ok(path), Ok(exe)) => path == exe,
+        _ => false
+    }
+}
+
+/// Find the program run by the command `name`, remembering where it was
+/// found in PATH. Also returns whether it was remembered already.
+pub fn find_program(name: &[u8], ev: &mut ExecEnv) -> Option<(PathBuf, bool)> {
+    if name.contains(&b'/') {
+        return Some((PathBuf::from(OsStr::from_bytes(name)), false))
+    }
+
+    // A program which has since been removed is searched for again
+    let name = OsStr::from_bytes(name);
+    if let Some(path) = ev.command_hash.get(name) {
+        if is_executable(path) {
+            return Some((path.clone(), true))
+        }
+    }
+    let path = search_path(name.as_bytes(), ev.get_var(b"PATH").unwrap_or(DEFAULT_PATH))?;
+    ev.command_hash.insert(name.to_os_string(), path.clone());
+    Some((path, false))
+}
+
+/// Find an executable file called `name` in one of the directories of
+/// `path`, with an empty directory meaning the current one
+pub fn search_path(name: &[u8], path: &[u8]) -> Option<PathBuf> {
+    path.split(|c| *c == b':')
+        .map(|dir| Path::new(OsStr::from_bytes(if dir.is_empty() { b"." } else { dir })).join(OsStr::from_bytes(name)))
+        .find(|path| is_executable(path))
+}
+
+fn is_executable(path: &Path) -> bool {
+    match path.metadata() {
+        Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+        Err(_) => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_command_hash() {
+        let dir = std::env::temp_dir().join(format!("rustybox-lookup-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let program = dir.join("prog");
+        fs::write(&program, b"").unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(dir.join("data"), b"").unwrap();
+
+        let mut ev = ExecEnv::new();
+        let mut path = b"/nonexistent::".to_vec();
+        path.extend_from_slice(dir.as_os_str().as_bytes());
+        ev.set_var(b"PATH", path).unwrap();
+        assert_eq!(find_program(b"prog", &mut ev), Some((program.clone(), false)));
+        assert_eq!(find_program(b"prog", &mut ev), Some((program.clone(), true)));
+        assert_eq!(find_program(b"./prog", &mut ev), Some((PathBuf::from("./prog"), false)));
+        assert_eq!(find_program(b"data", &mut ev), None);
+        assert_eq!(find_program(b"rustybox-no-such-program", &mut ev), None);
+
+        ev.set_var(b"PATH", b"/nonexistent".to_vec()).unwrap();
+        assert!(ev.command_hash.is_empty());
+        assert_eq!(find_program(b"prog", &mut ev), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_link_to_binary() {
+        let dir = std::env::temp_dir().join(format!("rustybox-link-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let exe = env::current_exe().unwrap();
+        std::os::unix::fs::symlink(&exe, dir.join("echo")).unwrap();
+        std::os::unix::fs::symlink(&exe, dir.join("basename")).unwrap();
+        fs::write(dir.join("cat"), b"").unwrap();
+        fs::set_permissions(dir.join("cat"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut ev = ExecEnv::new();
+        ev.set_var(b"PATH", dir.as_os_str().as_bytes().to_vec()).unwrap();
+        let lookup = Lookup::default();
+        assert!(matches!(resolve(b"echo", &mut ev, lookup), Some(Resolved::Applet(("echo", _)))));
+        assert!(matches!(resolve(b"basename", &mut ev, lookup), Some(Resolved::Applet(("basename", _)))));
+        if !cfg!(feature = "sh-prefer-applets") {
+            assert!(matches!(resolve(b"cat", &mut ev, lookup), Some(Resolved::Program(..))));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,116 +1,320 @@
 use std::ffi::OsString; 
-use std::io::{Read, Write, stdin, stderr};
+use std::io::{ErrorKind, Write, stderr};
+use std::os::unix::ffi::OsStringExt;
 use std::process::ExitCode;
 use std::error::Error;
-use std::fs::File;
+use std::fs;
 use std::path::Path;
-use std::collections::HashMap;
+use nix::sys::signal::{signal, SigHandler, Signal};
+use nix::unistd::isatty;
 
-use self::ast_nodes::ExecEnv;
+use self::ast_nodes::{ExecEnv, Flow};
+use self::error::{name_syntax_error, SyntaxError};
 use self::parser::Parser;
+use crate::io_util::error_message;
 
 mod parser;
 mod ast_nodes;
 mod expansions;
 mod error;
+mod subshell;
+mod arithmetic;
+mod redirect;
+mod builtins;
+mod jobs;
+mod applets;
+mod glob;
+mod trap;
+mod history;
+mod lineedit;
+mod prompt;
+mod lookup;
 
-pub fn sh_main(_cmd_name: &str, args: Vec<OsString>) -> Result<ExitCode, Box<dyn Error>> {
-    if args.len() == 0 {
-        repl()?;
-        return Ok(ExitCode::FAILURE);
-    }
+/// Where the shell reads its commands from
+enum Source {
+    Command(Vec<u8>),
+    File(OsString),
+    Stdin
+}
 
-    if args.len() > 1 {
-        println!("sh: too many operands");
-        return Ok(ExitCode::FAILURE);
-    }
+pub fn sh_main(_cmd_name: &str, args: Vec<OsString>) -> Result<ExitCode, Box<dyn Error>> {
+    let args: Vec<Vec<u8>> = args.into_iter().map(|a| a.into_vec()).collect();
+    let mut ev = ExecEnv::new();
+    ev.inherit_environment();
+    ev.ignored_signals = trap::ignored_signals();
 
-    let script_path = Path::new(&args[0]);
-    let mut script_file;
-    match File::open(script_path) {
-        Err(why) => {
-            println!("sh: {}: {}", script_path.display(), why);
-            return Ok(ExitCode::FAILURE)
+    // Options come first, with the same syntax as for `set`
+    let mut command = false;
+    let mut read_stdin = false;
+    let mut interactive = false;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg == b"--" || arg == b"-" {
+            i += 1;
+            break;
+        }
+        let on = match arg.first() {
+            Some(b'-') if arg.len() > 1 => true,
+            Some(b'+') if arg.len() > 1 => false,
+            _ => break
+        };
+        for c in arg[1..].iter() {
+            let result = match c {
+                b'c' if on => { command = true; Ok(()) },
+                b's' if on => { read_stdin = true; Ok(()) },
+                b'i' if on => { interactive = true; Ok(()) },
+                b'o' => {
+                    i += 1;
+                    match args.get(i) {
+                        Some(name) => builtins::set_named_option(&mut ev, name, on),
+                        None => Err("-o: option name expected".into())
+                    }
+                },
+                _ => builtins::set_option(&mut ev, *c, on)
+            };
+            if let Err(e) = result {
+                eprintln!("sh: {}", e);
+                return Ok(ExitCode::from(2))
+            }
         }
-        Ok(file) => script_file = file
+        i += 1;
     }
 
-    let mut script_contents = vec![];
-    match script_file.read_to_end(&mut script_contents) {
-        Err(why) => {
-            println!("sh: {}: {}", script_path.display(), why);
-            return Ok(ExitCode::FAILURE)
+    let mut name = None;
+    let mut operands = args[i..].iter().cloned();
+    let source = if command {
+        match operands.next() {
+            Some(command) => {
+                if let Some(name) = operands.next() {
+                    ev.arg0 = name;
+                }
+                name = Some("-c".to_string());
+                ev.command_string = true;
+                Source::Command(command)
+            },
+            None => {
+                eprintln!("sh: -c requires an argument");
+                return Ok(ExitCode::from(2))
+            }
         }
-        Ok(_) => ()
-    }
+    } else if read_stdin || i == args.len() {
+        Source::Stdin
+    } else {
+        let path = operands.next().unwrap();
+        ev.arg0 = path.clone();
+        name = Some(String::from_utf8_lossy(&path).into_owned());
+        Source::File(OsString::from_vec(path))
+    };
+    ev.args = operands.collect();
 
-    let parser = Parser::new();
-    let stuff = parser.script(&script_contents);
-    let (_, cmds) = match stuff {
-        Err(e) => {
-            println!("sh: {}", e);
-            return Ok(ExitCode::FAILURE)
+    let result = match source {
+        Source::Command(command) => run_script(&command, &mut ev),
+        Source::Stdin if interactive || (isatty(0).unwrap_or(false) && isatty(2).unwrap_or(false)) => {
+            return repl(ev)
         },
-        Ok((input, cmds)) => (input, cmds)
+        Source::Stdin => run_stdin(&mut ev),
+        Source::File(path) => match fs::read(&path) {
+            Ok(script) => run_script(&script, &mut ev),
+            Err(why) => {
+                eprintln!("sh: {}: {}", Path::new(&path).display(), error_message(&why));
+                let status = if why.kind() == ErrorKind::NotFound { 127 } else { 126 };
+                return Ok(ExitCode::from(status))
+            }
+        }
     };
-
-    let mut env = ExecEnv {
-        env: HashMap::new()
+    let status = match result.map_err(|e| match &name { Some(name) => name_syntax_error(e, name), None => e }) {
+        Err(e) => {
+            eprintln!("sh: {}", e);
+            2
+        },
+        Ok(status) => status
     };
+    Ok(ExitCode::from(builtins::run_exit_trap(&mut ev, status) as u8))
+}
 
-    for cmd in cmds {
-        cmd.execute(&mut env)?;
+/// Parse and execute a script in the given environment, returning the exit
+/// status of the last command run
+pub fn run_script(input: &[u8], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    // Each command is run before the next is parsed, so a syntax error
+    // stops the script only when it is reached, and the aliases used are
+    // those defined by then
+    let parser = Parser::new();
+    parser.start_script(input);
+    let mut rest = input;
+    let mut status = 0;
+    loop {
+        parser.set_aliases(&ev.aliases);
+        let (next, cmd) = match parser.script_command(input, rest) {
+            Ok(parsed) => parsed,
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => return Err(SyntaxError::new(input, e).into()),
+            Err(e) => return Err(e.into())
+        };
+        if ev.options.verbose {
+            let text = &rest[..(rest.len() - next.len())];
+            stderr().write_all(text)?;
+            if !text.is_empty() && !text.ends_with(b"\n") {
+                eprintln!();
+            }
+        }
+        rest = next;
+        let cmd = match cmd {
+            Some(cmd) => cmd,
+            None => break
+        };
+        if ev.options.noexec {
+            continue;
+        }
+        status = cmd.execute(ev)?;
+        if ev.flow != Flow::Normal {
+            break;
+        }
     }
-
-    Ok(ExitCode::SUCCESS)
+    Ok(status)
 }
 
-fn print_ps1() -> Result<(), Box<dyn Error>> {
-    eprint!("$ ");
-    stderr().flush()?;
-    Ok(())
-}
+/// Run a script read from stdin. Each command is run as soon as it has been
+/// read, and no further, so that the commands can read the input which
+/// follows them.
+fn run_stdin(ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let mut parser = Parser::new();
+    let mut status = 0;
+    let mut lines_read = 0;
+    loop {
+        parser.clear_alias_text();
+        parser.set_aliases(&ev.aliases);
 
-fn repl() -> Result<ExitCode, Box<dyn Error>> {
+        // Read lines until they make up a complete command
+        let mut cmd_str = vec![];
+        let mut end_of_input = false;
+        let result = loop {
+            match lineedit::read_plain_line()? {
+                Some(line) => {
+                    if ev.options.verbose {
+                        stderr().write_all(&line)?;
+                    }
+                    cmd_str.extend_from_slice(&line);
+                },
+                None => {
+                    end_of_input = true;
+                    if !cmd_str.is_empty() && !cmd_str.ends_with(b"\n") {
+                        cmd_str.push(b'\n');
+                    }
+                }
+            }
+            match parser.interactive_command(&cmd_str) {
+                Err(nom::Err::Error(e)) if e.is_incomplete() && !end_of_input => (),
+                result => break result.map(|(_, cmd)| cmd)
+            }
+        };
+
+        let cmd = match result {
+            Ok(Some(cmd)) => cmd,
+            Ok(None) if end_of_input => return Ok(status),
+            Ok(None) => {
+                lines_read += cmd_str.iter().filter(|c| **c == b'\n').count();
+                continue
+            },
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                let mut error = SyntaxError::new(&cmd_str, e);
+                error.line += lines_read;
+                return Err(error.into())
+            },
+            Err(e) => return Err(e.into())
+        };
+        lines_read += cmd_str.iter().filter(|c| **c == b'\n').count();
+
+        if !ev.options.noexec {
+            status = cmd.execute(ev)?;
+        }
+        if ev.flow != Flow::Normal || end_of_input {
+            return Ok(status)
+        }
+    }
+}
 
-    // Create execution environment
-    // TODO: This needs to be inherited
+fn repl(ev: ExecEnv) -> Result<ExitCode, Box<dyn Error>> {
     let mut parser = Parser::new();
+    parser.ev = ev;
+    parser.ev.interactive = true;
+
+    // An interactive shell is not killed by the keyboard signals meant for
+    // the command it is running
+    unsafe {
+        signal(Signal::SIGINT, SigHandler::SigIgn)?;
+        signal(Signal::SIGQUIT, SigHandler::SigIgn)?;
+    }
+    if isatty(0).unwrap_or(false) {
+        if let Err(e) = jobs::enable_job_control(&mut parser.ev) {
+            eprintln!("sh: cannot enable job control: {}", e);
+        }
+    }
+    history::load_history(&mut parser.ev);
 
     loop {
-        // Print cursor
-        // TODO: Need to get this from $PS1
-        // TODO: Needs to use &[u8]
-        print_ps1()?;
-
-        // Read input
-        // TODO: Needs to use &[u8] probably?
-        let mut cmd_str = String::new();
-        let num_bytes = stdin().read_line(&mut cmd_str)?;
-
-        // EOF detected return
-        if num_bytes == 0 {
-            return Ok(ExitCode::SUCCESS)
+        trap::run_pending_traps(&mut parser.ev);
+        if parser.ev.flow == Flow::Exit {
+            let status = parser.ev.last_status;
+            let status = builtins::run_exit_trap(&mut parser.ev, status);
+            return Ok(ExitCode::from(status as u8))
         }
+        jobs::notify_jobs(&mut parser.ev);
+        parser.clear_alias_text();
+        parser.set_aliases(&parser.ev.aliases);
 
-        // Parse command
-        let r = parser.complete_command(&cmd_str.as_bytes());
-        let (_, cmd_ast) = match r {
-            Err(e) => {
-                println!("sh: {}", e);
-                println!("input: {:?}", cmd_str);
-                continue
-            },
-            Ok((input, cmd)) => (input, cmd)
+        // Read input, continuing onto more lines while the command is
+        // incomplete
+        let ps1 = prompt::expand_prompt(&mut parser.ev, b"PS1");
+        let mut cmd_str = match lineedit::read_line(&ps1, &parser.ev)? {
+            Some(line) => line,
+            None => {
+                // EOF detected return
+                let status = parser.ev.last_status;
+                let status = builtins::run_exit_trap(&mut parser.ev, status);
+                return Ok(ExitCode::from(status as u8))
+            }
         };
+        let result = loop {
+            match parser.interactive_command(&cmd_str) {
+                Err(nom::Err::Error(e)) if e.is_incomplete() => (),
+                result => break result.map(|(_, cmd)| cmd)
+            }
+            let ps2 = prompt::expand_prompt(&mut parser.ev, b"PS2");
+            match lineedit::read_line(&ps2, &parser.ev)? {
+                Some(line) => cmd_str.extend_from_slice(&line),
+                None => break parser.interactive_command(&cmd_str).map(|(_, cmd)| cmd)
+            }
+        };
+        if parser.ev.options.verbose {
+            stderr().write_all(&cmd_str)?;
+        }
+        history::add_history(&mut parser.ev, &cmd_str);
 
-        match cmd_ast.execute(&mut parser.ev) {
-            Err(e) => {
-                println!("sh: {}", e);
-                println!("input: {:?}", cmd_str);
+        let cmd_ast = match result {
+            Ok(Some(cmd)) => cmd,
+            Ok(None) => continue,
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                eprintln!("sh: {}", e);
+                parser.ev.last_status = 2;
                 continue
             },
-            _ => ()
+            Err(e) => {
+                eprintln!("sh: {}", e);
+                parser.ev.last_status = 2;
+                continue
+            }
         };
+
+        if let Err(e) = cmd_ast.execute(&mut parser.ev) {
+            eprintln!("sh: {}", e);
+            parser.ev.last_status = 2;
+            continue
+        }
+
+        if parser.ev.flow == Flow::Exit {
+            let status = parser.ev.last_status;
+            let status = builtins::run_exit_trap(&mut parser.ev, status);
+            return Ok(ExitCode::from(status as u8))
+        }
     }
 }
\ No newline at end of file
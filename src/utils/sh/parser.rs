@@ -1,10 +1,14 @@
 use std::{ffi::OsString, os::unix::prelude::OsStringExt}; 
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::slice;
 
 use nom::{
     bytes::complete::take_until1,
+    branch::alt,
     combinator::{opt, fail},
-    multi::many0,
+    multi::{many0, many1},
     error::ErrorKind
 };
 
@@ -12,25 +16,25 @@ use super::ast_nodes::*;
 use super::error::*;
 
 
+type BoxedParser<'p, 'a, O> = Box<dyn Fn(&'a [u8]) -> RbResult<&'a [u8], O> + 'p>;
+
 #[derive(Debug, PartialEq)]
 enum TokenType<'a> {
-    Word(Word<'a>),
+    Word(&'a [u8]),
+    IoNumber(IoNumber),
     Newline,
     LogicalOp(LogicalOp),
     RedirectionOp(RedirectionOp),
     IoHereOp(IoHereOp),
     SeperatorOp(SeperatorOp),
     Pipe,
+    LParen,
+    RParen,
+    DSemi,
 }
 
-fn new_word(s: &[u8]) -> TokenType {
-    if s == b"\n" {
-        new_newline()
-    } else {
-        TokenType::Word(
-            Word::new(s)
-        )
-    }
+fn new_word(s: &[u8]) -> TokenType<'_> {
+    TokenType::Word(s)
 }
 
 fn new_op<'a>(tok: &'a [u8]) -> Option<TokenType<'a>> {
@@ -49,6 +53,9 @@ fn new_op<'a>(tok: &'a [u8]) -> Option<TokenType<'a>> {
         b"|" => Some(TokenType::Pipe),
         b"&" => Some(TokenType::SeperatorOp(SeperatorOp::Async)),
         b";" => Some(TokenType::SeperatorOp(SeperatorOp::Seq)),
+        b";;" => Some(TokenType::DSemi),
+        b"(" => Some(TokenType::LParen),
+        b")" => Some(TokenType::RParen),
         _ => None
     }
 }
@@ -58,11 +65,7 @@ fn new_newline<'a>() -> TokenType<'a> {
 }
 
 fn is_op_initial(c: u8) -> bool {
-    b"&|;<>".contains(&c)
-}
-
-fn is_op_character(c: u8) -> bool {
-    b"&|;<>-".contains(&c)
+    b"&|;<>()".contains(&c)
 }
 
 fn is_blank(c: u8) -> bool {
@@ -77,152 +80,546 @@ fn is_comment(c: u8) -> bool {
     c == b'#'
 }
 
-fn after_comment(s: &[u8]) -> &[u8] {
+fn is_line_continuation(s: &[u8]) -> bool {
+    s.starts_with(b"\\\n")
+}
+
+/// Words which are reserved when they appear as the name of a command. `in`
+/// is only reserved within `for` and `case` so is not included.
+const RESERVED_WORDS: &[&[u8]] = &[
+    b"!", b"{", b"}", b"case", b"do", b"done", b"elif", b"else", b"esac",
+    b"fi", b"for", b"if", b"then", b"until", b"while"
+];
+
+pub fn is_reserved_word(s: &[u8]) -> bool {
+    RESERVED_WORDS.contains(&s)
+}
+
+/// Whether `s` can be the name of an alias, which is a word with no quoting
+/// or expansions
+pub fn is_alias_name(s: &[u8]) -> bool {
+    !s.is_empty() && s.iter().all(|c| {
+        !b"\\'\"`$=/#".contains(c) && !is_blank(*c) && !is_newline(*c) && !is_op_initial(*c)
+    })
+}
 
-    // Nothing after comment
-    if s == b"#" {
-        return b"";
+pub fn is_name(s: &[u8]) -> bool {
+    match s.first() {
+        Some(c) if c.is_ascii_alphabetic() || *c == b'_' => (),
+        _ => return false
     }
+    s.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+}
 
-    for (i, c) in s.into_iter().enumerate() {
+fn after_comment(s: &[u8]) -> &[u8] {
+    for (i, c) in s.iter().enumerate() {
         if is_newline(*c) {
             return &s[i..];
         }
     }
 
-    return b"";
+    &s[s.len()..]
+}
+
+/// The address just past the end of `input`
+fn end_address(input: &[u8]) -> usize {
+    input.as_ptr() as usize + input.len()
+}
+
+// The *_len functions below return the length of the quoted or expansion
+// construct at the start of `s`, including its opening and closing
+// characters, or None if the construct is not terminated.
+
+fn single_quoted_len(s: &[u8]) -> Option<usize> {
+    let n = s[1..].iter().position(|c| *c == b'\'')?;
+    Some(n + 2)
+}
+
+fn double_quoted_len(s: &[u8]) -> Option<usize> {
+    let mut i = 1;
+    while i < s.len() {
+        match s[i] {
+            b'"' => return Some(i + 1),
+            b'\\' => i += 2,
+            b'`' => i += backquoted_len(&s[i..])?,
+            b'$' => i += dollar_len(&s[i..])?,
+            _ => i += 1
+        }
+    }
+    None
+}
+
+pub fn backquoted_len(s: &[u8]) -> Option<usize> {
+    let mut i = 1;
+    while i < s.len() {
+        match s[i] {
+            b'`' => return Some(i + 1),
+            b'\\' => i += 2,
+            _ => i += 1
+        }
+    }
+    None
+}
+
+pub fn dollar_len(s: &[u8]) -> Option<usize> {
+    match s.get(1) {
+        Some(b'(') => Some(1 + bracketed_len(&s[1..], b'(', b')')?),
+        Some(b'{') => Some(1 + bracketed_len(&s[1..], b'{', b'}')?),
+        Some(c) if b"@*#?-$!".contains(c) => Some(2),
+        _ => Some(1)
+    }
 }
 
-#[allow(dead_code)]
-fn count_until_quote(s: &[u8]) -> Option<usize> {
-    for i in 0..s.len() {
+pub fn bracketed_len(s: &[u8], open: u8, close: u8) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < s.len() {
         match s[i] {
-            b'\'' => return Some(i),
-            _ => () 
+            c if c == open => {
+                depth += 1;
+                i += 1;
+            },
+            c if c == close => {
+                depth -= 1;
+                i += 1;
+                if depth == 0 {
+                    return Some(i)
+                }
+            },
+            b'\\' => i += 2,
+            b'\'' => i += single_quoted_len(&s[i..])?,
+            b'"' => i += double_quoted_len(&s[i..])?,
+            b'`' => i += backquoted_len(&s[i..])?,
+            b'$' => i += dollar_len(&s[i..])?,
+            _ => i += 1
         }
     }
     None
 }
 
+/// Remove the quoting from a here-document delimiter, also returning
+/// whether any part of it was quoted
+fn here_doc_delimiter(word: &[u8]) -> (Vec<u8>, bool) {
+    let mut delimiter = vec![];
+    let mut quoted = false;
+    let mut in_double_quotes = false;
+    let mut i = 0;
+    while i < word.len() {
+        match word[i] {
+            b'\\' if i + 1 < word.len() => {
+                if in_double_quotes && !b"$`\"\\".contains(&word[i + 1]) {
+                    delimiter.push(b'\\');
+                }
+                delimiter.push(word[i + 1]);
+                quoted = true;
+                i += 2;
+                continue;
+            },
+            b'\'' if !in_double_quotes => {
+                let len = single_quoted_len(&word[i..]).unwrap_or(word.len() - i);
+                delimiter.extend_from_slice(&word[(i + 1)..usize::min(i + len - 1, word.len())]);
+                quoted = true;
+                i += len;
+                continue;
+            },
+            b'"' => {
+                in_double_quotes = !in_double_quotes;
+                quoted = true;
+            },
+            c => delimiter.push(c)
+        }
+        i += 1;
+    }
+    (delimiter, quoted)
+}
+
+/// Read the body of a here-document from the start of `input`, returning the
+/// input following the delimiter line and whether the delimiter was found
+fn read_here_doc<'a>(input: &'a [u8], here_doc: &PendingHereDoc) -> (&'a [u8], Vec<u8>, bool) {
+    let mut body = vec![];
+    let mut rest = input;
+    while !rest.is_empty() {
+        let line_len = match rest.iter().position(|c| is_newline(*c)) {
+            Some(n) => n + 1,
+            None => rest.len()
+        };
+        let mut line = &rest[..line_len];
+        rest = &rest[line_len..];
+
+        if here_doc.strip_tabs {
+            while line.first() == Some(&b'\t') {
+                line = &line[1..];
+            }
+        }
+        if line.strip_suffix(b"\n").unwrap_or(line) == here_doc.delimiter.as_slice() {
+            return (rest, body, true)
+        }
+        body.extend_from_slice(line);
+    }
+    (rest, body, false)
+}
+
+/// A here-document operator seen on the current line whose body has not
+/// been read yet
+struct PendingHereDoc {
+    key: usize,
+    delimiter: Vec<u8>,
+    strip_tabs: bool
+}
+
+/// The bodies of the here-documents in the input being parsed. The input is
+/// scanned for them before parsing as a body is only found after the newline
+/// ending the command that uses it, and the tokenizer must then step over it.
+#[derive(Default)]
+struct HereDocs {
+    /// Bodies keyed by the address of the delimiter word
+    bodies: HashMap<usize, Vec<u8>>,
+    /// Bytes to skip keyed by the address just after a newline token
+    skips: HashMap<usize, usize>,
+    /// Whether the input ended before the delimiter of a here-document
+    unterminated: bool
+}
+
+/// Text substituted for an alias. The tokenizer reads it in place of the
+/// alias name, then carries on with the input following the name.
+struct AliasText {
+    /// The value of the alias followed by a byte which is not part of it, so
+    /// that the address of the end of the value belongs to no other input
+    text: Box<[u8]>,
+    /// The address and length of the input starting with the alias name
+    source: (*const u8, usize),
+    name_len: usize,
+    /// The aliases being substituted, which aren't substituted again within
+    /// this text
+    names: Vec<Vec<u8>>
+}
+
 pub struct Parser {
-    pub ev: ExecEnv
+    pub ev: ExecEnv,
+    here_docs: RefCell<HereDocs>,
+    /// Set when the tokenizer runs out of input, at the end of the input or
+    /// in an unterminated quote or expansion
+    out_of_input: Cell<bool>,
+    /// The address of the furthest token read. No token after the point
+    /// where a parse fails is read, so this locates the error.
+    furthest: Cell<usize>,
+    aliases: RefCell<Rc<HashMap<Vec<u8>, Vec<u8>>>>,
+    /// The text substituted for aliases, keyed by the address of its end
+    alias_text: RefCell<HashMap<usize, AliasText>>,
+    /// The addresses of words following an alias whose value ends with a
+    /// blank, which are also checked for aliases
+    after_blank: RefCell<HashSet<usize>>
 }
 
 impl Parser {
     pub fn new() -> Parser {
         Parser {
-            ev: ExecEnv {
-                env: HashMap::new()
-            }
+            ev: ExecEnv::new(),
+            here_docs: RefCell::new(HereDocs::default()),
+            out_of_input: Cell::new(false),
+            furthest: Cell::new(0),
+            aliases: RefCell::new(Rc::new(HashMap::new())),
+            alias_text: RefCell::new(HashMap::new()),
+            after_blank: RefCell::new(HashSet::new())
         }
     }
 
-    fn raw_token<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], TokenType<'a>> {
-        // Initialise with maximum size of token
-        let mut is_operator = false;
-        let mut tok_start = 0;
-        let mut tok_len = 0;
-        let mut active_tok = false;
-        //let mut active_comment = false;
-        for (i, c) in input.into_iter().enumerate() {
-
-            active_tok = tok_len > 0;
-
-            // Tokenizer rule 2 - operators
-            if is_operator {
-                // Tokenizer rule 3 - delimit operator if next character is not part of op
-                if is_op_character(*c) {
-                    tok_len += 1;
-                    continue;
-                } else {
-                    let tok_end = tok_len + tok_start;
-                    let tok = &input[tok_start..tok_end];
-                    if let Some(tt) = new_op(tok) {
-                        return Ok((&input[i..], tt))
-                    } else {
-                        return fail(input)
+    /// Substitute `aliases` for the command names parsed from now on
+    pub fn set_aliases(&self, aliases: &Rc<HashMap<Vec<u8>, Vec<u8>>>) {
+        *self.aliases.borrow_mut() = aliases.clone();
+    }
+
+    /// Free the text substituted for aliases by earlier parses, none of
+    /// whose input can still be in use once the parser is borrowed mutably
+    pub fn clear_alias_text(&mut self) {
+        self.alias_text.get_mut().clear();
+    }
+
+    /// Find the here-documents in `input` and read their bodies
+    fn scan_here_docs<'a>(&'a self, input: &'a [u8]) {
+        *self.here_docs.borrow_mut() = HereDocs::default();
+
+        let mut pending: Vec<PendingHereDoc> = vec![];
+        let mut input = input;
+        while let Ok((rest, tok)) = self.raw_token(input) {
+            input = rest;
+            match tok {
+                TokenType::IoHereOp(op) => {
+                    if let Ok((rest, TokenType::Word(word))) = self.raw_token(input) {
+                        let (delimiter, _) = here_doc_delimiter(word);
+                        pending.push(PendingHereDoc {
+                            key: word.as_ptr() as usize,
+                            delimiter,
+                            strip_tabs: op == IoHereOp::DLessDash
+                        });
+                        input = rest;
                     }
-                }
+                },
+                TokenType::Newline if !pending.is_empty() => {
+                    let start = input.as_ptr() as usize;
+                    for here_doc in pending.drain(..) {
+                        let (rest, body, found) = read_here_doc(input, &here_doc);
+                        let mut here_docs = self.here_docs.borrow_mut();
+                        here_docs.bodies.insert(here_doc.key, body);
+                        here_docs.unterminated |= !found;
+                        input = rest;
+                    }
+                    let len = input.as_ptr() as usize - start;
+                    self.here_docs.borrow_mut().skips.insert(start, len);
+                },
+                _ => ()
             }
+        }
 
-            // TODO: Tokenizer rule 4 - quoting
-            // if *c == b'\'' {
-            //     if let Some(count) = count_until_quote(&input[i..]) {
-            //         tok_len += count + 1;
-            //         continue;
-            //     } else {
-            //         return fail(input);
-            //     }
-            // }
-
-            // TODO: Tokenizer rule 5 - expansions
-
-            // Tokenizer rule 6 - start of operator
-            if is_op_initial(*c) {
-                is_operator = true;
-                if active_tok {
-                    let tok_end = tok_len + tok_start;
-                    return Ok((&input[i..], new_word(&input[tok_start..tok_end])))
-                } else {
-                    tok_len += 1;
-                    continue;
-                }
+        // Here-documents with no body before the end of input are empty
+        for here_doc in pending {
+            let mut here_docs = self.here_docs.borrow_mut();
+            here_docs.bodies.insert(here_doc.key, vec![]);
+            here_docs.unterminated = true;
+        }
+    }
+
+    /// Step over any here-document bodies following a newline token
+    fn skip_here_docs<'a>(&'a self, rest: &'a [u8]) -> &'a [u8] {
+        match self.here_docs.borrow().skips.get(&(rest.as_ptr() as usize)) {
+            Some(len) => &rest[*len..],
+            None => rest
+        }
+    }
+
+    /// The input starting with the alias name for which the alias text
+    /// ending `input` was substituted, and the length of the name
+    fn alias_source<'a>(&'a self, input: &'a [u8]) -> Option<(&'a [u8], usize)> {
+        let alias_text = self.alias_text.borrow();
+        let alias = alias_text.get(&end_address(input))?;
+        // SAFETY: the alias text was substituted while parsing this input,
+        // whose lifetime bounds that of any input read from the text
+        let source = unsafe { slice::from_raw_parts(alias.source.0, alias.source.1) };
+        Some((source, alias.name_len))
+    }
+
+    /// The input following an alias name, where the tokenizer carries on
+    /// when `input` is at the end of the alias text
+    fn after_alias<'a>(&'a self, input: &'a [u8]) -> Option<&'a [u8]> {
+        self.alias_source(input).map(|(source, name_len)| &source[name_len..])
+    }
+
+    /// `input`, or if it is in alias text, the input starting with the name
+    /// of the outermost alias substituted. This relates positions in alias
+    /// text to the input being parsed.
+    fn outer_input<'a>(&'a self, input: &'a [u8]) -> &'a [u8] {
+        let mut input = input;
+        while let Some((source, _)) = self.alias_source(input) {
+            input = source;
+        }
+        input
+    }
+
+    /// As `outer_input`, but with the input following the alias name
+    fn outer_rest<'a>(&'a self, input: &'a [u8]) -> &'a [u8] {
+        let mut input = input;
+        while let Some(rest) = self.after_alias(input) {
+            input = rest;
+        }
+        input
+    }
+
+    /// The text of the input from `start` up to `end`. If alias text lies
+    /// between them, the text is that of the input with the alias names.
+    fn source_text<'a>(&'a self, start: &'a [u8], end: &'a [u8]) -> &'a [u8] {
+        let (start, end) = if end_address(start) == end_address(end) {
+            (start, end)
+        } else {
+            (self.outer_input(start), self.outer_rest(end))
+        };
+        &start[..start.len().saturating_sub(end.len())]
+    }
+
+    /// The word at the start of `input`, if there is one, read without
+    /// moving where a failed parse is found to fail
+    fn peek_word<'a>(&'a self, input: &'a [u8]) -> Option<&'a [u8]> {
+        let (out_of_input, furthest) = (self.out_of_input.get(), self.furthest.get());
+        let word = self.word(input).ok().map(|(_, word)| word);
+        self.out_of_input.set(out_of_input);
+        self.furthest.set(furthest);
+        word
+    }
+
+    /// Substitute an alias for the word at the start of `input` if it is
+    /// one to substitute, returning the input to parse in its place. Command
+    /// names are substituted, and words following an alias whose value ends
+    /// with a blank. The first word of the alias text takes the place of the
+    /// alias name so is checked in turn, but no alias is substituted within
+    /// its own text.
+    fn substitute_alias<'a>(&'a self, input: &'a [u8], command_name: bool) -> &'a [u8] {
+        let aliases = self.aliases.borrow().clone();
+        if aliases.is_empty() {
+            return input
+        }
+
+        let mut input = self.token_start(input);
+        let mut eligible = command_name;
+        loop {
+            let word = match self.peek_word(input) {
+                Some(word) => word,
+                None => return input
+            };
+            eligible |= self.after_blank.borrow().contains(&(word.as_ptr() as usize));
+            if !eligible || is_reserved_word(word) || !is_alias_name(word) {
+                return input
+            }
+            let value = match aliases.get(word) {
+                Some(value) => value,
+                None => return input
+            };
+            let mut names = self.alias_text.borrow().get(&end_address(input))
+                .map(|alias| alias.names.clone())
+                .unwrap_or_default();
+            if names.iter().any(|name| name == word) {
+                return input
             }
+            names.push(word.to_vec());
 
-            // Tokenizer rule 7 - spaces
-            if is_newline(*c) {
-                if active_tok {
-                    let tok_end = tok_len + tok_start;
-                    return Ok((&input[i..], new_word(&input[tok_start..tok_end])))
-                } else {
-                    return Ok((&input[1..], new_newline()))
+            let rest = &input[word.len()..];
+            if value.last().is_some_and(|c| is_blank(*c)) {
+                if let Some(next) = self.peek_word(rest) {
+                    self.after_blank.borrow_mut().insert(next.as_ptr() as usize);
                 }
             }
+            if value.is_empty() {
+                input = self.token_start(rest);
+                continue;
+            }
 
-            // Tokenizer rule 8 - words
-            if is_blank(*c) {
-                if tok_len > 0 {
-                    let tok_end = tok_len + tok_start;
-                    return Ok((&input[(i+1)..], new_word(&input[tok_start..tok_end])))
-                } else {
-                    tok_start += 1;
-                    continue;
-                }
-            } 
-
-            // Tokenizer rule 9 - comments
-            if is_comment(*c) {
-                let rest = after_comment(&input[i..]);
-                if active_tok {
-                    let tok_end = tok_len + tok_start;
-                    return Ok((rest, new_word(&input[tok_start..tok_end])))
-                } else {
-                    return Ok((&rest[1..], new_newline()))
-                }
+            let mut text = value.clone();
+            text.push(0);
+            let text = text.into_boxed_slice();
+            let mut alias_text = self.alias_text.borrow_mut();
+            let alias = alias_text.entry(text.as_ptr() as usize + value.len()).or_insert(AliasText {
+                text,
+                source: (input.as_ptr(), input.len()),
+                name_len: word.len(),
+                names
+            });
+            let text = &alias.text[..value.len()];
+            // SAFETY: alias text is only freed by `clear_alias_text`, which
+            // can't be called while the parser is borrowed
+            let text = unsafe { slice::from_raw_parts(text.as_ptr(), text.len()) };
+            drop(alias_text);
+            input = self.token_start(text);
+        }
+    }
+
+    /// Skip the blanks and line continuations preceding a token, carrying on
+    /// after the alias name at the end of alias text
+    fn token_start<'a>(&'a self, input: &'a [u8]) -> &'a [u8] {
+        let mut input = input;
+        loop {
+            if input.first().is_some_and(|c| is_blank(*c)) {
+                input = &input[1..];
+            } else if is_line_continuation(input) {
+                input = &input[2..];
+            } else if let (true, Some(rest)) = (input.is_empty(), self.after_alias(input)) {
+                input = rest;
+            } else {
+                return input
             }
+        }
+    }
 
-            // Tokenizer rule 10 - start of word (I think)
-            if tok_len > 0 {
-                tok_len += 1;
-                continue;
+    fn raw_token<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], TokenType<'a>> {
+        let input = self.token_start(input);
+        let start = self.outer_input(input).as_ptr() as usize;
+        self.furthest.set(self.furthest.get().max(start));
+
+        // Tokenizer rule 1 - end of input
+        if input.is_empty() {
+            self.out_of_input.set(true);
+            return fail(input);
+        }
+
+        let c = input[0];
+
+        // Tokenizer rule 6 - start of operator
+        if is_op_initial(c) {
+            // Tokenizer rules 2 and 3 - extend the operator for as long as
+            // the extended sequence is still an operator
+            let mut tok_end = 1;
+            while tok_end < input.len() && new_op(&input[..=tok_end]).is_some() {
+                tok_end += 1;
             }
+            return match new_op(&input[..tok_end]) {
+                Some(tt) => Ok((&input[tok_end..], tt)),
+                None => fail(input)
+            }
+        }
 
-            // Tokenizer rule 11 - rule 11??
-            tok_len += 1;
+        // Tokenizer rule 7 - newlines
+        if is_newline(c) {
+            let rest = self.skip_here_docs(&input[1..]);
+            return Ok((rest, new_newline()))
         }
 
-        // Tokenizer rule 1
-        let tok_end = tok_len + tok_start;
-        if active_tok {
-            Ok((b"", new_word(&input[tok_start..tok_end])))
-        } else {
-            Err(nom::Err::Error(
-                RbError::new(ErrorKind::Fail)
-            ))
+        // Tokenizer rule 9 - comments
+        if is_comment(c) {
+            let rest = after_comment(input);
+            if rest.is_empty() {
+                // A comment in alias text only runs to the end of the text
+                if self.after_alias(rest).is_some() {
+                    return self.raw_token(rest)
+                }
+                return Ok((rest, new_newline()))
+            }
+            return Ok((self.skip_here_docs(&rest[1..]), new_newline()))
         }
+
+        // Tokenizer rule 10 - words
+        let mut tok_end = 0;
+        while tok_end < input.len() {
+            let c = input[tok_end];
+
+            // Tokenizer rule 4 - quoting
+            // Tokenizer rule 5 - expansions
+            let len = match c {
+                b'\\' => Some(2),
+                b'\'' => single_quoted_len(&input[tok_end..]),
+                b'"' => double_quoted_len(&input[tok_end..]),
+                b'`' => backquoted_len(&input[tok_end..]),
+                b'$' => dollar_len(&input[tok_end..]),
+                _ if is_blank(c) => {
+                    let word = new_word(&input[..tok_end]);
+                    return Ok((&input[(tok_end + 1)..], word))
+                },
+                // Tokenizer rule 2 - a number directly before < or > is an IO_NUMBER
+                b'<' | b'>' if tok_end > 0 && input[..tok_end].iter().all(u8::is_ascii_digit) => {
+                    let digits = std::str::from_utf8(&input[..tok_end]).unwrap();
+                    match digits.parse() {
+                        Ok(n) => return Ok((&input[tok_end..], TokenType::IoNumber(n))),
+                        Err(_) => break
+                    }
+                },
+                _ if is_newline(c) || is_op_initial(c) => break,
+                _ if is_comment(c) => {
+                    let rest = after_comment(&input[tok_end..]);
+                    return Ok((rest, new_word(&input[..tok_end])))
+                },
+                _ => Some(1)
+            };
+
+            match len {
+                Some(len) => tok_end = usize::min(tok_end + len, input.len()),
+                None => {
+                    self.out_of_input.set(true);
+                    return fail(input)
+                }
+            }
+        }
+
+        Ok((&input[tok_end..], new_word(&input[..tok_end])))
     }
 
-    fn word<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], Word<'a>> {
+    /// A word token, as the text of the word in the input
+    fn word<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], &'a [u8]> {
         let (rest, tok) = self.raw_token(input)?;
         match tok {
             TokenType::Word(tok) => Ok((rest, tok)),
@@ -232,7 +629,7 @@ impl Parser {
         }
     }
 
-    fn logical_op<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], LogicalOp> {
+    fn logical_op<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], LogicalOp> {
         if let (rest, TokenType::LogicalOp(op)) = self.raw_token(input)? {
             Ok((rest, op))
         } else {
@@ -240,8 +637,7 @@ impl Parser {
         }
     }
 
-    #[allow(dead_code)]
-    fn redirection_op<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], RedirectionOp> {
+    fn redirection_op<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], RedirectionOp> {
         if let (rest, TokenType::RedirectionOp(op)) = self.raw_token(input)? {
             Ok((rest, op))
         } else {
@@ -249,8 +645,7 @@ impl Parser {
         }
     }
 
-    #[allow(dead_code)]
-    fn io_here_op<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], IoHereOp> {
+    fn io_here_op<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], IoHereOp> {
         if let (rest, TokenType::IoHereOp(op)) = self.raw_token(input)? {
             Ok((rest, op))
         } else {
@@ -258,7 +653,15 @@ impl Parser {
         }
     }
 
-    fn pipe_op<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], ()> {
+    fn io_number<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], IoNumber> {
+        if let (rest, TokenType::IoNumber(n)) = self.raw_token(input)? {
+            Ok((rest, n))
+        } else {
+            fail(input)
+        }
+    }
+
+    fn pipe_op<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], ()> {
         if let (rest, TokenType::Pipe) = self.raw_token(input)? {
             Ok((rest, ()))
         } else {
@@ -266,8 +669,7 @@ impl Parser {
         }
     }
 
-    #[allow(dead_code)]
-    fn seperator_op<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], SeperatorOp> {
+    fn seperator_op<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], SeperatorOp> {
         if let (rest, TokenType::SeperatorOp(op)) = self.raw_token(input)? {
             Ok((rest, op))
         } else {
@@ -275,7 +677,7 @@ impl Parser {
         }
     }
 
-    fn newline<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], ()> {
+    fn newline<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], ()> {
         if let (rest, TokenType::Newline) = self.raw_token(input)? {
             Ok((rest, ()))
         } else {
@@ -283,22 +685,169 @@ impl Parser {
         }
     }
 
+    fn lparen<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], ()> {
+        if let (rest, TokenType::LParen) = self.raw_token(input)? {
+            Ok((rest, ()))
+        } else {
+            fail(input)
+        }
+    }
 
-    pub fn script<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], Script<'a>> {
-        many0(|input| self.complete_command(input))(input)
+    fn rparen<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], ()> {
+        if let (rest, TokenType::RParen) = self.raw_token(input)? {
+            Ok((rest, ()))
+        } else {
+            fail(input)
+        }
     }
 
-    pub fn complete_command<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], CompleteCommand<'a>> {
-        let (input, expr) = self.expression(input)?;
-        let (input, _) = self.newline(input)?;
+    fn dsemi<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], ()> {
+        if let (rest, TokenType::DSemi) = self.raw_token(input)? {
+            Ok((rest, ()))
+        } else {
+            fail(input)
+        }
+    }
+
+    fn linebreak<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], ()> {
+        let (input, _) = many0(|input| self.newline(input))(input)?;
+        Ok((input, ()))
+    }
+
+    fn newline_list<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], ()> {
+        let (input, _) = many1(|input| self.newline(input))(input)?;
+        Ok((input, ()))
+    }
+
+    fn separator<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], SeperatorOp> {
+        if let Ok((input, op)) = self.seperator_op(input) {
+            let (input, _) = self.linebreak(input)?;
+            return Ok((input, op))
+        }
+        let (input, _) = self.newline_list(input)?;
+        Ok((input, SeperatorOp::Seq))
+    }
+
+    fn sequential_sep<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], ()> {
+        if let Ok((input, SeperatorOp::Seq)) = self.seperator_op(input) {
+            return self.linebreak(input)
+        }
+        self.newline_list(input)
+    }
+
+
+    fn end_of_input<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], ()> {
+        if !input.iter().all(|c| is_blank(*c)) {
+            return fail(input)
+        }
+        let input = &input[input.len()..];
+        match self.after_alias(input) {
+            Some(rest) => self.end_of_input(rest),
+            None => Ok((input, ()))
+        }
+    }
+
+    /// Prepare to parse `script` one command at a time with
+    /// `script_command`
+    pub fn start_script<'a>(&'a self, script: &'a [u8]) {
+        self.scan_here_docs(script);
+    }
+
+    /// Parse the command of `script` starting at `input`, so that it can be
+    /// run before the rest of the script is parsed. Returns None once only
+    /// blank lines and comments remain. A syntax error is located by its
+    /// offset in `script`.
+    pub fn script_command<'a>(&'a self, script: &[u8], input: &'a [u8]) -> RbResult<&'a [u8], Option<CompleteCommand>> {
+        let (rest, _) = self.linebreak(input)?;
+        if self.end_of_input(rest).is_ok() {
+            return Ok((b"", None))
+        }
+
+        self.start_parse();
+        match self.complete_command(rest) {
+            Ok((rest, cmd)) => Ok((rest, Some(cmd))),
+            Err(_) => Err(self.located_error(script))
+        }
+    }
+
+    fn start_parse(&self) {
+        self.out_of_input.set(false);
+        self.furthest.set(0);
+        self.after_blank.borrow_mut().clear();
+    }
+
+    /// The error for a failed parse of `input`, found at the furthest token
+    /// read or at the end of the input if the tokenizer ran out of it
+    fn located_error<'a>(&'a self, input: &'a [u8]) -> nom::Err<RbError> {
+        let end = input.strip_suffix(b"\n").unwrap_or(input).len();
+        if self.out_of_input.get() {
+            return nom::Err::Error(RbError::incomplete(end))
+        }
+        let offset = self.furthest.get().saturating_sub(input.as_ptr() as usize).min(end);
+        let token = match self.raw_token(&input[offset..]) {
+            Ok((_, TokenType::Word(word))) => word,
+            Ok((_, TokenType::Newline)) => b"newline".as_ref(),
+            Ok((rest, _)) => &input[offset..(input.len() - rest.len())],
+            Err(_) => return nom::Err::Error(RbError::incomplete(end))
+        };
+        nom::Err::Error(RbError::unexpected(token, offset))
+    }
+
+    pub fn complete_command<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], CompleteCommand> {
+        if self.end_of_input(input).is_ok() {
+            return fail(input);
+        }
+
+        let (input, list) = self.list(input, false)?;
+        let (input, _) = alt((
+            |input| self.newline(input),
+            |input| self.end_of_input(input)
+        ))(input)?;
 
         Ok((input, CompleteCommand {
-            expression: expr,
-            subshell: false
+            list
         }))
     }
 
-    fn expression<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], Expression<'a>> {
+    /// Parse a command typed at an interactive shell, which is ended by a
+    /// newline. If the input ends before the command does, including in the
+    /// body of a here-document, the error is `RbErrorKind::Incomplete` and
+    /// the shell should read another line. Blank lines and comments parse as
+    /// no command.
+    pub fn interactive_command<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], Option<CompleteCommand>> {
+        self.scan_here_docs(input);
+        let (rest, _) = self.linebreak(input)?;
+        if self.end_of_input(rest).is_ok() {
+            return Ok((b"", None))
+        }
+
+        self.start_parse();
+        let result = self.list(rest, false).and_then(|(rest, mut list)| {
+            let (mut rest, _) = self.newline(rest)?;
+            // Alias text may hold more lines, whose commands are run as if
+            // they were part of the same list
+            loop {
+                let (more, _) = self.linebreak(rest)?;
+                if self.end_of_input(more).is_ok() {
+                    break;
+                }
+                let (more, next) = self.list(more, false)?;
+                let (more, _) = self.newline(more)?;
+                list.items.extend(next.items);
+                rest = more;
+            }
+            Ok((rest, CompleteCommand {
+                list
+            }))
+        });
+        match result {
+            Err(_) => Err(self.located_error(input)),
+            Ok(_) if self.here_docs.borrow().unterminated => Err(nom::Err::Error(RbError::incomplete(input.len()))),
+            Ok((rest, cmd)) => Ok((rest, Some(cmd)))
+        }
+    }
+
+    fn expression<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], Expression> {
         let (input, pipeline) = self.pipeline_sequence(input)?;
         let (input, logical_seq_list) = self.logical_sequence(input)?;
 
@@ -308,41 +857,121 @@ impl Parser {
         }))
     }
 
-    fn logical_sequence<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], Vec<LogicalSeqElem<'a>>> {
+    fn logical_sequence<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], Vec<LogicalSeqElem>> {
         many0(|input| self.logical_segment(input))(input)
     }
 
-    fn logical_segment<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], LogicalSeqElem<'a>> {
+    fn logical_segment<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], LogicalSeqElem> {
         let (input, op) = self.logical_op(input)?;
+        let (input, _) = self.linebreak(input)?;
         let (input, pipeline) = self.pipeline_sequence(input)?;
 
         Ok((input, LogicalSeqElem {
-            op: op,
-            pipeline: pipeline
+            op,
+            pipeline
         }))
     }
 
-    fn simple_command<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], SimpleCommand<'a>> {
-        let (input, assignment_words) = many0(|input| self.assignment_word(input))(input)?;
-        let (input, words) = many0(|input| self.word(input))(input)?;
+    fn simple_command<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], SimpleCommand> {
+        let mut assignment_words = vec![];
+        let mut words = vec![];
+        let mut redirects = vec![];
+
+        // cmd_prefix
+        let mut input = input;
+        loop {
+            if let Ok((rest, redirect)) = self.io_redirect(input) {
+                redirects.push(redirect);
+                input = rest;
+            } else if let Ok((rest, assignment)) = self.assignment_word(input) {
+                assignment_words.push(assignment);
+                input = rest;
+            } else {
+                break;
+            }
+        }
+
+        // cmd_name and cmd_suffix
+        input = self.substitute_alias(input, true);
+        loop {
+            if !words.is_empty() {
+                input = self.substitute_alias(input, false);
+            }
+            if let Ok((rest, redirect)) = self.io_redirect(input) {
+                redirects.push(redirect);
+                input = rest;
+            } else if let Ok((rest, word)) = self.word(input) {
+                // A reserved word in place of the command name ends the
+                // command rather than being run
+                let is_cmd_name = words.is_empty() && assignment_words.is_empty() && redirects.is_empty();
+                if is_cmd_name && RESERVED_WORDS.contains(&word) {
+                    break;
+                }
+                words.push(Word::new(word));
+                input = rest;
+            } else {
+                break;
+            }
+        }
+
+        if words.is_empty() && assignment_words.is_empty() && redirects.is_empty() {
+            return fail(input);
+        }
 
         Ok((input, SimpleCommand {
-            assignment_words: assignment_words,
-            words: words
+            assignment_words,
+            words,
+            redirects
         }))
     }
 
-    fn assignment_word<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], (OsString, OsString)> {
+    fn io_redirect<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], IoRedirect> {
+        let (input, fd) = opt(|input| self.io_number(input))(input)?;
+        alt((
+            move |input| self.io_file(input, fd),
+            move |input| self.io_here(input, fd)
+        ))(input)
+    }
+
+    fn io_file<'a>(&'a self, input: &'a [u8], fd: Option<IoNumber>) -> RbResult<&'a [u8], IoRedirect> {
+        let (input, op) = self.redirection_op(input)?;
+        let (input, target) = self.word(input)?;
+        Ok((input, IoRedirect::File(Redirect {
+            fd,
+            op,
+            target: Word::new(target)
+        })))
+    }
+
+    fn io_here<'a>(&'a self, input: &'a [u8], fd: Option<IoNumber>) -> RbResult<&'a [u8], IoRedirect> {
+        let (input, _) = self.io_here_op(input)?;
+        let (input, delimiter) = self.word(input)?;
+        let (_, quoted) = here_doc_delimiter(delimiter);
+        let body = self.here_docs.borrow().bodies
+            .get(&(delimiter.as_ptr() as usize))
+            .cloned()
+            .unwrap_or_default();
+        Ok((input, IoRedirect::Here(HereDoc {
+            fd,
+            body,
+            expand: !quoted
+        })))
+    }
+
+    fn assignment_word<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], (OsString, Word)> {
         let (input, tok) = self.word(input)?;
-        let (remaining, name) = take_until1(b"=".as_ref())(tok.text)?;
-        let value = &remaining[1..];
-        Ok((input, (OsString::from_vec(name.to_vec()), OsString::from_vec(value.to_vec()))))
+        let (remaining, name) = take_until1(b"=".as_ref())(tok)?;
+        if !is_name(name) {
+            return fail(input);
+        }
+        let value = Word::new(&remaining[1..]);
+        Ok((input, (OsString::from_vec(name.to_vec()), value)))
     }
 
-    fn reserved_name<'a>(&self, word_name: &'static [u8]) -> Box<dyn Fn(&'a [u8]) -> RbResult<&'a [u8], ()> + '_> {
+    fn reserved_name<'a>(&'a self, word_name: &'static [u8]) -> BoxedParser<'a, 'a, ()> {
         Box::new(move |input: & [u8]| {
             let (input, tok) = self.word(input)?;
-            let tok = tok.eval();
+            // Reserved words are only recognised when entirely unquoted
             if tok == word_name {
                 Ok((input, ()))
             } else {
@@ -351,24 +980,279 @@ impl Parser {
         })
     }
 
-    fn pipeline_segment<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], SimpleCommand<'a>> {
+    fn pipeline_segment<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], ShellCommand> {
         let (input, _) = self.pipe_op(input)?;
-        let (input, cmd) = self.simple_command(input)?;
+        let (input, _) = self.linebreak(input)?;
+        let (input, cmd) = self.command(input)?;
         Ok((input, cmd))
     }
 
-    fn pipeline_sequence<'a>(&self, input: &'a [u8]) -> RbResult<&'a [u8], PipeLine<'a>> {
+    fn pipeline_sequence<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], PipeLine> {
         let reserved_bang = |input| self.reserved_name(b"!")(input);
+        let start = input;
         let (input, bang) = opt(reserved_bang)(input)?;
-        let (input, cmd0) = self.simple_command(input)?;
+        let (input, cmd0) = self.command(input)?;
         let (input, mut cmds) = many0(|input| self.pipeline_segment(input))(input)?;
         cmds.insert(0, cmd0);
+
+        let source = self.source_text(start, input);
+        let leading = source.iter().take_while(|c| is_blank(**c)).count();
+        let trailing = source.iter().rev().take_while(|c| is_blank(**c)).count();
         Ok((input, PipeLine {
             bang: bang.is_some(),
-            pipesequence: cmds
+            pipesequence: cmds,
+            source: source[leading..(source.len() - trailing).max(leading)].to_vec()
         }))
     } 
 
+    fn command<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], ShellCommand> {
+        let input = self.substitute_alias(input, true);
+        if let Ok((input, cmd)) = self.compound_command(input) {
+            let (input, redirects) = many0(|input| self.io_redirect(input))(input)?;
+            return Ok((input, ShellCommand::Compound(cmd, redirects)))
+        }
+
+        if let Ok((input, def)) = self.function_definition(input) {
+            return Ok((input, ShellCommand::FunctionDefinition(def)))
+        }
+
+        let (input, cmd) = self.simple_command(input)?;
+        Ok((input, ShellCommand::Simple(cmd)))
+    }
+
+    fn compound_command<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], CompoundCommand> {
+        alt((
+            |input| self.brace_group(input),
+            |input| self.subshell(input),
+            |input| self.for_clause(input),
+            |input| self.case_clause(input),
+            |input| self.if_clause(input),
+            |input| self.while_clause(input),
+            |input| self.until_clause(input)
+        ))(input)
+    }
+
+    fn function_definition<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], FunctionDefinition> {
+        let (input, name) = self.word(input)?;
+        if !is_name(name) || RESERVED_WORDS.contains(&name) {
+            return fail(input);
+        }
+        let (input, _) = self.lparen(input)?;
+        let (input, _) = self.rparen(input)?;
+        let (input, _) = self.linebreak(input)?;
+
+        let (input, cmd) = self.compound_command(input)?;
+        let (input, redirects) = many0(|input| self.io_redirect(input))(input)?;
+
+        Ok((input, FunctionDefinition {
+            name: OsString::from_vec(name.to_vec()),
+            body: Rc::new(ShellCommand::Compound(cmd, redirects))
+        }))
+    }
+
+    fn compound_list<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], CompoundList> {
+        let (input, _) = self.linebreak(input)?;
+        self.list(input, true)
+    }
+
+    /// And-or lists separated by `;` or `&`, and also by newlines within a
+    /// compound list. A trailing separator is consumed even if no command
+    /// follows it.
+    fn list<'a>(&'a self, input: &'a [u8], newlines: bool) -> RbResult<&'a [u8], CompoundList> {
+        let mut items = vec![];
+        let mut input = input;
+        loop {
+            let (rest, expression) = match self.expression(input) {
+                Ok(res) => res,
+                Err(e) if items.is_empty() => return Err(e),
+                Err(_) => break
+            };
+            input = rest;
+
+            let separator = if newlines {
+                self.separator(input)
+            } else {
+                self.seperator_op(input)
+            };
+            match separator {
+                Ok((rest, op)) => {
+                    items.push(ListItem {
+                        expression,
+                        asynchronous: op == SeperatorOp::Async
+                    });
+                    input = rest;
+                },
+                Err(_) => {
+                    items.push(ListItem {
+                        expression,
+                        asynchronous: false
+                    });
+                    break;
+                }
+            }
+        }
+
+        Ok((input, CompoundList {
+            items
+        }))
+    }
+
+    fn brace_group<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], CompoundCommand> {
+        let (input, _) = self.reserved_name(b"{")(input)?;
+        let (input, list) = self.compound_list(input)?;
+        let (input, _) = self.reserved_name(b"}")(input)?;
+        Ok((input, CompoundCommand::BraceGroup(list)))
+    }
+
+    fn subshell<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], CompoundCommand> {
+        let (input, _) = self.lparen(input)?;
+        let (input, list) = self.compound_list(input)?;
+        let (input, _) = self.rparen(input)?;
+        Ok((input, CompoundCommand::Subshell(list)))
+    }
+
+    fn if_clause<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], CompoundCommand> {
+        let mut branches = vec![];
+        let (mut input, _) = self.reserved_name(b"if")(input)?;
+        loop {
+            let (rest, condition) = self.compound_list(input)?;
+            let (rest, _) = self.reserved_name(b"then")(rest)?;
+            let (rest, body) = self.compound_list(rest)?;
+            branches.push((condition, body));
+            input = rest;
+
+            match self.reserved_name(b"elif")(input) {
+                Ok((rest, _)) => input = rest,
+                Err(_) => break
+            }
+        }
+
+        let (input, else_part) = opt(|input| {
+            let (input, _) = self.reserved_name(b"else")(input)?;
+            self.compound_list(input)
+        })(input)?;
+        let (input, _) = self.reserved_name(b"fi")(input)?;
+
+        Ok((input, CompoundCommand::If(IfClause {
+            branches,
+            else_part
+        })))
+    }
+
+    fn while_clause<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], CompoundCommand> {
+        let (input, _) = self.reserved_name(b"while")(input)?;
+        let (input, condition) = self.compound_list(input)?;
+        let (input, body) = self.do_group(input)?;
+        Ok((input, CompoundCommand::While(LoopClause {
+            condition,
+            body
+        })))
+    }
+
+    fn until_clause<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], CompoundCommand> {
+        let (input, _) = self.reserved_name(b"until")(input)?;
+        let (input, condition) = self.compound_list(input)?;
+        let (input, body) = self.do_group(input)?;
+        Ok((input, CompoundCommand::Until(LoopClause {
+            condition,
+            body
+        })))
+    }
+
+    fn for_clause<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], CompoundCommand> {
+        let (input, _) = self.reserved_name(b"for")(input)?;
+        let (input, name) = self.word(input)?;
+        if !is_name(name) {
+            return fail(input);
+        }
+        let (input, _) = self.linebreak(input)?;
+
+        let (input, words) = match self.reserved_name(b"in")(input) {
+            Ok((input, _)) => {
+                let (input, words) = many0(|input| self.word(input))(input)?;
+                let words = words.into_iter().map(Word::new).collect();
+                let (input, _) = self.sequential_sep(input)?;
+                (input, Some(words))
+            },
+            Err(_) => {
+                let (input, _) = opt(|input| self.sequential_sep(input))(input)?;
+                (input, None)
+            }
+        };
+        let (input, body) = self.do_group(input)?;
+
+        Ok((input, CompoundCommand::For(ForClause {
+            name: OsString::from_vec(name.to_vec()),
+            words,
+            body
+        })))
+    }
+
+    fn do_group<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], CompoundList> {
+        let (input, _) = self.reserved_name(b"do")(input)?;
+        let (input, list) = self.compound_list(input)?;
+        let (input, _) = self.reserved_name(b"done")(input)?;
+        Ok((input, list))
+    }
+
+    fn case_clause<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], CompoundCommand> {
+        let (input, _) = self.reserved_name(b"case")(input)?;
+        let (input, word) = self.word(input)?;
+        let word = Word::new(word);
+        let (input, _) = self.linebreak(input)?;
+        let (input, _) = self.reserved_name(b"in")(input)?;
+        let (mut input, _) = self.linebreak(input)?;
+
+        let mut items = vec![];
+        loop {
+            if let Ok((rest, _)) = self.reserved_name(b"esac")(input) {
+                input = rest;
+                break;
+            }
+
+            let (rest, item) = self.case_item(input)?;
+            items.push(item);
+
+            // Only the last item may omit its terminating ;;
+            match self.dsemi(rest) {
+                Ok((rest, _)) => {
+                    let (rest, _) = self.linebreak(rest)?;
+                    input = rest;
+                },
+                Err(_) => {
+                    let (rest, _) = self.reserved_name(b"esac")(rest)?;
+                    input = rest;
+                    break;
+                }
+            }
+        }
+
+        Ok((input, CompoundCommand::Case(CaseClause {
+            word,
+            items
+        })))
+    }
+
+    fn case_item<'a>(&'a self, input: &'a [u8]) -> RbResult<&'a [u8], CaseItem> {
+        let (input, _) = opt(|input| self.lparen(input))(input)?;
+        let (input, pattern0) = self.word(input)?;
+        let (input, mut patterns) = many0(|input| {
+            let (input, _) = self.pipe_op(input)?;
+            self.word(input)
+        })(input)?;
+        patterns.insert(0, pattern0);
+        let patterns = patterns.into_iter().map(Word::new).collect();
+        let (input, _) = self.rparen(input)?;
+
+        let (input, body) = opt(|input| self.compound_list(input))(input)?;
+        let (input, _) = self.linebreak(input)?;
+
+        Ok((input, CaseItem {
+            patterns,
+            body
+        }))
+    }
+
     // #[allow(dead_code)]
     // fn sh_main(&self, _cmd_name: &str, _args: Vec<OsString>) -> Result<ExitCode, Box<dyn Error>> {
     //     Ok(ExitCode::SUCCESS)
@@ -390,8 +1274,7 @@ mod tests {
                 let test_string = $test_string.as_bytes().to_vec();
                 let (remaining, tok) = parser.word(&test_string).unwrap();
                 let remaining = str::from_utf8(&remaining).unwrap();
-                let tok = tok.eval();
-                let tok = str::from_utf8(&tok).unwrap();
+                let tok = str::from_utf8(tok).unwrap();
                 assert_eq!(tok, $expected_tok);
                 assert_eq!(remaining, $expected_remain);
             }
@@ -506,12 +1389,34 @@ mod tests {
     test_redir_op_token!(test_redir_op2, ">afile", RedirectionOp::Great, "afile");
     test_redir_op_token!(test_redir_op3, ">|afile", RedirectionOp::Clobber, "afile");
     test_redir_op_token!(test_redir_op4, "<afile", RedirectionOp::Less, "afile");
+    test_word_token!(test_single_quote, "'a  b' c", "'a  b'", "c");
+    test_word_token!(test_single_quote2, "'x;y|z'", "'x;y|z'", "");
+    test_word_token!(test_single_quote3, "'a\\'b", "'a\\'b", "");
+    test_word_token!(test_double_quote, "\"x;y\" z", "\"x;y\"", "z");
+    test_word_token!(test_double_quote2, "\"a\\\"b\" c", "\"a\\\"b\"", "c");
+    test_word_token!(test_double_quote3, "\"$(echo \")\")\"", "\"$(echo \")\")\"", "");
+    test_word_token!(test_mixed_quotes, "a'b'\"c\"d e", "a'b'\"c\"d", "e");
+    test_word_token!(test_backslash, "a\\ b c", "a\\ b", "c");
+    test_word_token!(test_backslash2, "\\;x y", "\\;x", "y");
+    test_word_token!(test_line_continuation, "foo\\\nbar baz", "foo\\\nbar", "baz");
+    test_word_token!(test_line_continuation2, "\\\nfoo", "foo", "");
+    test_word_token!(test_command_sub_token, "$(a b) c", "$(a b)", "c");
+    test_word_token!(test_backquote_token, "`a b` c", "`a b`", "c");
+    test_word_token!(test_special_param_token, "$# c", "$#", "c");
+    test_word_token!(test_braced_param_token, "${a:-b c}d e", "${a:-b c}d", "e");
+
+    #[test]
+    fn test_unterminated_quote() {
+        let parser = Parser::new();
+        assert!(parser.word(b"'foo").is_err());
+        assert!(parser.word(b"\"foo").is_err());
+    }
 
     #[test]
     fn test_empty_word() {
         let parser = Parser::new();
         let test_string = b"";
-        let expected: RbResult<&[u8], Word> = Err(
+        let expected: RbResult<&[u8], &[u8]> = Err(
             nom::Err::Error(RbError::new(error::ErrorKind::Fail))
         );
         let actual_result = parser.word(test_string);
@@ -559,7 +1464,7 @@ mod tests {
     fn test_assignment_word() {
         let parser = Parser::new();
         let input = b"foo=bar";
-        let expected = (OsString::from("foo"), OsString::from("bar"));
+        let expected = (OsString::from("foo"), Word::new(b"bar"));
         let (_, actual) = parser.assignment_word(input).unwrap();
         assert_eq!(actual, expected);
     }
@@ -576,27 +1481,282 @@ mod tests {
         ];
         let expected = SimpleCommand {
             assignment_words: vec![],
-            words: words
+            words,
+            redirects: vec![]
         };
         let (_, actual) = parser.simple_command(input).unwrap();
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_simple_cmd_redirects() {
+        let parser = Parser::new();
+        let input = b">out x=1 cmd 2>&1 arg 12<in";
+        let expected = SimpleCommand {
+            assignment_words: vec![(OsString::from("x"), Word::new(b"1"))],
+            words: vec![Word::new(b"cmd"), Word::new(b"arg")],
+            redirects: vec![
+                IoRedirect::File(Redirect { fd: None, op: RedirectionOp::Great, target: Word::new(b"out") }),
+                IoRedirect::File(Redirect { fd: Some(2), op: RedirectionOp::GreatAnd, target: Word::new(b"1") }),
+                IoRedirect::File(Redirect { fd: Some(12), op: RedirectionOp::Less, target: Word::new(b"in") })
+            ]
+        };
+        let (_, actual) = parser.simple_command(input).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_io_number_needs_operator() {
+        let parser = Parser::new();
+        let (_, actual) = parser.simple_command(b"echo 2 >x").unwrap();
+        assert_eq!(actual.words, vec![Word::new(b"echo"), Word::new(b"2")]);
+        assert!(matches!(actual.redirects[0], IoRedirect::File(Redirect { fd: None, .. })));
+    }
+
+    /// Parse all the commands of a script
+    fn script<'a>(parser: &'a Parser, input: &'a [u8]) -> Result<Vec<CompleteCommand>, nom::Err<RbError>> {
+        parser.start_script(input);
+        let mut cmds = vec![];
+        let mut rest = input;
+        while let (next, Some(cmd)) = parser.script_command(input, rest)? {
+            cmds.push(cmd);
+            rest = next;
+        }
+        Ok(cmds)
+    }
+
+    fn here_docs(input: &[u8]) -> Vec<HereDoc> {
+        let parser = Parser::new();
+        let cmds = script(&parser, input).unwrap();
+        let mut here_docs = vec![];
+        for mut cmd in cmds {
+            if let ShellCommand::Simple(simple) = cmd.list.items.remove(0).expression.term.pipesequence.remove(0) {
+                for redirect in simple.redirects {
+                    if let IoRedirect::Here(here_doc) = redirect {
+                        here_docs.push(here_doc);
+                    }
+                }
+            }
+        }
+        here_docs
+    }
+
+    #[test]
+    fn test_here_doc() {
+        let actual = here_docs(b"cat <<EOF\nhello $x\nEOF\necho done\n");
+        assert_eq!(actual, vec![HereDoc { fd: None, body: b"hello $x\n".to_vec(), expand: true }]);
+    }
+
+    #[test]
+    fn test_here_doc_multiple() {
+        let actual = here_docs(b"cat <<a 3<<'b' x\n1\na\n2\nb\ncat <<-c\n\t\t3\n\tc\n");
+        assert_eq!(actual, vec![
+            HereDoc { fd: None, body: b"1\n".to_vec(), expand: true },
+            HereDoc { fd: Some(3), body: b"2\n".to_vec(), expand: false },
+            HereDoc { fd: None, body: b"3\n".to_vec(), expand: true }
+        ]);
+    }
+
+    #[test]
+    fn test_here_doc_delimiter() {
+        assert_eq!(here_doc_delimiter(b"EOF"), (b"EOF".to_vec(), false));
+        assert_eq!(here_doc_delimiter(b"'E O'F"), (b"E OF".to_vec(), true));
+        assert_eq!(here_doc_delimiter(b"\\EOF"), (b"EOF".to_vec(), true));
+        assert_eq!(here_doc_delimiter(b"\"E\\$\"OF"), (b"E$OF".to_vec(), true));
+    }
+
     use super::PipeLine;
     #[test]
     fn test_pipeline() {
         let parser = Parser::new();
         let input = b"! ls | grep stuff | cat";
         let cmds = vec![
-            parser.simple_command(b"ls").unwrap().1,
-            parser.simple_command(b"grep stuff").unwrap().1,
-            parser.simple_command(b"cat").unwrap().1
+            parser.command(b"ls").unwrap().1,
+            parser.command(b"grep stuff").unwrap().1,
+            parser.command(b"cat").unwrap().1
         ];
         let expected = PipeLine {
             bang: true,
-            pipesequence: cmds
+            pipesequence: cmds,
+            source: b"! ls | grep stuff | cat".to_vec()
         };
         let (_, actual) = parser.pipeline_sequence(input).unwrap();
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn test_reserved_word_ends_command() {
+        let parser = Parser::new();
+        assert!(parser.simple_command(b"fi").is_err());
+        let (_, actual) = parser.simple_command(b"echo fi").unwrap();
+        assert_eq!(actual.words, vec![Word::new(b"echo"), Word::new(b"fi")]);
+    }
+
+    #[test]
+    fn test_if_clause() {
+        let parser = Parser::new();
+        let (rest, actual) = parser.compound_command(b"if a\nthen b; c; elif d; then e; else f; fi x").unwrap();
+        assert_eq!(rest, b"x");
+        match actual {
+            CompoundCommand::If(clause) => {
+                assert_eq!(clause.branches.len(), 2);
+                assert_eq!(clause.branches[0].1.items.len(), 2);
+                assert!(clause.else_part.is_some());
+            },
+            _ => panic!("expected an if clause")
+        }
+    }
+
+    #[test]
+    fn test_for_clause() {
+        let parser = Parser::new();
+        let (_, actual) = parser.compound_command(b"for i in a b; do echo $i; done").unwrap();
+        match actual {
+            CompoundCommand::For(clause) => {
+                assert_eq!(clause.name, OsString::from("i"));
+                assert_eq!(clause.words, Some(vec![Word::new(b"a"), Word::new(b"b")]));
+            },
+            _ => panic!("expected a for clause")
+        }
+
+        let (_, actual) = parser.compound_command(b"for i do :; done").unwrap();
+        assert!(matches!(actual, CompoundCommand::For(ForClause { words: None, .. })));
+    }
+
+    #[test]
+    fn test_case_clause() {
+        let parser = Parser::new();
+        let (_, actual) = parser.compound_command(b"case $x in\n(a|b) one;;\nc) ;;\n*) two\nesac").unwrap();
+        match actual {
+            CompoundCommand::Case(clause) => {
+                assert_eq!(clause.items.len(), 3);
+                assert_eq!(clause.items[0].patterns, vec![Word::new(b"a"), Word::new(b"b")]);
+                assert!(clause.items[1].body.is_none());
+                assert_eq!(clause.items[2].patterns, vec![Word::new(b"*")]);
+            },
+            _ => panic!("expected a case clause")
+        }
+    }
+
+    #[test]
+    fn test_unterminated_compound() {
+        let parser = Parser::new();
+        assert!(parser.compound_command(b"if true; then echo").is_err());
+        assert!(parser.compound_command(b"{ echo; ").is_err());
+        assert!(parser.compound_command(b"(echo").is_err());
+    }
+
+    #[test]
+    fn test_function_definition() {
+        let parser = Parser::new();
+        let (rest, actual) = parser.command(b"f ()\n  { echo $1; } >out\nnext").unwrap();
+        assert_eq!(rest, b"\nnext");
+        let (_, body) = parser.command(b"{ echo $1; } >out").unwrap();
+        assert_eq!(actual, ShellCommand::FunctionDefinition(FunctionDefinition {
+            name: OsString::from("f"),
+            body: Rc::new(body)
+        }));
+    }
+
+    #[test]
+    fn test_function_name_must_be_name() {
+        let parser = Parser::new();
+        assert!(parser.function_definition(b"if() { :; }").is_err());
+        assert!(parser.function_definition(b"'f'() { :; }").is_err());
+        assert!(parser.function_definition(b"f() echo").is_err());
+    }
+
+    #[test]
+    fn test_list_separators() {
+        let parser = Parser::new();
+        let (rest, actual) = parser.complete_command(b"a; b & c &\nd").unwrap();
+        assert_eq!(rest, b"d");
+        let asynchronous: Vec<bool> = actual.list.items.iter().map(|item| item.asynchronous).collect();
+        assert_eq!(asynchronous, vec![false, true, true]);
+    }
+
+    #[test]
+    fn test_compound_list_newlines() {
+        let parser = Parser::new();
+        let (rest, actual) = parser.compound_list(b"\n a &\n\n b;\n }").unwrap();
+        assert_eq!(rest, b" }");
+        assert_eq!(actual.items.len(), 2);
+        assert!(actual.items[0].asynchronous);
+    }
+
+    #[test]
+    fn test_interactive_command() {
+        let parser = Parser::new();
+        for input in [
+            b"echo 'a\n".as_ref(), b"echo \"$(b\n", b"echo a \\\n", b"echo a\\\n", b"a |\n", b"a &&\n",
+            b"if true\n", b"if true; then a; fi; for x in a\n", b"case x in a) b;;\n", b"f() {\n",
+            b"cat <<EOF\n", b"cat <<EOF\nbody\n"
+        ] {
+            let error = parser.interactive_command(input).unwrap_err();
+            assert!(matches!(error, nom::Err::Error(ref e) if e.is_incomplete()), "{:?}", String::from_utf8_lossy(input));
+        }
+        for input in [
+            b"echo a\n".as_ref(), b"echo 'a\nb'\n", b"a | b\n", b"if true; then a; fi\n", b"echo if\n",
+            b"case x in a) b;; esac\n", b"f() { a; }\n", b"a # if\n", b"echo a\\\\\n", b"cat <<EOF\nbody\nEOF\n"
+        ] {
+            let (rest, cmd) = parser.interactive_command(input).unwrap();
+            assert!(rest.is_empty() && cmd.is_some(), "{:?}", String::from_utf8_lossy(input));
+        }
+        assert_eq!(parser.interactive_command(b"\n"), Ok((b"".as_ref(), None)));
+        assert_eq!(parser.interactive_command(b"  # comment\n"), Ok((b"".as_ref(), None)));
+        assert_eq!(parser.interactive_command(b"echo a; fi\n"), Err(nom::Err::Error(RbError::unexpected(b"fi", 8))));
+    }
+
+    /// The words of each simple command in a complete command
+    fn command_words(cmd: &CompleteCommand) -> Vec<String> {
+        let mut commands = vec![];
+        for item in cmd.list.items.iter() {
+            let expression = &item.expression;
+            let pipelines = std::iter::once(&expression.term).chain(expression.seq.iter().map(|l| &l.pipeline));
+            for cmd in pipelines.flat_map(|pipeline| pipeline.pipesequence.iter()) {
+                if let ShellCommand::Simple(simple) = cmd {
+                    let words: Vec<&[u8]> = simple.words.iter().map(|word| word.text.as_slice()).collect();
+                    commands.push(String::from_utf8(words.join(&b' ')).unwrap());
+                }
+            }
+        }
+        commands
+    }
+
+    #[test]
+    fn test_aliases() {
+        let parser = Parser::new();
+        let aliases: HashMap<Vec<u8>, Vec<u8>> = [
+            ("ll", "ls -l"), ("ls", "ls -F"), ("sudo", "sudo "), ("loop", "loop x"), ("ifx", "if true"), ("a", "b"), ("b", "a"),
+            ("two", "echo 1\necho 2")
+        ].iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec())).collect();
+        parser.set_aliases(&Rc::new(aliases));
+        let command = |input: &[u8]| parser.interactive_command(input).unwrap().1.unwrap();
+
+        assert_eq!(command_words(&command(b"ll dir; echo ll | ll\n")), ["ls -F -l dir", "echo ll", "ls -F -l"]);
+        assert_eq!(command_words(&command(b"X=1 ll >f 'll' \"ll\" ll\n")), ["ls -F -l 'll' \"ll\" ll"]);
+        assert_eq!(command_words(&command(b"sudo ll\n")), ["sudo ls -F -l"]);
+        assert_eq!(command_words(&command(b"loop; a\n")), ["loop x", "a"]);
+        assert_eq!(command_words(&command(b"'ll' x\n")), ["'ll' x"]);
+        assert_eq!(command_words(&command(b"two; echo 3\n")), ["echo 1", "echo 2", "echo 3"]);
+
+        let cmd = command(b"ifx; then :; fi\n");
+        assert!(matches!(cmd.list.items[0].expression.term.pipesequence[0], ShellCommand::Compound(CompoundCommand::If(_), _)));
+        let cmd = command(b"ll | cat &\n");
+        assert_eq!(cmd.list.items[0].expression.term.source, b"ll | cat");
+    }
+
+    #[test]
+    fn test_located_errors() {
+        let parser = Parser::new();
+        let error = |input: &[u8]| match script(&parser, input) {
+            Err(nom::Err::Error(e)) => e,
+            _ => panic!("{:?} parsed", String::from_utf8_lossy(input))
+        };
+        assert_eq!(error(b"echo a\nif true\nthen fi\n"), RbError::unexpected(b"fi", 20));
+        assert_eq!(error(b"a && &&b\n"), RbError::unexpected(b"&&", 5));
+        assert_eq!(error(b"echo >\n"), RbError::unexpected(b"newline", 6));
+        assert_eq!(error(b"(echo a\n"), RbError::incomplete(7));
+        assert_eq!(error(b"echo 'a\n"), RbError::incomplete(7));
+    }
 }
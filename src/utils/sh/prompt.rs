@@ -0,0 +1,74 @@
+use nix::unistd::geteuid;
+
+use super::ast_nodes::ExecEnv;
+use super::expansions::expand_prompt_string;
+
+/// The value a prompt variable has when it is unset
+fn default_prompt(name: &[u8]) -> &'static [u8] {
+    match name {
+        b"PS1" if geteuid().is_root() => b"# ",
+        b"PS1" => b"$ ",
+        b"PS2" => b"> ",
+        _ => b"+ "
+    }
+}
+
+/// Expand one of the prompt variables PS1, PS2 or PS4. In PS1 a `!` is
+/// replaced by the number the next command will have in the history, and
+/// `!!` by a single `!`.
+pub fn expand_prompt(ev: &mut ExecEnv, name: &[u8]) -> Vec<u8> {
+    let value = match ev.get_var(name) {
+        Some(value) => value.to_vec(),
+        None => return default_prompt(name).to_vec()
+    };
+
+    // Commands run by the prompt are not traced
+    let xtrace = ev.options.xtrace;
+    ev.options.xtrace = false;
+    let expanded = expand_prompt_string(&value, ev);
+    ev.options.xtrace = xtrace;
+    let expanded = match expanded {
+        Ok(expanded) => expanded,
+        Err(e) => {
+            eprintln!("sh: {}", e);
+            value
+        }
+    };
+    if name != b"PS1" {
+        return expanded
+    }
+
+    let mut prompt = vec![];
+    let mut i = 0;
+    while i < expanded.len() {
+        if expanded[i] == b'!' {
+            if expanded.get(i + 1) == Some(&b'!') {
+                prompt.push(b'!');
+                i += 1;
+            } else {
+                prompt.extend_from_slice(ev.history.next_number().to_string().as_bytes());
+            }
+        } else {
+            prompt.push(expanded[i]);
+        }
+        i += 1;
+    }
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_prompt() {
+        let mut ev = ExecEnv::new();
+        ev.set_var(b"PS2", b"more> ".to_vec()).unwrap();
+        assert_eq!(expand_prompt(&mut ev, b"PS2"), b"more> ");
+        assert_eq!(expand_prompt(&mut ev, b"PS4"), b"+ ");
+
+        ev.set_var(b"NAME", b"box".to_vec()).unwrap();
+        ev.set_var(b"PS1", b"$NAME !/!! $ ".to_vec()).unwrap();
+        assert_eq!(expand_prompt(&mut ev, b"PS1"), b"box 1/! $ ");
+    }
+}
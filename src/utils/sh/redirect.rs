@@ -0,0 +1,350 @@
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, stdout, stderr, Seek, Write};
+use std::ffi::OsStr;
+use std::os::unix::prelude::{AsRawFd, FromRawFd, OsStrExt, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::unistd::{close, dup2};
+use nix::errno::Errno;
+
+use super::ast_nodes::{ExecEnv, Flow, HereDoc, IoRedirect, Redirect, RedirectionOp};
+use super::expansions::expand_here_doc;
+use crate::io_util::{check_stdout, error_message};
+
+/// File descriptors at or above this number are used internally by the shell
+/// so that they do not clash with descriptors named in a redirection
+const SHELL_FD_BASE: RawFd = 10;
+
+#[derive(Debug)]
+enum FdAction {
+    /// Duplicate the first descriptor onto the second
+    Dup(RawFd, RawFd),
+    Close(RawFd)
+}
+
+/// The redirections of a single command with every target opened, ready to
+/// be applied either in a child before exec or to the shell itself
+#[derive(Debug)]
+pub struct RedirectPlan {
+    actions: Vec<FdAction>,
+    // Held open until the plan has been applied
+    #[allow(dead_code)]
+    files: Vec<File>
+}
+
+impl RedirectPlan {
+    /// Expand and open the targets of `redirects`. Returns None if a target
+    /// could not be opened, in which case the error has been reported and
+    /// the command should fail with status 1.
+    pub fn new(redirects: &[IoRedirect], ev: &mut ExecEnv) -> Result<Option<RedirectPlan>, Box<dyn Error>> {
+        let mut plan = RedirectPlan { actions: vec![], files: vec![] };
+
+        for redirect in redirects {
+            let opened = match redirect {
+                IoRedirect::File(redirect) => plan.add_file(redirect, ev)?,
+                IoRedirect::Here(here_doc) => plan.add_here_doc(here_doc, ev)?
+            };
+            if !opened {
+                return Ok(None)
+            }
+        }
+
+        Ok(Some(plan))
+    }
+
+    fn add_file(&mut self, redirect: &Redirect, ev: &mut ExecEnv) -> Result<bool, Box<dyn Error>> {
+        let target = redirect.target.eval(ev)?;
+        let fd = match redirect.fd {
+            Some(n) => n as RawFd,
+            None => default_fd(&redirect.op)
+        };
+
+        let mut options = OpenOptions::new();
+        match redirect.op {
+            RedirectionOp::Less => options.read(true),
+            // With noclobber > may only create a file, or write to one
+            // which isn't a regular file such as /dev/null
+            RedirectionOp::Great if ev.options.noclobber => {
+                match fs::metadata(OsStr::from_bytes(&target)) {
+                    Ok(metadata) if metadata.is_file() => {
+                        eprintln!("sh: {}: cannot overwrite existing file", String::from_utf8_lossy(&target));
+                        return Ok(false)
+                    },
+                    Ok(_) => options.write(true),
+                    Err(_) => options.write(true).create_new(true)
+                }
+            },
+            RedirectionOp::Great | RedirectionOp::Clobber => options.write(true).create(true).truncate(true),
+            RedirectionOp::DGreat => options.append(true).create(true),
+            RedirectionOp::LessGreat => options.read(true).write(true).create(true),
+            RedirectionOp::LessAnd | RedirectionOp::GreatAnd => {
+                match dup_action(&target, fd) {
+                    Some(action) => self.actions.push(action),
+                    None => {
+                        eprintln!("sh: {}: bad file descriptor", String::from_utf8_lossy(&target));
+                        return Ok(false)
+                    }
+                }
+                return Ok(true)
+            }
+        };
+
+        match options.open(OsStr::from_bytes(&target)) {
+            Ok(file) => {
+                self.add_dup(file, fd)?;
+                Ok(true)
+            },
+            Err(why) => {
+                eprintln!("sh: {}: {}", String::from_utf8_lossy(&target), error_message(&why));
+                Ok(false)
+            }
+        }
+    }
+
+    /// Write the body of a here-document to an unlinked temporary file
+    /// which becomes the input of the command
+    fn add_here_doc(&mut self, here_doc: &HereDoc, ev: &mut ExecEnv) -> Result<bool, Box<dyn Error>> {
+        let body = if here_doc.expand {
+            expand_here_doc(&here_doc.body, ev)?
+        } else {
+            here_doc.body.clone()
+        };
+        let fd = match here_doc.fd {
+            Some(n) => n as RawFd,
+            None => 0
+        };
+
+        let mut file = match here_doc_file() {
+            Ok(file) => file,
+            Err(why) => {
+                eprintln!("sh: cannot create temp file for here-document: {}", error_message(&why));
+                return Ok(false)
+            }
+        };
+        file.write_all(&body)?;
+        file.rewind()?;
+        self.add_dup(file, fd)?;
+        Ok(true)
+    }
+
+    fn add_dup(&mut self, file: File, fd: RawFd) -> Result<(), Box<dyn Error>> {
+        // Move the file out of the way of any descriptor the command may
+        // name in a later redirection
+        let src = fcntl(file.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(SHELL_FD_BASE))?;
+        drop(file);
+        let file = unsafe { File::from_raw_fd(src) };
+
+        self.actions.push(FdAction::Dup(src, fd));
+        self.files.push(file);
+        Ok(())
+    }
+
+    /// Perform the redirections on the current process. Only async-signal-safe
+    /// calls are made so this may be used between fork and exec.
+    pub fn apply_in_child(&self) -> io::Result<()> {
+        for action in self.actions.iter() {
+            perform(action)?;
+        }
+        Ok(())
+    }
+
+    /// Perform the redirections on the shell itself, saving the descriptors
+    /// they replace so they can be restored once the command has run
+    pub fn apply(&self) -> io::Result<SavedFds> {
+        stdout().flush()?;
+        stderr().flush()?;
+
+        let mut saved = SavedFds { fds: vec![] };
+        for action in self.actions.iter() {
+            let fd = match action {
+                FdAction::Dup(_, fd) | FdAction::Close(fd) => *fd
+            };
+
+            if !saved.fds.iter().any(|(saved_fd, _)| *saved_fd == fd) {
+                let copy = match fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(SHELL_FD_BASE)) {
+                    Ok(copy) => Some(copy),
+                    Err(Errno::EBADF) => None,
+                    Err(e) => {
+                        saved.restore();
+                        return Err(e.into())
+                    }
+                };
+                saved.fds.push((fd, copy));
+            }
+
+            if let Err(e) = perform(action) {
+                saved.restore();
+                return Err(e)
+            }
+        }
+        Ok(saved)
+    }
+}
+
+/// Run `f` with `redirects` applied to the shell itself, restoring the
+/// original descriptors afterwards
+pub fn with_redirects<F>(redirects: &[IoRedirect], ev: &mut ExecEnv, f: F) -> Result<i32, Box<dyn Error>>
+where
+    F: FnOnce(&mut ExecEnv) -> Result<i32, Box<dyn Error>>
+{
+    Ok(redirected(redirects, ev, f)?.unwrap_or(1))
+}
+
+/// Like `with_redirects`, for a special builtin: a redirection which cannot
+/// be performed makes a non-interactive shell exit
+pub fn with_special_redirects<F>(redirects: &[IoRedirect], ev: &mut ExecEnv, f: F) -> Result<i32, Box<dyn Error>>
+where
+    F: FnOnce(&mut ExecEnv) -> Result<i32, Box<dyn Error>>
+{
+    match redirected(redirects, ev, f)? {
+        Some(status) => Ok(status),
+        None => Ok(special_redirect_failed(ev))
+    }
+}
+
+fn special_redirect_failed(ev: &mut ExecEnv) -> i32 {
+    if !ev.interactive {
+        ev.flow = Flow::Exit;
+    }
+    1
+}
+
+/// Run `f` with `redirects` applied, or return None if they could not be
+fn redirected<F>(redirects: &[IoRedirect], ev: &mut ExecEnv, f: F) -> Result<Option<i32>, Box<dyn Error>>
+where
+    F: FnOnce(&mut ExecEnv) -> Result<i32, Box<dyn Error>>
+{
+    if redirects.is_empty() {
+        check_stdout();
+        return f(ev).map(Some)
+    }
+
+    let plan = match RedirectPlan::new(redirects, ev)? {
+        Some(plan) => plan,
+        None => return Ok(None)
+    };
+    let saved = match plan.apply() {
+        Ok(saved) => saved,
+        Err(why) => {
+            eprintln!("sh: {}", error_message(&why));
+            return Ok(None)
+        }
+    };
+    check_stdout();
+    let result = f(ev);
+    saved.restore();
+    result.map(Some)
+}
+
+/// Apply `redirects` to the shell for good, as `exec` without a command does
+pub fn redirect_shell(redirects: &[IoRedirect], ev: &mut ExecEnv) -> Result<i32, Box<dyn Error>> {
+    let plan = match RedirectPlan::new(redirects, ev)? {
+        Some(plan) => plan,
+        None => return Ok(special_redirect_failed(ev))
+    };
+    match plan.apply() {
+        Ok(saved) => {
+            saved.discard();
+            Ok(0)
+        },
+        Err(why) => {
+            eprintln!("sh: {}", error_message(&why));
+            Ok(special_redirect_failed(ev))
+        }
+    }
+}
+
+/// Descriptors replaced by `RedirectPlan::apply`
+#[derive(Debug)]
+pub struct SavedFds {
+    fds: Vec<(RawFd, Option<RawFd>)>
+}
+
+impl SavedFds {
+    /// Keep the redirections, closing the saved copies of the descriptors
+    /// they replaced
+    pub fn discard(self) {
+        for (_, copy) in self.fds {
+            if let Some(copy) = copy {
+                let _ = close(copy);
+            }
+        }
+    }
+
+    /// Put back every descriptor replaced by the redirections
+    pub fn restore(self) {
+        let _ = stdout().flush();
+        let _ = stderr().flush();
+
+        for (fd, copy) in self.fds.into_iter().rev() {
+            match copy {
+                Some(copy) => {
+                    let _ = dup2(copy, fd);
+                    let _ = close(copy);
+                },
+                None => {
+                    let _ = close(fd);
+                }
+            }
+        }
+    }
+}
+
+/// Create and unlink a new temporary file
+fn here_doc_file() -> io::Result<File> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    loop {
+        let n = COUNT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("sh-heredoc-{}-{}", std::process::id(), n));
+        match OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+            Ok(file) => {
+                std::fs::remove_file(&path)?;
+                return Ok(file)
+            },
+            Err(why) if why.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(why) => return Err(why)
+        }
+    }
+}
+
+fn default_fd(op: &RedirectionOp) -> RawFd {
+    match op {
+        RedirectionOp::Less | RedirectionOp::LessAnd | RedirectionOp::LessGreat => 0,
+        _ => 1
+    }
+}
+
+/// The action for `<&word` and `>&word`, where word is a descriptor or `-`,
+/// or None if it is neither
+fn dup_action(target: &[u8], fd: RawFd) -> Option<FdAction> {
+    if target == b"-" {
+        return Some(FdAction::Close(fd))
+    }
+
+    match std::str::from_utf8(target).ok().and_then(|s| s.parse::<RawFd>().ok()) {
+        Some(src) if target.iter().all(u8::is_ascii_digit) => Some(FdAction::Dup(src, fd)),
+        _ => None
+    }
+}
+
+fn perform(action: &FdAction) -> io::Result<()> {
+    match *action {
+        FdAction::Dup(src, fd) if src == fd => {
+            // dup2 is a no-op here so the descriptor must be made
+            // inheritable by hand
+            fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+        },
+        FdAction::Dup(src, fd) => {
+            dup2(src, fd)?;
+        },
+        FdAction::Close(fd) => {
+            match close(fd) {
+                Ok(()) | Err(Errno::EBADF) => (),
+                Err(e) => return Err(e.into())
+            }
+        }
+    }
+    Ok(())
+}
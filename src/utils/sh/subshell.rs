@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::io::{stdout, stderr, Write};
+use nix::errno::Errno;
+use nix::unistd::{fork, ForkResult, Pid};
+use nix::sys::signal::{signal, SigHandler, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+
+use super::ast_nodes::ExecEnv;
+use super::builtins::run_exit_trap;
+use super::trap::pending_signal;
+
+/// Run `f` in a forked copy of the shell. The child exits with the status
+/// returned by `f` and never returns from this function.
+pub fn fork_subshell<F>(ev: &mut ExecEnv, f: F) -> Result<Pid, Box<dyn Error>>
+where
+    F: FnOnce(&mut ExecEnv) -> i32
+{
+    // Anything still buffered would otherwise be written twice
+    stdout().flush()?;
+    stderr().flush()?;
+
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => Ok(child),
+        ForkResult::Child => {
+            // The shell's background jobs are not children of the subshell
+            ev.jobs.clear();
+            ev.job_control = None;
+            // Traps other than ignored signals are not inherited
+            for (number, action) in ev.traps.iter() {
+                if let (false, Ok(sig)) = (action.is_empty(), Signal::try_from(*number)) {
+                    let _ = unsafe { signal(sig, SigHandler::SigDfl) };
+                }
+            }
+            ev.traps.retain(|_, action| action.is_empty());
+            if ev.interactive {
+                reset_signals();
+            }
+            // Rust ignores SIGPIPE, but a subshell writing to a closed pipe
+            // should be killed by it like any other command
+            let _ = unsafe { signal(Signal::SIGPIPE, SigHandler::SigDfl) };
+            let status = f(ev);
+            exit_subshell(ev, status)
+        }
+    }
+}
+
+/// Restore the default action of the signals an interactive shell ignores.
+/// Only async-signal-safe calls are made so this may be used between fork
+/// and exec.
+pub fn reset_signals() {
+    for sig in [Signal::SIGINT, Signal::SIGQUIT, Signal::SIGTSTP, Signal::SIGTTIN, Signal::SIGTTOU] {
+        let _ = unsafe { signal(sig, SigHandler::SigDfl) };
+    }
+}
+
+/// The exit status of a subshell whose commands produced `result`, reporting
+/// any error that aborted them
+pub fn subshell_status(result: Result<i32, Box<dyn Error>>) -> i32 {
+    match result {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("sh: {}", e);
+            2
+        }
+    }
+}
+
+/// Terminate a forked subshell without running the parent's cleanup. An
+/// EXIT trap set by the subshell itself is run first.
+fn exit_subshell(ev: &mut ExecEnv, status: i32) -> ! {
+    let status = run_exit_trap(ev, status);
+    let _ = stdout().flush();
+    let _ = stderr().flush();
+    unsafe { libc::_exit(status) }
+}
+
+/// Wait for a child process, returning its exit status
+pub fn wait_for(pid: Pid) -> Result<i32, Box<dyn Error>> {
+    loop {
+        if let Some(status) = wait_trapped(pid)? {
+            return Ok(status)
+        }
+    }
+}
+
+/// Wait for a child process like `wait_for`, but give up and return None if
+/// a signal with a trap is received first
+pub fn wait_trapped(pid: Pid) -> Result<Option<i32>, Box<dyn Error>> {
+    loop {
+        match waitpid(pid, None) {
+            Ok(WaitStatus::Exited(_, status)) => return Ok(Some(status)),
+            Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(Some(128 + signal as i32)),
+            Ok(_) => continue,
+            Err(Errno::EINTR) if pending_signal().is_some() => return Ok(None),
+            Err(Errno::EINTR) => continue,
+            Err(e) => return Err(e.into())
+        }
+    }
+}
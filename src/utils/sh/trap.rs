@@ -0,0 +1,80 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+use super::ast_nodes::{ExecEnv, Flow};
+use super::run_script;
+
+/// One more than the largest signal number
+const NSIG: usize = 65;
+
+/// The signals which have been received but whose traps haven't run yet
+static PENDING: [AtomicBool; NSIG] = [const { AtomicBool::new(false) }; NSIG];
+static ANY_PENDING: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn catch_signal(sig: libc::c_int) {
+    if let Some(pending) = PENDING.get(sig as usize) {
+        pending.store(true, Ordering::SeqCst);
+        ANY_PENDING.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Catch a signal so that its trap runs once the current command is done.
+/// System calls interrupted by it are not restarted, so that `wait` can
+/// return as soon as it arrives.
+pub fn catch(sig: Signal) -> nix::Result<()> {
+    let action = SigAction::new(SigHandler::Handler(catch_signal), SaFlags::empty(), SigSet::empty());
+    unsafe { sigaction(sig, &action) }.map(|_| ())
+}
+
+/// The number of a signal received whose trap hasn't run yet
+pub fn pending_signal() -> Option<i32> {
+    if !ANY_PENDING.load(Ordering::SeqCst) {
+        return None
+    }
+    PENDING.iter().position(|pending| pending.load(Ordering::SeqCst)).map(|sig| sig as i32)
+}
+
+/// The signals which were ignored when the shell started
+pub fn ignored_signals() -> HashSet<i32> {
+    Signal::iterator()
+        // Rust ignores SIGPIPE before main, so whether it was ignored
+        // already is unknown
+        .filter(|sig| *sig != Signal::SIGPIPE)
+        .filter(|sig| {
+            let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+            let ret = unsafe { libc::sigaction(*sig as i32, std::ptr::null(), &mut action) };
+            ret == 0 && action.sa_sigaction == libc::SIG_IGN
+        })
+        .map(|sig| sig as i32)
+        .collect()
+}
+
+/// Run the traps of any signals received since this was last called. The
+/// value of $? is left as it was unless a trap exits the shell.
+pub fn run_pending_traps(ev: &mut ExecEnv) {
+    if !ANY_PENDING.swap(false, Ordering::SeqCst) {
+        return
+    }
+
+    for (number, pending) in PENDING.iter().enumerate() {
+        if !pending.swap(false, Ordering::SeqCst) {
+            continue;
+        }
+        let action = match ev.traps.get(&(number as i32)) {
+            Some(action) if !action.is_empty() => action.clone(),
+            _ => continue
+        };
+
+        let (status, flow) = (ev.last_status, ev.flow);
+        ev.flow = Flow::Normal;
+        if let Err(e) = run_script(&action, ev) {
+            eprintln!("sh: {}", e);
+        }
+        if ev.flow == Flow::Exit {
+            return
+        }
+        ev.flow = flow;
+        ev.last_status = status;
+    }
+}
@@ -39,11 +39,7 @@ enum Mode {
 }
 
 fn test_expr1(arg1: &[u8]) -> bool {
-    if arg1.len() == 0 {
-        false
-    } else {
-        true
-    }
+    !arg1.is_empty()
 }
 
 fn check_access(path: &Path, mode: Mode) -> io::Result<bool> {
@@ -83,7 +79,7 @@ fn check_access(path: &Path, mode: Mode) -> io::Result<bool> {
         return Ok(true)
     }
 
-    return Ok(false)
+    Ok(false)
 }
 
 fn is_readable(path: &Path) -> io::Result<bool> {
@@ -135,15 +131,12 @@ fn test_tty(arg: &[u8]) -> bool {
         _ => return false
     };
 
-    let fd = match i32::from_str_radix(arg_s, 10) {
+    let fd = match arg_s.parse::<i32>() {
         Ok(n) => n,
         _ => return false
     };
 
-    match safe_libc::isatty(fd) {
-        Ok(v) => v,
-        _ => false
-    }
+    safe_libc::isatty(fd).unwrap_or_default()
 }
 
 fn test_expr2(arg1: &[u8], arg2: &[u8]) -> io::Result<bool> {
@@ -158,7 +151,7 @@ fn test_expr2(arg1: &[u8], arg2: &[u8]) -> io::Result<bool> {
         b"-g" => test_path(FileTest::GidSet, path)?,
         b"-h" => test_path(FileTest::SymLink, path)?,
         b"-L" => test_path(FileTest::SymLink, path)?,
-        b"-n" => arg2.len() != 0,
+        b"-n" => !arg2.is_empty(),
         b"-p" => test_path(FileTest::Fifo, path)?,
         b"-r" => test_path(FileTest::Readable, path)?,
         b"-S" => test_path(FileTest::Socket, path)?,
@@ -167,7 +160,7 @@ fn test_expr2(arg1: &[u8], arg2: &[u8]) -> io::Result<bool> {
         b"-u" => test_path(FileTest::UidSet, path)?,
         b"-w" => test_path(FileTest::Writable, path)?,
         b"-x" => test_path(FileTest::Executable, path)?,
-        b"-z" => arg2.len() == 0,
+        b"-z" => arg2.is_empty(),
         _ => {
             error!("Unrecognised operator");
             false
@@ -177,13 +170,70 @@ fn test_expr2(arg1: &[u8], arg2: &[u8]) -> io::Result<bool> {
     Ok(ret)
 }
 
+fn parse_integer(arg: &[u8]) -> Option<i64> {
+    str::from_utf8(arg).ok()?.trim().parse::<i64>().ok()
+}
+
+fn is_binary_op(arg: &[u8]) -> bool {
+    matches!(arg, b"=" | b"!=" | b"-eq" | b"-ne" | b"-gt" | b"-ge" | b"-lt" | b"-le")
+}
+
+fn test_binary(arg1: &[u8], op: &[u8], arg2: &[u8]) -> bool {
+    match op {
+        b"=" => return arg1 == arg2,
+        b"!=" => return arg1 != arg2,
+        _ => ()
+    }
+
+    let (n1, n2) = match (parse_integer(arg1), parse_integer(arg2)) {
+        (Some(n1), Some(n2)) => (n1, n2),
+        _ => {
+            error!("Integer expression expected");
+            return false
+        }
+    };
+    match op {
+        b"-eq" => n1 == n2,
+        b"-ne" => n1 != n2,
+        b"-gt" => n1 > n2,
+        b"-ge" => n1 >= n2,
+        b"-lt" => n1 < n2,
+        b"-le" => n1 <= n2,
+        _ => false
+    }
+}
+
+fn test_expr3(arg1: &[u8], arg2: &[u8], arg3: &[u8]) -> io::Result<bool> {
+    if is_binary_op(arg2) {
+        Ok(test_binary(arg1, arg2, arg3))
+    } else if arg1 == b"!" {
+        Ok(!test_expr2(arg2, arg3)?)
+    } else if arg1 == b"(" && arg3 == b")" {
+        Ok(test_expr1(arg2))
+    } else {
+        error!("Unrecognised operator");
+        Ok(false)
+    }
+}
+
+fn test_expr4(arg1: &[u8], arg2: &[u8], arg3: &[u8], arg4: &[u8]) -> io::Result<bool> {
+    if arg1 == b"!" {
+        Ok(!test_expr3(arg2, arg3, arg4)?)
+    } else if arg1 == b"(" && arg4 == b")" {
+        test_expr2(arg2, arg3)
+    } else {
+        error!("Too many arguments");
+        Ok(false)
+    }
+}
+
 pub fn test_main(cmd_name: &str, mut args: Vec<OsString>) -> Result<ExitCode, Box<dyn Error>> {
 
     // Check end bracket
     if cmd_name == "[" {
-        if args.len() > 0 {
+        if !args.is_empty() {
             let final_arg = args.pop().unwrap();
-            if final_arg.to_str().unwrap() != "]" {
+            if final_arg.as_bytes() != b"]" {
                 error!("Missing \"]\"");
                 return Ok(ExitCode::FAILURE)
             }
@@ -193,12 +243,16 @@ pub fn test_main(cmd_name: &str, mut args: Vec<OsString>) -> Result<ExitCode, Bo
         }
     }
 
-    let ret = if args.len() == 0 {
+    let ret = if args.is_empty() {
         false
     } else if args.len() == 1 {
         test_expr1(args[0].as_bytes())
     } else if args.len() == 2{
         test_expr2(args[0].as_bytes(), args[1].as_bytes())?
+    } else if args.len() == 3 {
+        test_expr3(args[0].as_bytes(), args[1].as_bytes(), args[2].as_bytes())?
+    } else if args.len() == 4 {
+        test_expr4(args[0].as_bytes(), args[1].as_bytes(), args[2].as_bytes(), args[3].as_bytes())?
     } else {
         false
     };
@@ -8,7 +8,7 @@ use crate::io_util::write_line;
 
 pub fn yes_main(_cmd_name: &str, args: Vec<OsString>) -> Result<ExitCode, Box<dyn Error>> {
     let mut yes_val = OsString::from("y");
-    if args.len() > 0 {
+    if !args.is_empty() {
         let sep = OsStr::new(" ");
         yes_val = args.join(sep);
     }
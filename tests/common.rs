@@ -4,5 +4,5 @@ use std::process::Command;
 pub fn get_cmd(rustbox_cmd: &str) -> Command {
     let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME")).unwrap();
     cmd.arg(rustbox_cmd);
-    return cmd;
+    cmd
 }
\ No newline at end of file
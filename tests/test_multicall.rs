@@ -0,0 +1,44 @@
+use assert_cmd::prelude::*;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::process::Command;
+
+mod common;
+
+#[test]
+fn multicall_non_utf8_command() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = common::get_cmd("caf\u{e9}");
+    cmd.assert()
+        .code(127);
+
+    let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME"))?;
+    cmd.arg(OsStr::from_bytes(b"caf\xe9"));
+    cmd.assert()
+        .code(127);
+
+    Ok(())
+}
+
+#[test]
+fn multicall_non_utf8_link() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(OsStr::from_bytes(b"rustybox-multicall-\xff"));
+    std::fs::create_dir_all(&dir)?;
+    let binary = assert_cmd::cargo::cargo_bin(env!("CARGO_PKG_NAME"));
+    for name in [OsStr::new("echo"), OsStr::from_bytes(b"\xfe")] {
+        let link = dir.join(name);
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&binary, &link)?;
+    }
+
+    let echo = Command::new(dir.join("echo"))
+        .arg(OsStr::from_bytes(b"caf\xe9"))
+        .output();
+    let unknown = Command::new(dir.join(OsStr::from_bytes(b"\xfe"))).output();
+    std::fs::remove_dir_all(&dir)?;
+
+    let echo = echo?;
+    assert!(echo.status.success());
+    assert_eq!(echo.stdout, b"caf\xe9\n");
+    assert_eq!(unknown?.status.code(), Some(127));
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use assert_cmd::prelude::*;
+
+mod common;
+
+macro_rules! test_printf {
+    ( $test_name:ident, $test_args:expr, $expected:expr ) => {
+        #[test]
+        fn $test_name() -> Result<(), Box<dyn std::error::Error>> {
+            let mut cmd = common::get_cmd("printf");
+            cmd.args($test_args);
+            cmd.assert()
+                .success()
+                .stdout($expected);
+
+            Ok(())
+        }
+    }
+}
+
+test_printf!(printf_escapes, ["a\\tb\\101\\n"], "a\tbA\n");
+
+test_printf!(printf_reuse_format, ["%s-%s\\n", "a", "b", "c"], "a-b\nc-\n");
+
+test_printf!(printf_strings, ["[%5s|%-5s|%.2s|%c]", "ab", "cd", "xyz", "qrs"], "[   ab|cd   |xy|q]");
+
+test_printf!(
+    printf_integers,
+    ["%d %i %+d %05d %x %X %o %#x %u", "42", "-7", "3", "-42", "255", "0xff", "8", "16", "-1"],
+    "42 -7 +3 -0042 ff FF 10 0x10 18446744073709551615"
+);
+
+test_printf!(printf_integer_forms, ["%d %d %d", "'A", "010", " 12"], "65 8 12");
+
+test_printf!(printf_star, ["%*d|%-*s|%.*d", "4", "7", "3", "x", "3", "5"], "   7|x  |005");
+
+test_printf!(printf_floats, ["%.2f %e %g %G", "3.14159", "1500", "0.0001", "1e20"], "3.14 1.500000e+03 0.0001 1E+20");
+
+test_printf!(printf_b, ["%b|%b", "a\\nb\\0101", "x\\cy"], "a\nbA|x");
+
+test_printf!(printf_stop, ["100%% %s\\c more", "done"], "100% done");
+
+test_printf!(printf_missing_args, ["%s %d\\n"], " 0\n");
+
+#[test]
+fn printf_invalid() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = common::get_cmd("printf");
+    cmd.args(["%d %d %z", "12abc", "x"]);
+    cmd.assert()
+        .failure()
+        .stdout("12 0 ")
+        .stderr("printf: 12abc: invalid number\nprintf: x: invalid number\nprintf: %z: invalid conversion specification\n");
+
+    Ok(())
+}
+
+#[test]
+fn printf_out_of_range() -> Result<(), Box<dyn std::error::Error>> {
+    let mut cmd = common::get_cmd("printf");
+    cmd.args(["a%999999999999sb", "x"]);
+    cmd.assert()
+        .failure()
+        .stdout("a")
+        .stderr("printf: %999999999999: field width or precision out of range\n");
+
+    let mut cmd = common::get_cmd("printf");
+    cmd.args(["%+.2147483647d|", "1"]);
+    cmd.assert()
+        .failure()
+        .stdout("")
+        .stderr("printf: %+.2147483647d: Value too large for defined data type\n");
+
+    Ok(())
+}
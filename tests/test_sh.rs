@@ -15,7 +15,7 @@ macro_rules! check_output {
                 .stdout(Stdio::piped())
                 .spawn()?;
             
-            let child_stdin = child.stdin.as_mut().unwrap();
+            let mut child_stdin = child.stdin.take().unwrap();
             child_stdin.write_all(concat!($command, " ", $args, "\n").as_bytes())?;
             drop(child_stdin);
         
@@ -28,6 +28,740 @@ macro_rules! check_output {
     }
 }
 
+macro_rules! check_script {
+    ( $test_name:ident, $script:expr, $expected:expr) => {
+        #[test]
+        fn $test_name() -> Result<(), Box<dyn std::error::Error>> {
+            let path = std::env::temp_dir().join(concat!("rustybox-", stringify!($test_name), ".sh"));
+            std::fs::write(&path, $script)?;
+
+            let output = common::get_cmd("sh").arg(&path).output();
+            std::fs::remove_file(&path)?;
+
+            assert_eq!($expected, str::from_utf8(&output?.stdout)?);
+
+            Ok(())
+        }
+    }
+}
+
 check_output!(sh_echo1, "echo", "fooword", "fooword\n");
 
 check_output!(sh_echo2, "echo", "foo word", "foo word\n");
+
+check_output!(sh_single_quote, "echo", "'a  b'", "a  b\n");
+
+check_output!(sh_double_quote, "echo", "\"x;y\"", "x;y\n");
+
+check_output!(sh_backslash, "echo", "a\\ \\ b", "a  b\n");
+
+check_output!(sh_variable, "x=hello &&", "echo $x \"${x}\" '$x'", "hello hello $x\n");
+
+check_output!(sh_param_default, "echo", "${unset:-default}", "default\n");
+
+check_output!(sh_param_strip, "f=archive.tar.gz &&", "echo ${f%%.*} ${f#*.}", "archive tar.gz\n");
+
+check_output!(sh_tilde, "HOME=/home/me; x=~ y=a:~/b:'~' &&", "echo ~ ~/x ~root '~' \"~\" $x $y", "/home/me /home/me/x /root ~ ~ /home/me a:/home/me/b:~\n");
+
+check_output!(sh_last_status, "false ||", "echo $?", "1\n");
+
+check_output!(sh_command_sub, "echo", "$(echo hello) `echo world`", "hello world\n");
+
+check_output!(sh_command_sub_nested, "echo", "\"$(echo \"a $(echo b)\")\"", "a b\n");
+
+check_output!(sh_command_sub_newlines, "echo", "\"[$(printf 'a\\n\\n\\n')]\"", "[a]\n");
+
+check_output!(sh_command_sub_status, "x=$(false) ||", "echo $?", "1\n");
+
+check_output!(sh_arithmetic, "x=4 &&", "echo $((x * (2 + 1))) $((x += 1)) $x", "12 5 5\n");
+
+check_output!(sh_arithmetic_quoted, "echo", "\"$(( $(echo 6) / 4 ))\"", "1\n");
+
+check_output!(sh_command_sub_assign, "v=$(echo 1.2) &&", "echo \"v$v\"", "v1.2\n");
+
+check_output!(sh_redirect_file, "f=/tmp/rustybox-redir-$$ &&", "echo hi >$f && echo there >>$f && cat <$f && rm $f", "hi\nthere\n");
+
+check_output!(sh_redirect_truncate, "f=/tmp/rustybox-trunc-$$ &&", "echo old >$f && >$f && wc -c <$f && rm $f", "0\n");
+
+check_output!(sh_redirect_dup, "echo", "a 3>&1 >/dev/null 1>&3", "a\n");
+
+check_output!(sh_redirect_close, "echo", "hidden >&- 2>/dev/null || echo closed", "closed\n");
+
+check_output!(sh_builtin_closed_stdout, "printf", "hidden >&- 2>/dev/null || printf 'closed\\n'", "closed\n");
+
+check_output!(sh_redirect_pipe, "echo", "x 2>/dev/null | cat 1>&1", "x\n");
+
+check_output!(sh_redirect_missing, "cat", "</nonexistent/file 2>/dev/null || echo $?", "1\n");
+
+check_script!(sh_here_doc, "x=world\ncat <<EOF\nhello $x\n\"$(echo quoted)\" \\$x\nEOF\necho after\n", "hello world\n\"quoted\" $x\nafter\n");
+
+check_script!(sh_here_doc_quoted, "x=world\ncat <<'EOF'\nhello $x\nEOF\n", "hello $x\n");
+
+check_script!(sh_here_doc_strip_tabs, "cat <<-EOF\n\tindented\n\tEOF\n", "indented\n");
+
+check_script!(sh_here_doc_multiple, "cat <<A - 3<<B\nfirst\nA\nsecond\nB\ncat 3<<B <&3\nthird\nB\n", "first\nthird\n");
+
+check_script!(sh_if, "if false; then echo no\nelif true; then echo yes\nelse echo else\nfi\nif false; then :; fi\necho $?\n", "yes\n0\n");
+
+check_script!(sh_while_until, "i=0\nwhile test $i -lt 2; do echo $i; i=$((i + 1)); done\nuntil test $i -eq 0\ndo\n  i=$((i - 1))\ndone\necho $i\n", "0\n1\n0\n");
+
+check_script!(sh_for, "for w in a 'b c'; do echo \"[$w]\"; done\n", "[a]\n[b c]\n");
+
+check_script!(sh_case, "for f in a.c b.txt c; do\n  case $f in\n    *.c) echo C;;\n    (*.txt|*.md) echo text;;\n    *) echo other\n  esac\ndone\n", "C\ntext\nother\n");
+
+check_script!(sh_brace_group, "{ echo one; echo two; } | cat\n{ x=set; }\necho $x\n", "one\ntwo\nset\n");
+
+check_script!(sh_subshell, "x=outer\n(x=inner; echo $x)\necho $x\n(false) || echo failed\n", "inner\nouter\nfailed\n");
+
+check_script!(sh_compound_redirect, "f=/tmp/rustybox-compound-$$\nfor i in 1 2; do echo $i; done >$f\ncat $f\nrm $f\n", "1\n2\n");
+
+check_script!(sh_function, "greet() {\n  echo \"hello $1 ($#)\"\n}\ngreet world two\necho \"$#\"\n", "hello world (2)\n0\n");
+
+check_script!(sh_function_recursion, "fact() {\n  if test $1 -le 1; then echo 1; return; fi\n  echo $(( $1 * $(fact $(( $1 - 1 ))) ))\n}\nfact 5\n", "120\n");
+
+check_script!(sh_function_return, "f() { for i in 1 2 3; do if test $i = 2; then return 7; fi; echo $i; done; echo never; }\nf\necho $?\nf && echo no || echo $?\n", "1\n7\n1\n7\n");
+
+check_script!(sh_function_globals, "f() { x=changed; }\nx=orig\nf\necho $x\n", "changed\n");
+
+check_script!(sh_function_here_doc, "f() { cat <<EOF; }\nbody $1\nEOF\nf one\nf two\n", "body one\nbody two\n");
+
+check_script!(sh_and_or_skip, "false && echo no || echo yes\ntrue || echo no && echo yes\n", "yes\nyes\n");
+
+check_output!(sh_sequential_list, "echo", "a; echo b;", "a\nb\n");
+
+check_script!(sh_background, "sleep 0.2 && echo second &\necho first\nwait $!\necho waited $?\n", "first\nsecond\nwaited 0\n");
+
+check_script!(sh_wait_status, "(false) &\np=$!\ntrue &\nwait %1; echo $?\nwait $p; echo $?\nwait %2; echo $?\n", "1\n127\n0\n");
+
+check_script!(sh_wait_all, "for i in 1 2 3; do (sleep 0.1; echo w) & done; wait; echo all\n", "w\nw\nw\nall\n");
+
+check_script!(sh_special_builtin_assignment, "a=1 :; echo $a; b=2 cd .; echo ${b-unset}\n", "1\nunset\n");
+
+check_script!(sh_break_continue,
+    "for i in 1 2 3; do for j in a b c; do [ $j = b ] && continue; [ $i = 3 ] && break 2; echo $i$j; done; done\n",
+    "1a\n1c\n2a\n2c\n");
+
+check_script!(sh_exit, "echo a; (exit 3); echo $?; exit 4; echo b\n", "a\n3\n");
+
+check_script!(sh_set_shift, "set -- a b c; echo $# $1; shift 2; echo $# $1\n", "3 a\n1 c\n");
+
+check_script!(sh_export, "export X=1; env | grep '^X='; unset X; echo ${X-unset}\n", "X=1\nunset\n");
+
+check_script!(sh_readonly, "readonly R=1; echo $R; R=2; echo not reached\n", "1\n");
+
+check_script!(sh_eval, "eval 'x=1; echo $x'; eval echo '$x'\n", "1\n1\n");
+
+check_script!(sh_dot,
+    "echo 'y=2; return; y=3' > /tmp/rustybox-sh-dot; . /tmp/rustybox-sh-dot; echo $y\n",
+    "2\n");
+
+check_script!(sh_cd, "cd /; pwd; cd /tmp; cd -; echo $OLDPWD\n", "/\n/\n/tmp\n");
+
+check_script!(sh_umask,
+    "umask 077; umask; umask -S; f=/tmp/rustybox-umask-$$; : >$f; ls -l $f | cut -c1-10; rm $f\numask g+rx,o=r; umask; umask u+q || echo invalid\n",
+    "0077\nu=rwx,g=,o=\n-rw-------\n0023\ninvalid\n");
+
+check_script!(sh_times, "times | sed 's/[0-9]/N/g'\n", "NmN.NNNs NmN.NNNs\nNmN.NNNs NmN.NNNs\n");
+
+check_script!(sh_exec_redirect, "exec 3>&1; echo three >&3; exec echo replaced; echo not reached\n", "three\nreplaced\n");
+
+check_script!(sh_trap_exit, "trap 'echo bye' EXIT; echo hi; exit 3\n", "hi\nbye\n");
+
+check_script!(sh_trap_exit_subshell,
+    "trap 'echo top' EXIT; ( trap 'echo bye' EXIT; : ); x=$(trap 'echo sub' EXIT); echo \"[$x]\"; (echo child)\n",
+    "bye\n[sub]\nchild\ntop\n");
+
+check_script!(sh_special_builtin_error, "shift 5; echo not reached\n", "");
+
+check_script!(sh_applet_without_path,
+    "PATH=/nonexistent; echo hi; [ a = a ] && echo eq; cat <<E\nhere\nE\n",
+    "hi\neq\nhere\n");
+
+#[cfg(not(feature = "sh-prefer-applets"))]
+check_script!(sh_program_before_applet,
+    "d=/tmp/rustybox-program-$$; mkdir $d; printf '#!/bin/sh\\necho program\\n' >$d/basename; chmod +x $d/basename\nPATH=$d:$PATH; basename /a/b; rm -r $d\n",
+    "program\n");
+
+check_script!(sh_applet_through_link,
+    "d=/tmp/rustybox-link-$$; mkdir $d; exe=$(readlink /proc/$$/exe); ln -s $exe $d/echo; ln -s $exe $d/basename\nPATH=$d:$PATH; type echo basename; echo hi; basename /a/b; rm -r $d\n",
+    "echo is a rustybox applet\nbasename is a rustybox applet\nhi\nb\n");
+
+check_script!(sh_applet_pipe, "yes | cat | (exit 0); echo done\n", "done\n");
+
+check_script!(sh_field_splitting,
+    "x='a  b c'; for i in $x; do echo \"[$i]\"; done\nIFS=:; y='a::b:'; for i in $y; do echo \"<$i>\"; done\n",
+    "[a]\n[b]\n[c]\n<a>\n<>\n<b>\n");
+
+check_script!(sh_quoted_args,
+    "set -- 'p q' r; for i in \"$@\"; do echo \"($i)\"; done; for i in $@; do echo \"($i)\"; done\nset --; for i in \"$@\" ''; do echo \"[$i]\"; done\n",
+    "(p q)\n(r)\n(p)\n(q)\n(r)\n[]\n");
+
+check_script!(sh_pathname_expansion,
+    "d=/tmp/rustybox-glob-$$; mkdir $d && cd $d || exit 1\n: >b.txt; : >a.txt; : >.hidden.txt; : >c.rs\necho *.txt '*.txt' z*\nset -f; echo *.txt; set +f\ncd /; rm -r $d\n",
+    "a.txt b.txt *.txt z*\n*.txt\n");
+
+macro_rules! check_invocation {
+    ( $test_name:ident, $args:expr, $expected:expr) => {
+        #[test]
+        fn $test_name() -> Result<(), Box<dyn std::error::Error>> {
+            let output = common::get_cmd("sh").args($args).stdin(Stdio::null()).output()?;
+
+            assert_eq!($expected, str::from_utf8(&output.stdout)?);
+
+            Ok(())
+        }
+    }
+}
+
+check_invocation!(sh_command_string, ["-c", "echo \"$0 [$1] $#\"", "name", "a b", "c"], "name [a b] 2\n");
+
+check_invocation!(sh_command_string_name, ["-c", "echo $0"], "sh\n");
+
+check_invocation!(sh_invocation_options, ["-f", "-o", "noexec", "+n", "-c", "echo $- *"], "fc *\n");
+
+check_invocation!(sh_noexec, ["-n", "-c", "echo not run"], "");
+
+check_invocation!(sh_errexit,
+    ["-e", "-c", "false || true; ! true; if false; then :; fi; while false; do :; done; false && true; echo ok; false; echo no"],
+    "ok\n");
+
+check_invocation!(sh_errexit_function, ["-c", "set -e; f() { false; echo no; }; f; echo no"], "");
+
+check_invocation!(sh_nounset, ["-u", "-c", "echo ${x-default} ${x:+alt}; echo $x; echo no"], "default\n");
+
+check_invocation!(sh_pipefail,
+    ["-c", "false | true; echo $?; set -o pipefail; false | true; echo $?; set -o | grep pipefail"],
+    "0\n1\npipefail        on\n");
+
+check_invocation!(sh_noclobber,
+    ["-C", "-c", "f=/tmp/rustybox-noclobber-$$; echo a >$f; echo b >$f; cat $f; echo c >|$f; cat $f; rm $f"],
+    "a\nc\n");
+
+check_invocation!(sh_kill,
+    ["-c", "sleep 10 & kill %1; wait $!; echo $?; kill -l 143; trap 'echo hup' HUP; kill -s HUP $$; kill -0 $$ && echo alive; kill %9 2>/dev/null || echo none"],
+    "143\nTERM\nhup\nalive\nnone\n");
+
+check_invocation!(sh_trap_signal,
+    ["-c", "trap 'echo caught; exit 7' TERM; trap 'echo bye' EXIT; kill -TERM $$; echo not reached"],
+    "caught\nbye\n");
+
+check_invocation!(sh_trap_deferred,
+    ["-c", "trap 'echo trapped' USR1; (kill -USR1 $$; echo child); echo parent; trap -p USR1; trap - USR1; trap"],
+    "child\ntrapped\nparent\ntrap -- 'echo trapped' USR1\n");
+
+check_invocation!(sh_trap_interrupts_wait,
+    ["-c", "trap 'echo trapped' USR1; sleep 1 & (sleep 0.2; kill -USR1 $$) & wait %1; echo $?"],
+    "trapped\n138\n");
+
+check_invocation!(sh_signal_status, ["-c", "sh -c 'kill -9 $$'; echo $?"], "137\n");
+
+#[test]
+fn sh_trap_ignored_on_entry() -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::process::CommandExt;
+    use nix::sys::signal::{signal, SigHandler, Signal};
+
+    let mut cmd = common::get_cmd("sh");
+    cmd.args(["-c", "trap 'echo caught' USR1; kill -USR1 $$; echo ignored"]);
+    unsafe {
+        cmd.pre_exec(|| {
+            signal(Signal::SIGUSR1, SigHandler::SigIgn)?;
+            Ok(())
+        });
+    }
+    let output = cmd.output()?;
+
+    assert_eq!("ignored\n", str::from_utf8(&output.stdout)?);
+
+    Ok(())
+}
+
+#[test]
+fn sh_xtrace() -> Result<(), Box<dyn std::error::Error>> {
+    let output = common::get_cmd("sh")
+        .args(["-x", "-c", "x=1 y='a b'; echo \"$x\" ''; PS4='[$x] '; true"])
+        .output()?;
+
+    assert_eq!("1 \n", str::from_utf8(&output.stdout)?);
+    assert_eq!("+ x=1 y='a b'\n+ echo 1 ''\n+ PS4='[$x] '\n[1] true\n", str::from_utf8(&output.stderr)?);
+
+    Ok(())
+}
+
+#[test]
+fn sh_script_args() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("rustybox-sh_script_args.sh");
+    std::fs::write(&path, "echo $0 $# \"$2\"\n")?;
+
+    let output = common::get_cmd("sh").arg(&path).args(["a", "b c"]).output();
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(format!("{} 2 b c\n", path.display()), str::from_utf8(&output?.stdout)?);
+
+    Ok(())
+}
+
+#[test]
+fn sh_script_not_found() -> Result<(), Box<dyn std::error::Error>> {
+    let output = common::get_cmd("sh").arg("/nonexistent/script").output()?;
+    assert_eq!(output.status.code(), Some(127));
+    Ok(())
+}
+
+#[test]
+fn sh_environment() -> Result<(), Box<dyn std::error::Error>> {
+    let script = "echo $RB_VAR; env | grep -c '^RB_VAR='; unset RB_VAR; env | grep -c '^RB_VAR='\n\
+        export RB_NEW; export -p | grep RB_NEW; RB_NEW=1; env | grep RB_NEW\n\
+        echo \"[$IFS]\" $OPTIND ${HOME:+home} $PWD\n\
+        readonly RB_RO=1; unset RB_RO; echo not reached\n";
+    let output = common::get_cmd("sh")
+        .args(["-c", script])
+        .current_dir("/")
+        .env("RB_VAR", "inherited")
+        .env("IFS", "x")
+        .env("PWD", "/nonexistent")
+        .env_remove("HOME")
+        .output()?;
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!("inherited\n1\n0\nexport RB_NEW\nRB_NEW=1\n[ \t\n] 1 home /\n", str::from_utf8(&output.stdout)?);
+    assert_eq!("sh: RB_RO: readonly variable\n", str::from_utf8(&output.stderr)?);
+    Ok(())
+}
+
+#[test]
+fn sh_redirect_error() -> Result<(), Box<dyn std::error::Error>> {
+    let output = common::get_cmd("sh").args(["-c", "echo x >/nonexistent/x; echo $?"]).output()?;
+    assert_eq!("1\n", str::from_utf8(&output.stdout)?);
+    assert_eq!("sh: /nonexistent/x: No such file or directory\n", str::from_utf8(&output.stderr)?);
+    Ok(())
+}
+
+#[test]
+fn sh_cd_error() -> Result<(), Box<dyn std::error::Error>> {
+    let output = common::get_cmd("sh").args(["-c", "cd /nonexistent; echo $?"]).output()?;
+    assert_eq!("1\n", str::from_utf8(&output.stdout)?);
+    assert_eq!("sh: cd: /nonexistent: No such file or directory\n", str::from_utf8(&output.stderr)?);
+    Ok(())
+}
+
+#[test]
+fn sh_script_unreadable() -> Result<(), Box<dyn std::error::Error>> {
+    let output = common::get_cmd("sh").arg("/").output()?;
+    assert_eq!(output.status.code(), Some(126));
+    assert_eq!("sh: /: Is a directory\n", str::from_utf8(&output.stderr)?);
+
+    let output = common::get_cmd("sh").arg("/nonexistent").output()?;
+    assert_eq!(output.status.code(), Some(127));
+    assert_eq!("sh: /nonexistent: No such file or directory\n", str::from_utf8(&output.stderr)?);
+    Ok(())
+}
+
+#[test]
+fn sh_syntax_error() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join("rustybox-sh_syntax_error.sh");
+    std::fs::write(&path, "echo before\nif true\nthen fi\necho never\n")?;
+    let output = common::get_cmd("sh").arg(&path).output();
+    std::fs::remove_file(&path)?;
+    let output = output?;
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!("before\n", str::from_utf8(&output.stdout)?);
+    let expected = format!("sh: {}: line 3: syntax error near unexpected token 'fi'\n", path.display());
+    assert_eq!(expected, str::from_utf8(&output.stderr)?);
+
+    let output = common::get_cmd("sh").args(["-c", "echo a; eval 'for x'"]).output()?;
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!("a\n", str::from_utf8(&output.stdout)?);
+    assert_eq!("sh: eval: line 1: syntax error: unexpected end of file\n", str::from_utf8(&output.stderr)?);
+    Ok(())
+}
+
+#[test]
+fn sh_stdin_operands() -> Result<(), Box<dyn std::error::Error>> {
+    let mut child = common::get_cmd("sh")
+        .args(["-s", "p", "q"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut child_stdin = child.stdin.take().unwrap();
+    child_stdin.write_all(b"for i in \"$@\"; do\n  echo $i\ndone\n")?;
+    drop(child_stdin);
+
+    let output = child.wait_with_output()?;
+    assert_eq!("p\nq\n", str::from_utf8(&output.stdout)?);
+
+    Ok(())
+}
+
+check_output!(sh_stdin_shared, "read line; echo \"[$line]\"\nsome data\nhead -n 1; echo done\nrest\n", "", "[some data]\nrest\ndone\n");
+
+#[test]
+fn sh_stdin_syntax_error() -> Result<(), Box<dyn std::error::Error>> {
+    let mut child = common::get_cmd("sh")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(b"echo before\n\nif true\nthen fi\necho never\n")?;
+    let output = child.wait_with_output()?;
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!("before\n", str::from_utf8(&output.stdout)?);
+    assert_eq!("sh: line 4: syntax error near unexpected token 'fi'\n", str::from_utf8(&output.stderr)?);
+
+    Ok(())
+}
+
+/// The output of a shell running on a pseudo-terminal, read by a separate
+/// thread so that it can be waited for with a timeout
+struct Terminal {
+    output: std::sync::mpsc::Receiver<Vec<u8>>,
+    seen: Vec<u8>
+}
+
+impl Terminal {
+    /// Start a shell on a terminal of its own, so that it is interactive
+    /// and enables job control. Returns the terminal's output, the master
+    /// side to type into and the shell process.
+    fn spawn() -> Result<(Terminal, std::fs::File, std::process::Child), Box<dyn std::error::Error>> {
+        use std::fs::File;
+        use std::os::unix::io::FromRawFd;
+        use std::os::unix::process::CommandExt;
+        use std::io::Read;
+
+        let pty = nix::pty::openpty(None, None)?;
+        let slave = unsafe { File::from_raw_fd(pty.slave) };
+        let mut cmd = common::get_cmd("sh");
+        cmd.stdin(slave.try_clone()?).stdout(slave.try_clone()?).stderr(slave);
+        unsafe {
+            cmd.pre_exec(|| {
+                nix::unistd::setsid()?;
+                if libc::ioctl(0, libc::TIOCSCTTY, 0) != 0 {
+                    return Err(std::io::Error::last_os_error())
+                }
+                Ok(())
+            });
+        }
+        let child = cmd.spawn()?;
+        drop(cmd);
+
+        let master = unsafe { File::from_raw_fd(pty.master) };
+        let mut reader = master.try_clone()?;
+        let (sender, output) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0; 1024];
+            while let Ok(n) = reader.read(&mut buf) {
+                if n == 0 || sender.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok((Terminal { output, seen: vec![] }, master, child))
+    }
+
+    /// Wait until `expected` appears in the output, discarding everything
+    /// up to the end of it. A `$ ` in `expected` stands for the default
+    /// prompt, which is `# ` for root.
+    fn expect(&mut self, expected: &str) {
+        let expected = if nix::unistd::geteuid().is_root() { expected.replace("$ ", "# ") } else { expected.to_string() };
+        let expected = expected.as_str();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(10);
+        loop {
+            let text = String::from_utf8_lossy(&self.seen).into_owned();
+            if let Some(start) = text.find(expected) {
+                self.seen = text.as_bytes()[(start + expected.len())..].to_vec();
+                return
+            }
+
+            let timeout = deadline.saturating_duration_since(std::time::Instant::now());
+            match self.output.recv_timeout(timeout) {
+                Ok(bytes) => self.seen.extend(bytes),
+                Err(_) => panic!("expected {:?} in {:?}", expected, text)
+            }
+        }
+    }
+}
+
+#[test]
+fn sh_job_control() -> Result<(), Box<dyn std::error::Error>> {
+    use std::time::Duration;
+
+    let (mut term, mut master, mut child) = Terminal::spawn()?;
+
+    term.expect("$ ");
+    master.write_all(b"echo $-\n")?;
+    term.expect("im\r\n$ ");
+
+    // Stop a foreground job with the suspend character
+    master.write_all(b"sleep 10\n")?;
+    term.expect("sleep 10\r\n");
+    std::thread::sleep(Duration::from_millis(500));
+    master.write_all(b"\x1a")?;
+    term.expect("[1]+  Stopped                 sleep 10\r\n$ ");
+
+    master.write_all(b"sleep 20 &\n")?;
+    term.expect("[2] ");
+    term.expect("$ ");
+    master.write_all(b"jobs\n")?;
+    term.expect("jobs\r\n[1]+  Stopped                 sleep 10\r\n[2]   Running                 sleep 20 &\r\n$ ");
+
+    // Bring the stopped job back and interrupt it
+    master.write_all(b"fg %1\n")?;
+    term.expect("fg %1\r\nsleep 10\r\n");
+    std::thread::sleep(Duration::from_millis(500));
+    master.write_all(b"\x03")?;
+    term.expect("$ ");
+    master.write_all(b"echo status $?\n")?;
+    term.expect("status 130\r\n$ ");
+
+    master.write_all(b"fg\n")?;
+    term.expect("sleep 20\r\n");
+    std::thread::sleep(Duration::from_millis(500));
+    master.write_all(b"\x03")?;
+    term.expect("$ ");
+    master.write_all(b"\x04")?;
+    child.wait()?;
+    Ok(())
+}
+
+#[test]
+fn sh_kill_job() -> Result<(), Box<dyn std::error::Error>> {
+    use std::time::Duration;
+
+    let (mut term, mut master, mut child) = Terminal::spawn()?;
+
+    term.expect("$ ");
+    master.write_all(b"sleep 10\n")?;
+    term.expect("sleep 10\r\n");
+    std::thread::sleep(Duration::from_millis(500));
+    master.write_all(b"\x1a")?;
+    term.expect("[1]+  Stopped                 sleep 10\r\n$ ");
+
+    // A stopped job is continued so that it gets the signal
+    master.write_all(b"kill %1; wait %1; echo status $?\n")?;
+    term.expect("status 143\r\n$ ");
+
+    master.write_all(b"sleep 20 &\n")?;
+    term.expect("$ ");
+    master.write_all(b"kill -s KILL %sleep; wait %1; echo status $?\n")?;
+    term.expect("status 137\r\n$ ");
+
+    master.write_all(b"\x04")?;
+    child.wait()?;
+    Ok(())
+}
+
+#[test]
+fn sh_line_editing() -> Result<(), Box<dyn std::error::Error>> {
+    let (mut term, mut master, mut child) = Terminal::spawn()?;
+
+    term.expect("$ ");
+    master.write_all(b"echo one\n")?;
+    term.expect("one\r\n$ ");
+
+    // Recall the last command and change its first word
+    master.write_all(b"\x1b[A\x01\x1b[3~\x1b[3~\x1b[3~\x1b[3~printf '%s!\\n'\n")?;
+    term.expect("one!\r\n$ ");
+
+    // Complete a builtin name
+    master.write_all(b"histo\t\n")?;
+    term.expect("    1  echo one\r\n    2  printf '%s!\\n' one\r\n    3  history \r\n$ ");
+
+    master.write_all(b"set -o vi\n")?;
+    term.expect("$ ");
+    master.write_all(b"echo abc def\x1b")?;
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    master.write_all(b"bdwiX\n")?;
+    term.expect("abcX\r\n$ ");
+
+    master.write_all(b"fc -l -2\n")?;
+    term.expect("4\tset -o vi\r\n5\techo abcX \r\n$ ");
+
+    master.write_all(b"\x04")?;
+    child.wait()?;
+    Ok(())
+}
+
+#[test]
+fn sh_interactive_error_status() -> Result<(), Box<dyn std::error::Error>> {
+    let (mut term, mut master, mut child) = Terminal::spawn()?;
+
+    term.expect("$ ");
+    master.write_all(b"readonly R=1\n")?;
+    term.expect("$ ");
+    master.write_all(b"R=2\n")?;
+    term.expect("$ ");
+    master.write_all(b"echo status $?\n")?;
+    term.expect("status 2\r\n$ ");
+
+    master.write_all(b"echo ${nope:?msg}; echo not reached\n")?;
+    term.expect("msg");
+    term.expect("$ ");
+    master.write_all(b"echo status $?\n")?;
+    term.expect("status 2\r\n$ ");
+
+    master.write_all(b"echo hi >&x\n")?;
+    term.expect("bad file descriptor\r\n$ ");
+    master.write_all(b"echo status $?\n")?;
+    term.expect("status 1\r\n$ ");
+
+    master.write_all(b"\x04")?;
+    child.wait()?;
+    Ok(())
+}
+
+#[test]
+fn sh_interactive_multiline() -> Result<(), Box<dyn std::error::Error>> {
+    let mut child = common::get_cmd("sh")
+        .arg("-i")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let script = "PS1=; PS2=\n\n# comment\nfor i in 1 2\ndo echo $i\ndone\necho 'a\nb' |\ncat\ncat <<E\nhere\nE\necho a; fi\necho $?\nif true\n";
+    child.stdin.take().unwrap().write_all(script.as_bytes())?;
+    let output = child.wait_with_output()?;
+
+    assert_eq!("1\n2\na\nb\nhere\n2\n", str::from_utf8(&output.stdout)?);
+    let stderr = str::from_utf8(&output.stderr)?;
+    assert_eq!(stderr.lines().count(), 2, "{}", stderr);
+    assert!(stderr.ends_with("sh: syntax error: unexpected end of file\n"), "{}", stderr);
+    Ok(())
+}
+
+#[test]
+fn sh_prompts() -> Result<(), Box<dyn std::error::Error>> {
+    let (mut term, mut master, mut child) = Terminal::spawn()?;
+
+    term.expect("$ ");
+    master.write_all(b"name=box; PS1='$name ! % '; PS2='more> '\n")?;
+    term.expect("box 2 % ");
+
+    // Incomplete commands continue onto the next line
+    master.write_all(b"echo 'a\n")?;
+    term.expect("more> ");
+    master.write_all(b"b' |\n")?;
+    term.expect("more> ");
+    master.write_all(b"cat\n")?;
+    term.expect("a\r\nb\r\nbox 3 % ");
+
+    master.write_all(b"if true\n")?;
+    term.expect("more> ");
+    master.write_all(b"then echo yes; fi\n")?;
+    term.expect("yes\r\nbox 4 % ");
+
+    master.write_all(b"\x04")?;
+    child.wait()?;
+    Ok(())
+}
+
+check_script!(
+    sh_command_type,
+    "f() { echo function; }\ntype if cd f\ncommand -v cd f\ncommand -V export\ncommand f 2>/dev/null; echo $?\ntype rustybox-nosuch; echo $?\n",
+    "if is a shell keyword\ncd is a shell builtin\nf is a function\ncd\nf\nexport is a special shell builtin\n127\n1\n"
+);
+
+#[test]
+fn sh_command_lookup() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join("rustybox-sh_command_lookup");
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("rb-prog"), "echo ran prog\n")?;
+    std::fs::write(dir.join("rb-noexec"), "echo not run\n")?;
+    std::fs::set_permissions(dir.join("rb-prog"), std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+    let script = "rb-prog; type rb-prog; hash\nhash -r; hash; echo cleared\n\
+        rb-nosuch; echo $?; ./rb-noexec; echo $?\ncommand -v rb-prog\n";
+    let output = common::get_cmd("sh")
+        .args(["-c", script])
+        .current_dir(&dir)
+        .env("PATH", &dir)
+        .output();
+    std::fs::remove_dir_all(&dir)?;
+    let output = output?;
+
+    let prog = dir.join("rb-prog");
+    let prog = prog.display();
+    assert_eq!(format!("ran prog\nrb-prog is hashed ({prog})\n{prog}\ncleared\n127\n126\n{prog}\n"), str::from_utf8(&output.stdout)?);
+    assert_eq!("sh: rb-nosuch: not found\nsh: ./rb-noexec: Permission denied\n", str::from_utf8(&output.stderr)?);
+    Ok(())
+}
+
+#[test]
+fn sh_aliases() -> Result<(), Box<dyn std::error::Error>> {
+    let mut child = common::get_cmd("sh")
+        .arg("-i")
+        .env("PS1", "")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let script = "alias say='echo said' sudo='echo sudo ' loop='loop x'\nalias\nsay hi; echo say\nsudo say\n\
+        f() { say in f; }\nloop 2>/dev/null; echo $?\nunalias say; type say; f\nalias say; echo $?\n";
+    child.stdin.take().unwrap().write_all(script.as_bytes())?;
+    let output = child.wait_with_output()?;
+
+    assert_eq!("alias loop='loop x'\nalias say='echo said'\nalias sudo='echo sudo '\n\
+        said hi\nsay\nsudo echo said\n127\nsaid in f\n1\n", str::from_utf8(&output.stdout)?);
+    assert_eq!("sh: say: not found\nsh: alias: say: not found\n", str::from_utf8(&output.stderr)?);
+    Ok(())
+}
+
+check_script!(
+    sh_aliases_in_script,
+    "alias hi=\"echo hello\"\nhi there\nalias two='hi; echo 2\n'\ntwo\nunalias hi\nhi 2>/dev/null || echo gone\n",
+    "hello there\nhello\n2\ngone\n"
+);
+
+check_invocation!(sh_aliases_in_command_string, ["-c", "alias x='echo hi'\nx there"], "hi there\n");
+
+check_script!(
+    sh_read,
+    "printf 'root:x:0:0\\nbin:x:1:1\\n' | while IFS=: read -r user _ uid _; do echo \"[$user] [$uid]\"; done\n\
+    echo '  a  b  c  ' | { read x y; echo \"[$x][$y]\"; }\n\
+    echo 'a\\ b\\\\c d' | { read x y; echo \"[$x][$y]\"; }\n\
+    echo 'a\\ b' | { read -r x y; echo \"[$x][$y]\"; }\n\
+    echo '  keep  ' | { read; echo \"[$REPLY]\"; }\n\
+    printf 'partial' | { read x; echo \"$? [$x]\"; }\n\
+    { read a; read b; } <<E\nfirst\nsecond\nE\necho \"$a $b\"\n",
+    "[root] [0]\n[bin] [1]\n[a][b  c]\n[a b\\c][d]\n[a\\][b]\n[  keep  ]\n1 [partial]\nfirst second\n"
+);
+
+check_script!(
+    sh_getopts,
+    "set -- -ab -c arg -dval -- file\n\
+    while getopts abc:d: opt; do echo \"$opt ${OPTARG-unset} $OPTIND\"; done\n\
+    shift $((OPTIND - 1)); echo \"$@\"\n\
+    OPTIND=1\nwhile getopts :a:x opt -y -a; do echo \"$opt ${OPTARG-unset}\"; done\n\
+    OPTIND=1\ngetopts a opt -z 2>/dev/null; echo \"$? $opt ${OPTARG-unset}\"\n",
+    "a unset 1\nb unset 2\nc arg 4\nd val 5\nfile\n? y\n: a\n0 ? unset\n"
+);
+
+check_script!(
+    sh_printf,
+    "printf '%s=%d\\n' a 1 b 2\nprintf '%5.1f|%-3s|' 2.25 x; echo\nprintf '%d\\n' abc 2>/dev/null; echo $?\ntype printf\n",
+    "a=1\nb=2\n  2.2|x  |\n0\n1\nprintf is a shell builtin\n"
+);
+
+#[test]
+fn sh_non_utf8() -> Result<(), Box<dyn std::error::Error>> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = std::env::temp_dir().join("rustybox-sh_non_utf8");
+    std::fs::create_dir_all(&dir)?;
+    // "café" in Latin-1, and 0xff which is never valid in UTF-8
+    let script: &[u8] = b"v='caf\xe9'; echo \"$v\" >\"$v\"; echo \xff >>\"$v\"; cat \"$v\"\n\
+        for f in *; do echo \"[$f]\"; done\n\
+        alias x='echo \xff'; alias x; set | grep '^v='\n\
+        printf 'line \\351\\n' | { read -r l; echo \"$l\"; }\n\
+        set -x; echo \"$v\"\n";
+    let output = common::get_cmd("sh")
+        .arg("-c")
+        .arg(OsStr::from_bytes(script))
+        .current_dir(&dir)
+        .output();
+    std::fs::remove_dir_all(&dir)?;
+    let output = output?;
+
+    assert_eq!(output.stdout, b"caf\xe9\n\xff\n[caf\xe9]\nalias x='echo \xff'\nv='caf\xe9'\nline \xe9\ncaf\xe9\n");
+    assert_eq!(output.stderr, b"+ echo 'caf\xe9'\n");
+    assert_eq!(output.status.code(), Some(0));
+    Ok(())
+}
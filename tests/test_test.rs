@@ -0,0 +1,34 @@
+use assert_cmd::prelude::*;
+
+mod common;
+
+macro_rules! test_test {
+    ( $test_name:ident, $test_args:expr, $success:expr ) => {
+        #[test]
+        fn $test_name() -> Result<(), Box<dyn std::error::Error>> {
+            let mut cmd = common::get_cmd("test");
+            for arg in $test_args.split_whitespace() {
+                cmd.arg(arg);
+            }
+
+            let assert = cmd.assert();
+            if $success {
+                assert.success();
+            } else {
+                assert.failure();
+            }
+
+            Ok(())
+        }
+    }
+}
+
+test_test!(string_nonempty, "a", true);
+test_test!(string_zero, "-z a", false);
+test_test!(string_equal, "a = a", true);
+test_test!(string_not_equal, "a != a", false);
+test_test!(integer_less, "1 -lt 2", true);
+test_test!(integer_greater_equal, "1 -ge 2", false);
+test_test!(negate_binary, "! 1 -eq 2", true);
+test_test!(negate_unary, "! -n a", false);
+test_test!(parens, "( a )", true);